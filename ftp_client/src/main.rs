@@ -1,26 +1,84 @@
 use clap::Parser;
-use rocket::serde::json::Json;
-use rocket::serde::Deserialize;
-use rocket::{delete, fs, get, post, routes, State};
+use rocket::routes;
 use std::error::Error;
 use std::sync::Arc;
-use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::TcpStream;
+use tokio::io::{self, AsyncBufRead, AsyncBufReadExt, BufReader};
 use tokio::sync::Mutex;
-use utils::connection_commands::FileEntry;
 
 mod utils;
 use crate::utils::cli_utils::Cli;
 use crate::utils::fs_utils::{get_file, write_file_in_downloads};
 use crate::utils::{
     cli_utils::{self, Commands},
-    connection_commands, fs_utils, openssl_utils, rocket_utils,
+    client_config::ClientConfig,
+    connection_commands::{self, ConnectionSettings, ControlStream, DataMode},
+    fs_utils,
+    openssl_utils::{self, LoginSigner},
+    rocket_utils, ssh_agent_utils,
 };
 
-/// Represents the context for a user, including username and signed text.
+/// Represents the context for a user, including username and login signer.
+///
+/// Shared by the REPL and the web server, so a data mode picked in the REPL
+/// is used by the web requests too.
 pub struct UserContext {
     username: String,
-    text_signed: String,
+    signer: Arc<LoginSigner>,
+    connection_settings: ConnectionSettings,
+}
+
+/// What the REPL needs to log into the server.
+struct LoginDetails<'a> {
+    settings: &'a ConnectionSettings,
+    username: &'a str,
+    signer: &'a LoginSigner,
+    /// Whether to ask for a TOTP code at every login.
+    totp: bool,
+}
+
+/// Returns the logged in session, logging in again when there is none yet
+/// or the server stopped answering on it.
+///
+/// The TOTP code is asked for before connecting, so it is still valid when
+/// the login is sent.
+async fn logged_in_session<'a, R>(
+    session: &'a mut Option<ControlStream>,
+    login: &LoginDetails<'_>,
+    reader: &mut R,
+) -> Result<&'a mut ControlStream, Box<dyn Error>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut previous = session.take();
+    if let Some(stream) = previous.as_mut() {
+        if connection_commands::noop(stream).await.is_err() {
+            println!("Connection to the server was lost, logging in again");
+            previous = None;
+        }
+    }
+    let stream = match previous {
+        Some(stream) => stream,
+        None => {
+            let totp_code = if login.totp {
+                let mut input = String::new();
+                println!("Enter TOTP code: ");
+                reader.read_line(&mut input).await?;
+                Some(input.trim().to_string())
+            } else {
+                None
+            };
+            let mut stream = connection_commands::connect(login.settings).await?;
+            connection_commands::login(
+                &mut stream,
+                login.username,
+                login.signer,
+                totp_code.as_deref(),
+            )
+            .await?;
+            stream
+        }
+    };
+    Ok(session.insert(stream))
 }
 
 #[tokio::main]
@@ -28,17 +86,60 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // Parse command-line arguments.
     let args = Cli::parse();
     let username = args.username.clone();
-    let private_key_path = args.private_key_path;
 
-    // Read and sign the username using the private key.
-    let private_key = fs_utils::get_private_key(private_key_path);
-    let text_signed = openssl_utils::sign_message(&private_key, &username)?;
-    let text_signed_copy = text_signed.clone();
+    let config = ClientConfig::load(args.config.as_deref())?;
+    let connection_settings = ConnectionSettings {
+        host: args.host.clone().unwrap_or(config.host),
+        port: args.port.unwrap_or(config.port),
+        data_mode: if args.active {
+            DataMode::Active
+        } else {
+            config.data_mode
+        },
+        known_servers: args.known_servers.clone().unwrap_or(config.known_servers),
+        ca_file: args.ca_file.clone().or(config.ca_file),
+    };
+
+    if let Some(fingerprint) = &args.pin_fingerprint {
+        connection_commands::pin_server(&connection_settings, fingerprint)?;
+    }
+
+    // Pick what signs every login challenge: a key held by the ssh-agent,
+    // or the private key read from disk, asking for the passphrase if it is
+    // encrypted.
+    let signer = match &args.private_key_path {
+        Some(private_key_path) if !args.ssh_agent => {
+            let private_key_pem = fs_utils::get_private_key(private_key_path.clone());
+            let passphrase = if openssl_utils::is_encrypted(&private_key_pem) {
+                Some(cli_utils::read_passphrase(
+                    private_key_path,
+                    args.passphrase_fd,
+                )?)
+            } else {
+                None
+            };
+            LoginSigner::PrivateKey(openssl_utils::load_private_key(
+                &private_key_pem,
+                passphrase.as_deref(),
+            )?)
+        }
+        _ => {
+            let identity = ssh_agent_utils::find_identity(args.agent_key.as_deref()).await?;
+            println!(
+                "Using ssh-agent key {} ({})",
+                identity.fingerprint(),
+                identity.comment
+            );
+            LoginSigner::SshAgent(identity)
+        }
+    };
+    let signer = Arc::new(signer);
 
     // Create a UserContext and start the Rocket web server.
     let user_context = Arc::new(Mutex::new(UserContext {
         username,
-        text_signed,
+        signer: signer.clone(),
+        connection_settings,
     }));
 
     let rocket_handle = {
@@ -52,7 +153,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
                         rocket_utils::list_files_handler,
                         rocket_utils::upload_file_handler,
                         rocket_utils::download_file_handler,
-                        rocket_utils::delete_file_handler
+                        rocket_utils::delete_file_handler,
+                        rocket_utils::quota_handler
                     ],
                 )
                 .launch()
@@ -64,65 +166,107 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let stdin = io::stdin();
     let mut reader = BufReader::new(stdin);
     let mut input = String::new();
+    // The session is logged in once and reused by every command, until
+    // the server stops answering on it.
+    let mut session = None;
 
-    // Enter a loop to handle user commands (list, upload, download, delete, quit, help).
+    // Enter a loop to handle user commands (list, upload, download, delete, quota, mode, quit, help).
     loop {
-        let mut stream = connection_commands::connect()
-            .await
-            .map_err(|e| e.to_string())?;
+        input.clear();
+        println!("Enter command (list, upload, download, delete, quota, mode, quit, help): ");
+        if reader.read_line(&mut input).await? == 0 {
+            break;
+        }
+        let Some(command) = Commands::from_str(input.trim()) else {
+            continue;
+        };
+
+        match command {
+            Commands::Mode { mode } => {
+                user_context.lock().await.connection_settings.data_mode = mode;
+                if let Some(stream) = session.as_mut() {
+                    connection_commands::set_data_mode(stream, mode);
+                }
+                println!("Using {} mode for data connections", mode);
+                continue;
+            }
+            Commands::Quit => {
+                if let Some(mut stream) = session.take() {
+                    match connection_commands::quit(&mut stream).await {
+                        Ok(response) => println!("Quit response: {}", response),
+                        Err(e) => println!("Error on quit: {}", e),
+                    }
+                }
+                break;
+            }
+            Commands::Help => {
+                println!("Available commands:");
+                println!("list - List files");
+                println!("upload <path> - Upload a file");
+                println!("download <filename> - Download a file");
+                println!("delete <filename> - Delete a file");
+                println!("quota - Show storage usage and remaining quota");
+                println!("mode <active|passive> - Switch the data connection mode");
+                println!("quit - Quit the program");
+                continue;
+            }
+            _ => {}
+        }
 
-        let _ = connection_commands::login(
-            &mut stream,
-            args.username.clone().as_str(),
-            text_signed_copy.as_str(),
-        )
-        .await;
+        let connection_settings = user_context.lock().await.connection_settings.clone();
+        let login = LoginDetails {
+            settings: &connection_settings,
+            username: &args.username,
+            signer: &signer,
+            totp: args.totp,
+        };
+        let stream = match logged_in_session(&mut session, &login, &mut reader).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Login error: {}", e);
+                continue;
+            }
+        };
 
-        input.clear();
-        println!("Enter command (list, upload, download, delete, quit, help): ");
-        reader.read_line(&mut input).await?;
-        let input = input.trim();
-
-        if let Some(command) = Commands::from_str(input) {
-            match command {
-                Commands::List => {
-                    let files = connection_commands::list_files(&mut stream).await?;
+        match command {
+            Commands::List => match connection_commands::list_files(stream).await {
+                Ok(files) => {
                     for file in files {
                         println!("{:?}", file);
                     }
                 }
-                Commands::UploadFile { path } => {
-                    let content: String = get_file(path.clone());
+                Err(e) => println!("Error on list files: {}", e),
+            },
+            Commands::UploadFile { path } => {
+                let content: String = get_file(path.clone());
 
-                    let response =
-                        connection_commands::upload_file(&mut stream, &path, content.as_bytes())
-                            .await?;
-                    println!("Upload response: {}", response);
-                }
-                Commands::Download { filename } => {
-                    let content =
-                        connection_commands::download_file(&mut stream, &filename).await?;
-                    write_file_in_downloads(filename.clone(), &content).unwrap();
-                    println!("Downloaded content:\n{}", String::from_utf8_lossy(&content));
+                match connection_commands::upload_file(stream, &path, content.as_bytes()).await {
+                    Ok(response) => println!("Upload response: {}", response),
+                    Err(e) => println!("Error on upload file: {}", e),
                 }
-                Commands::Delete { filename } => {
-                    let response = connection_commands::delete_file(&mut stream, &filename).await?;
-                    println!("Delete response: {}", response);
-                }
-                Commands::Quit => {
-                    let response = connection_commands::quit(&mut stream).await?;
-                    println!("Quit response: {}", response);
-                    break;
+            }
+            Commands::Download { filename } => {
+                match connection_commands::download_file(stream, &filename).await {
+                    Ok(content) => {
+                        if let Err(e) = write_file_in_downloads(filename.clone(), &content) {
+                            println!("Error on save downloaded file: {}", e);
+                        }
+                        println!("Downloaded content:\n{}", String::from_utf8_lossy(&content));
+                    }
+                    Err(e) => println!("Error on download file: {}", e),
                 }
-                Commands::Help => {
-                    println!("Available commands:");
-                    println!("list - List files");
-                    println!("upload <path> - Upload a file");
-                    println!("download <filename> - Download a file");
-                    println!("delete <filename> - Delete a file");
-                    println!("quit - Quit the program");
+            }
+            Commands::Delete { filename } => {
+                match connection_commands::delete_file(stream, &filename).await {
+                    Ok(response) => println!("Delete response: {}", response),
+                    Err(e) => println!("Error on delete file: {}", e),
                 }
             }
+            Commands::Quota => match connection_commands::get_quota(stream).await {
+                Ok(usage) => println!("{}", usage),
+                Err(e) => println!("Error on get quota: {}", e),
+            },
+            Commands::Mode { .. } | Commands::Quit | Commands::Help => {}
         }
     }
 
@@ -1,7 +1,13 @@
-use clap::Parser;
 use clap_derive::Parser;
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
 
-use super::fs_utils::{check_if_file_exists, get_file};
+use super::connection_commands::{self, DataMode};
+use super::fs_utils::check_if_file_exists;
+
+/// Environment variable holding the passphrase of an encrypted private key.
+pub const PASSPHRASE_ENV: &str = "FTP_CLIENT_KEY_PASSPHRASE";
 
 /// Represents the command-line arguments.
 #[derive(Parser)]
@@ -11,8 +17,122 @@ pub(crate) struct Cli {
     #[arg(short, long)]
     pub username: String,
 
+    /// TOML config file with the server to connect to and how, the built-in
+    /// defaults are used when omitted. The arguments below win over it.
     #[arg(short, long)]
-    pub private_key_path: String,
+    pub config: Option<PathBuf>,
+
+    /// Host name or IP address of the FTP server [default: 127.0.0.1].
+    #[arg(long, value_parser = connection_commands::parse_host)]
+    pub host: Option<String>,
+
+    /// Port of the FTP server [default: 2121].
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    #[arg(short, long, required_unless_present = "ssh_agent")]
+    pub private_key_path: Option<String>,
+
+    /// Sign logins with a key held by the ssh-agent at `SSH_AUTH_SOCK`
+    /// instead of reading the private key from disk.
+    #[arg(long)]
+    pub ssh_agent: bool,
+
+    /// Fingerprint or comment of the ssh-agent key to use. Defaults to the
+    /// first supported key.
+    #[arg(long, requires = "ssh_agent")]
+    pub agent_key: Option<String>,
+
+    /// Prompt for a TOTP code at every login, for accounts with a second
+    /// factor.
+    #[arg(long)]
+    pub totp: bool,
+
+    /// Read the private key passphrase from this file descriptor.
+    #[arg(long)]
+    pub passphrase_fd: Option<i32>,
+
+    /// File holding the pinned certificate fingerprints of the servers
+    /// [default: known_servers].
+    #[arg(long)]
+    pub known_servers: Option<PathBuf>,
+
+    /// Pin the server certificate to this SHA-256 fingerprint before
+    /// connecting, replacing any previous pin.
+    #[arg(long)]
+    pub pin_fingerprint: Option<String>,
+
+    /// Also verify the server certificate chain and address against this CA
+    /// bundle (PEM).
+    #[arg(long)]
+    pub ca_file: Option<PathBuf>,
+
+    /// Use active mode (EPRT/PORT) for data connections: the server connects
+    /// back to the client instead of the other way around.
+    #[arg(long)]
+    pub active: bool,
+}
+
+/// Gets the passphrase of an encrypted private key.
+///
+/// The passphrase is read from `passphrase_fd` if given, then from the
+/// `FTP_CLIENT_KEY_PASSPHRASE` environment variable, and otherwise the user is
+/// prompted for it.
+pub fn read_passphrase(
+    private_key_path: &str,
+    passphrase_fd: Option<i32>,
+) -> Result<String, Box<dyn Error>> {
+    if let Some(fd) = passphrase_fd {
+        return read_passphrase_fd(fd);
+    }
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    Ok(rpassword::prompt_password(format!(
+        "Enter passphrase for {}: ",
+        private_key_path
+    ))?)
+}
+
+/// Reads the first line of the given file descriptor as the passphrase.
+///
+/// The descriptor stays open and nothing after the line is consumed, so it
+/// can also be standard input.
+#[cfg(unix)]
+fn read_passphrase_fd(fd: i32) -> Result<String, Box<dyn Error>> {
+    use std::io::Read;
+    use std::os::unix::io::BorrowedFd;
+
+    if fd < 0 {
+        return Err(Box::from(format!(
+            "Invalid passphrase file descriptor {}",
+            fd
+        )));
+    }
+    // SAFETY: the descriptor is only borrowed to duplicate it, which fails
+    // cleanly when it is not open. Reading goes through the duplicate, so
+    // the descriptor given by the caller is never closed.
+    let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+    let owned = borrowed
+        .try_clone_to_owned()
+        .map_err(|e| format!("Cannot read passphrase file descriptor {}: {}", fd, e))?;
+    let mut file = std::fs::File::from(owned);
+
+    let mut passphrase = Vec::new();
+    let mut byte = [0u8; 1];
+    while file.read(&mut byte)? == 1 && byte[0] != b'\n' {
+        passphrase.push(byte[0]);
+    }
+    let passphrase = String::from_utf8(passphrase)?;
+    Ok(passphrase.trim_end_matches('\r').to_string())
+}
+
+/// Reads the first line of the given file descriptor as the passphrase.
+#[cfg(not(unix))]
+fn read_passphrase_fd(_fd: i32) -> Result<String, Box<dyn Error>> {
+    Err(Box::from(
+        "Reading the passphrase from a file descriptor is only supported on Unix",
+    ))
 }
 
 /// Enum representing the various commands supported by the CLI.
@@ -22,6 +142,8 @@ pub enum Commands {
     UploadFile { path: String },
     Download { filename: String },
     Delete { filename: String },
+    Quota,
+    Mode { mode: DataMode },
     Quit,
     Help,
 }
@@ -43,7 +165,7 @@ impl Commands {
                         println!("File does not exist.");
                         return None;
                     }
-                    Some(Commands::UploadFile { path: path })
+                    Some(Commands::UploadFile { path })
                 } else {
                     println!("No path provided.");
                     None
@@ -69,6 +191,18 @@ impl Commands {
                     None
                 }
             }
+            "quota" => Some(Commands::Quota),
+            "mode" => match argument.map(str::parse) {
+                Some(Ok(mode)) => Some(Commands::Mode { mode }),
+                Some(Err(e)) => {
+                    println!("{}", e);
+                    None
+                }
+                None => {
+                    println!("No mode provided");
+                    None
+                }
+            },
             "quit" => Some(Commands::Quit),
             "help" => Some(Commands::Help),
             _ => {
@@ -78,3 +212,39 @@ impl Commands {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn reads_passphrase_from_fd_without_closing_it() {
+        use std::io::{Read, Write};
+        use std::os::unix::io::AsRawFd;
+
+        let (mut reader, mut writer) = std::io::pipe().unwrap();
+        writer.write_all(b"secret pass\r\nnext line\n").unwrap();
+        drop(writer);
+
+        assert_eq!(
+            read_passphrase_fd(reader.as_raw_fd()).unwrap(),
+            "secret pass"
+        );
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "next line\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_bad_passphrase_fds() {
+        assert!(read_passphrase_fd(-1).is_err());
+        assert!(read_passphrase_fd(i32::MAX).is_err());
+    }
+
+    #[test]
+    fn passphrase_fd_wins_over_environment() {
+        assert!(read_passphrase("key.pem", Some(-1)).is_err());
+    }
+}
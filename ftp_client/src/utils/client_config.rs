@@ -0,0 +1,131 @@
+use serde::{Deserialize, Deserializer};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::connection_commands::{self, DataMode};
+use super::known_servers_utils;
+
+/// Settings of the client, read from the TOML file given with `--config`.
+///
+/// Every field is optional and defaults to the value the client used before
+/// it had a config file. Command-line arguments win over the file.
+///
+/// ```toml
+/// host = "ftp.example.com"
+/// port = 2121
+/// data_mode = "passive"
+/// known_servers = "known_servers"
+/// ca_file = "ca.pem"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Host name or IP address of the FTP server, IPv6 addresses with or
+    /// without brackets.
+    #[serde(deserialize_with = "host")]
+    pub host: String,
+    /// Port of the FTP server.
+    pub port: u16,
+    /// Who opens the data connections, `"passive"` or `"active"`.
+    pub data_mode: DataMode,
+    /// File holding the pinned certificate fingerprints of the servers.
+    pub known_servers: PathBuf,
+    /// CA bundle the server certificate chain is also verified against.
+    pub ca_file: Option<PathBuf>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            host: connection_commands::DEFAULT_HOST.to_string(),
+            port: connection_commands::DEFAULT_PORT,
+            data_mode: DataMode::Passive,
+            known_servers: PathBuf::from(known_servers_utils::DEFAULT_KNOWN_SERVERS_PATH),
+            ca_file: None,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Reads the config file, or uses the defaults when no path is given.
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let Some(path) = path else {
+            return Ok(ClientConfig::default());
+        };
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read config file {}: {}", path.display(), e))?;
+        let config = toml::from_str(&content)
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+}
+
+/// Reads the host the same way as the `--host` argument.
+fn host<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    connection_commands::parse_host(&String::deserialize(deserializer)?)
+        .map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_config_file() {
+        let config: ClientConfig = toml::from_str(
+            r#"
+            host = "[::1]"
+            port = 990
+            data_mode = "active"
+            known_servers = "/etc/ftp_client/known_servers"
+            ca_file = "ca.pem"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            ClientConfig {
+                host: "::1".to_string(),
+                port: 990,
+                data_mode: DataMode::Active,
+                known_servers: PathBuf::from("/etc/ftp_client/known_servers"),
+                ca_file: Some(PathBuf::from("ca.pem")),
+            }
+        );
+    }
+
+    #[test]
+    fn defaults_match_the_client_without_config() {
+        assert_eq!(
+            toml::from_str::<ClientConfig>("").unwrap(),
+            ClientConfig::default()
+        );
+        assert_eq!(ClientConfig::load(None).unwrap(), ClientConfig::default());
+        let config: ClientConfig = toml::from_str("host = \"ftp.example.com\"").unwrap();
+        assert_eq!(config.host, "ftp.example.com");
+        assert_eq!(config.port, connection_commands::DEFAULT_PORT);
+        assert_eq!(config.data_mode, DataMode::Passive);
+    }
+
+    #[test]
+    fn rejects_bad_config() {
+        for content in [
+            "host = \"\"",
+            "host = \"[]\"",
+            "port = 70000",
+            "data_mode = \"both\"",
+            "user = \"fk\"",
+        ] {
+            assert!(
+                toml::from_str::<ClientConfig>(content).is_err(),
+                "{}",
+                content
+            );
+        }
+        assert!(ClientConfig::load(Some(Path::new("does/not/exist.toml"))).is_err());
+    }
+}
@@ -1,8 +1,132 @@
+use openssl::ssl::{Ssl, SslConnector, SslMethod, SslVerifyMode};
 use std::error::Error;
-use tokio::io::AsyncBufReadExt;
-use tokio::io::{AsyncBufReadExt as _, AsyncWriteExt};
-use tokio::io::{AsyncReadExt, BufReader};
-use tokio::net::TcpStream;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_openssl::SslStream;
+
+use super::ftp_reply::{self, Reply, ReplyClass};
+use super::known_servers_utils;
+use super::openssl_utils::{self, LoginSigner};
+
+/// Host of the FTP server when none is configured.
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+/// Port of the FTP server when none is configured.
+pub const DEFAULT_PORT: u16 = 2121;
+
+/// How long the server has to open an active mode data connection.
+const ACTIVE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Bounds of the length of the login nonce sent by the server.
+const MIN_NONCE_LENGTH: usize = 16;
+const MAX_NONCE_LENGTH: usize = 128;
+
+/// Who opens the data connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataMode {
+    /// The client connects to a port opened by the server (EPSV/PASV).
+    Passive,
+    /// The server connects to a port opened by the client (EPRT/PORT), for
+    /// servers behind firewalls that block their passive ports.
+    Active,
+}
+
+impl std::str::FromStr for DataMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "passive" => Ok(DataMode::Passive),
+            "active" => Ok(DataMode::Active),
+            _ => Err(format!(
+                "Unknown data mode {}, expected active or passive",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DataMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataMode::Passive => write!(f, "passive"),
+            DataMode::Active => write!(f, "active"),
+        }
+    }
+}
+
+/// How the connections to the FTP server are made and verified.
+#[derive(Debug, Clone)]
+pub struct ConnectionSettings {
+    /// Host name or IP address of the FTP server, resolved with DNS.
+    pub host: String,
+    pub port: u16,
+    /// Who opens the data connections.
+    pub data_mode: DataMode,
+    /// File holding the pinned certificate fingerprints of the servers.
+    pub known_servers: PathBuf,
+    /// CA bundle the server certificate chain must be verified against.
+    /// Without it, the server is only trusted through its pinned fingerprint.
+    pub ca_file: Option<PathBuf>,
+}
+
+impl ConnectionSettings {
+    /// The `host:port` address of the server, with IPv6 addresses in
+    /// brackets. Certificates are pinned under this address.
+    pub fn server_address(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Parses the host given by the user, accepting IPv6 addresses with or
+/// without brackets.
+pub fn parse_host(host: &str) -> Result<String, String> {
+    let host = host.trim();
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() {
+        return Err("Host cannot be empty".to_string());
+    }
+    Ok(host.to_string())
+}
+
+/// The control connection to the FTP server, secured with TLS.
+pub struct ControlStream {
+    tls: SslStream<TcpStream>,
+    connector: SslConnector,
+    host: String,
+    fingerprint: String,
+    data_mode: DataMode,
+    /// Whether to open data connections with EPSV. Cleared when the server
+    /// does not support it, so PASV is used for the rest of the session.
+    extended_passive: bool,
+    /// Whether to announce active mode ports with EPRT rather than PORT,
+    /// cleared the same way.
+    extended_active: bool,
+}
+
+/// A data connection to the FTP server, secured with TLS.
+type DataStream = SslStream<TcpStream>;
+
+/// A data connection before the transfer command is sent.
+enum DataConnection {
+    /// Already connected to the passive port of the server.
+    Passive(TcpStream),
+    /// Waiting for the server to connect.
+    Active(TcpListener),
+}
 
 /// Represents a file entry in the FTP server's directory listing.
 #[derive(serde::Serialize, Debug, Clone)]
@@ -18,52 +142,371 @@ pub struct FileEntry {
     name: String,
 }
 
-async fn get_response(stream: &mut TcpStream) -> String {
-    let mut reader = BufReader::new(stream);
-    let mut response = String::new();
-    reader.read_line(&mut response).await.unwrap();
-    response
+/// Storage used by the user on the FTP server and the quota limiting it.
+///
+/// Limits and remaining amounts are `None` when unlimited.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct QuotaUsage {
+    used_bytes: u64,
+    used_files: u64,
+    max_bytes: Option<u64>,
+    max_files: Option<u64>,
+    remaining_bytes: Option<u64>,
+    remaining_files: Option<u64>,
 }
 
-async fn send_command(stream: &mut TcpStream, command: &str) {
-    stream.write_all(command.as_bytes()).await.unwrap();
-    stream.flush().await.unwrap();
+impl fmt::Display for QuotaUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let limit = |max: Option<u64>| max.map_or("unlimited".to_string(), |max| max.to_string());
+        writeln!(
+            f,
+            "Bytes: {} used of {} ({} remaining)",
+            self.used_bytes,
+            limit(self.max_bytes),
+            limit(self.remaining_bytes)
+        )?;
+        write!(
+            f,
+            "Files: {} used of {} ({} remaining)",
+            self.used_files,
+            limit(self.max_files),
+            limit(self.remaining_files)
+        )
+    }
+}
+
+async fn get_response(stream: &mut ControlStream) -> Result<Reply, Box<dyn Error>> {
+    ftp_reply::read_reply(&mut stream.tls).await
 }
 
-/// Connects to the FTP server.
-pub async fn connect() -> Result<TcpStream, Box<dyn Error>> {
-    let mut stream = TcpStream::connect("127.0.0.1:2121").await?;
-    get_response(&mut stream).await;
+async fn send_command(stream: &mut ControlStream, command: &str) -> Result<(), Box<dyn Error>> {
+    stream.tls.write_all(command.as_bytes()).await?;
+    stream.tls.flush().await?;
+    Ok(())
+}
+
+/// Connects to the FTP server and secures the control connection with
+/// AUTH TLS, then asks for every data connection to be secured too (PBSZ 0,
+/// PROT P).
+///
+/// The server certificate is checked against the fingerprint pinned in the
+/// known servers file, and pinned there on the first connection. When a CA
+/// bundle is configured, its chain and address are verified as well.
+pub async fn connect(settings: &ConnectionSettings) -> Result<ControlStream, Box<dyn Error>> {
+    let server_address = settings.server_address();
+    let mut stream = TcpStream::connect((settings.host.as_str(), settings.port))
+        .await
+        .map_err(|e| format!("Cannot connect to {}: {}", server_address, e))?;
+    // A 120 reply asks to wait for the 220 that follows it.
+    let mut greeting = ftp_reply::read_reply(&mut stream).await?;
+    while greeting.code == 120 {
+        greeting = ftp_reply::read_reply(&mut stream).await?;
+    }
+    greeting.expect("Connection", &[220])?;
+
+    stream.write_all(b"AUTH TLS\r\n").await?;
+    ftp_reply::read_reply(&mut stream)
+        .await?
+        .expect("AUTH TLS", &[234])?;
+
+    let connector = tls_connector(settings)?;
+    let mut tls = SslStream::new(tls_session(&connector, settings, &settings.host)?, stream)?;
+    Pin::new(&mut tls)
+        .connect()
+        .await
+        .map_err(|e| format!("TLS handshake with {} failed: {}", server_address, e))?;
+
+    let certificate = tls
+        .ssl()
+        .peer_certificate()
+        .ok_or("Server did not present a certificate")?;
+    let fingerprint = known_servers_utils::certificate_fingerprint(&certificate)?;
+    known_servers_utils::check_server(&settings.known_servers, &server_address, &fingerprint)?;
+
+    let mut stream = ControlStream {
+        tls,
+        connector,
+        host: settings.host.clone(),
+        fingerprint,
+        data_mode: settings.data_mode,
+        extended_passive: true,
+        extended_active: true,
+    };
+    for command in ["PBSZ 0\r\n", "PROT P\r\n"] {
+        send_command(&mut stream, command).await?;
+        get_response(&mut stream)
+            .await?
+            .expect(command.trim(), &[200])?;
+    }
     Ok(stream)
 }
 
+/// Builds the TLS connector used for the control and data connections.
+fn tls_connector(settings: &ConnectionSettings) -> Result<SslConnector, Box<dyn Error>> {
+    let mut connector = SslConnector::builder(SslMethod::tls_client())?;
+    match &settings.ca_file {
+        Some(ca_file) => {
+            connector.set_ca_file(ca_file)?;
+            connector.set_verify(SslVerifyMode::PEER);
+        }
+        // The certificate is trusted because of its pinned fingerprint, not
+        // because of its chain, so self-signed certificates can be used.
+        None => connector.set_verify(SslVerifyMode::NONE),
+    }
+    Ok(connector.build())
+}
+
+/// Creates the TLS session of one connection, checking the server address
+/// against its certificate when a CA bundle is configured.
+///
+/// Host names are sent with SNI so servers hosting several names can pick
+/// the right certificate.
+fn tls_session(
+    connector: &SslConnector,
+    settings: &ConnectionSettings,
+    host: &str,
+) -> Result<Ssl, Box<dyn Error>> {
+    let mut config = connector.configure()?;
+    let ip = host.parse::<IpAddr>().ok();
+    config.set_use_server_name_indication(ip.is_none());
+    config.set_verify_hostname(settings.ca_file.is_some() && ip.is_none());
+    if let (Some(ip), Some(_)) = (ip, &settings.ca_file) {
+        config.param_mut().set_ip(ip)?;
+    }
+    Ok(config.into_ssl(host)?)
+}
+
+/// Prepares a data connection in the mode of the session.
+///
+/// TLS is only negotiated once the transfer command has been sent, since
+/// the server starts its side of the handshake when it handles the command.
+async fn open_data_connection(
+    stream: &mut ControlStream,
+) -> Result<DataConnection, Box<dyn Error>> {
+    match stream.data_mode {
+        DataMode::Passive => Ok(DataConnection::Passive(
+            open_passive_connection(stream).await?,
+        )),
+        DataMode::Active => Ok(DataConnection::Active(open_active_listener(stream).await?)),
+    }
+}
+
+/// Opens the TCP part of a passive data connection.
+///
+/// EPSV is tried first since it also works over IPv6. Servers that do not
+/// support it get PASV instead, which only works over IPv4. Either way the
+/// data connection goes to the address of the control connection, the
+/// address in a PASV reply is ignored so a server cannot point the client
+/// at another host.
+async fn open_passive_connection(stream: &mut ControlStream) -> Result<TcpStream, Box<dyn Error>> {
+    let server_ip = stream.tls.get_ref().peer_addr()?.ip();
+
+    if stream.extended_passive {
+        send_command(stream, "EPSV\r\n").await?;
+        let reply = get_response(stream).await?;
+        if reply.code == 229 {
+            let port = parse_epsv_response(&reply.text())?;
+            return Ok(TcpStream::connect(SocketAddr::new(server_ip, port)).await?);
+        }
+        if reply.class() != ReplyClass::PermanentNegative {
+            return Err(Box::from(format!("EPSV failed: {}", reply)));
+        }
+        stream.extended_passive = false;
+    }
+
+    if server_ip.is_ipv6() {
+        return Err(Box::from(
+            "Server does not support EPSV, which is needed over IPv6",
+        ));
+    }
+    send_command(stream, "PASV\r\n").await?;
+    let reply = get_response(stream).await?.expect("PASV", &[227])?;
+    let port = parse_pasv_response(&reply.text())?.port();
+    Ok(TcpStream::connect(SocketAddr::new(server_ip, port)).await?)
+}
+
+/// Opens a port for an active mode data connection and tells the server to
+/// connect to it.
+///
+/// The port is opened on the address the control connection comes from.
+/// EPRT is tried first since it also works over IPv6, servers that do not
+/// support it get PORT instead, which only works over IPv4.
+async fn open_active_listener(stream: &mut ControlStream) -> Result<TcpListener, Box<dyn Error>> {
+    let local_ip = stream.tls.get_ref().local_addr()?.ip();
+    let listener = TcpListener::bind(SocketAddr::new(local_ip, 0)).await?;
+    let address = listener.local_addr()?;
+
+    if stream.extended_active {
+        send_command(
+            stream,
+            &format!("EPRT {}\r\n", format_eprt_argument(address)),
+        )
+        .await?;
+        let reply = get_response(stream).await?;
+        if reply.code == 200 {
+            return Ok(listener);
+        }
+        if reply.class() != ReplyClass::PermanentNegative {
+            return Err(Box::from(format!("EPRT failed: {}", reply)));
+        }
+        stream.extended_active = false;
+    }
+
+    let SocketAddr::V4(address) = address else {
+        return Err(Box::from(
+            "Server does not support EPRT, which is needed over IPv6",
+        ));
+    };
+    send_command(
+        stream,
+        &format!(
+            "PORT {}\r\n",
+            format_port_argument(address.ip(), address.port())
+        ),
+    )
+    .await?;
+    get_response(stream).await?.expect("PORT", &[200])?;
+    Ok(listener)
+}
+
+impl DataConnection {
+    /// Waits for the data connection to be established, once the transfer
+    /// command has been sent.
+    ///
+    /// In active mode, connections from another address than the server are
+    /// refused, so nobody else can inject or steal the transfer.
+    async fn establish(self, stream: &ControlStream) -> Result<TcpStream, Box<dyn Error>> {
+        let listener = match self {
+            DataConnection::Passive(data_stream) => return Ok(data_stream),
+            DataConnection::Active(listener) => listener,
+        };
+        let server_ip = stream.tls.get_ref().peer_addr()?.ip();
+        let (data_stream, peer_addr) =
+            tokio::time::timeout(ACTIVE_CONNECTION_TIMEOUT, listener.accept())
+                .await
+                .map_err(|_| "Server did not open the active mode data connection")??;
+        if peer_addr.ip() != server_ip {
+            return Err(Box::from(format!(
+                "Refused active mode data connection from {}, expected {}",
+                peer_addr, server_ip
+            )));
+        }
+        Ok(data_stream)
+    }
+}
+
+/// Secures a data connection with TLS, checking that the server presents
+/// the same certificate as on the control connection.
+///
+/// The client is the TLS client in both passive and active mode.
+async fn secure_data_connection(
+    stream: &ControlStream,
+    data_connection: DataConnection,
+) -> Result<DataStream, Box<dyn Error>> {
+    let data_stream = data_connection.establish(stream).await?;
+    let ssl = stream
+        .connector
+        .configure()?
+        .use_server_name_indication(stream.host.parse::<IpAddr>().is_err())
+        .verify_hostname(false)
+        .into_ssl(&stream.host)?;
+    let mut tls = SslStream::new(ssl, data_stream)?;
+    Pin::new(&mut tls).connect().await?;
+
+    let certificate = tls
+        .ssl()
+        .peer_certificate()
+        .ok_or("Server did not present a certificate on the data connection")?;
+    if known_servers_utils::certificate_fingerprint(&certificate)? != stream.fingerprint {
+        return Err(Box::from(
+            "Data connection certificate does not match the control connection",
+        ));
+    }
+    Ok(tls)
+}
+
+/// Pins the certificate fingerprint of the FTP server.
+pub fn pin_server(settings: &ConnectionSettings, fingerprint: &str) -> Result<(), Box<dyn Error>> {
+    let fingerprint = known_servers_utils::parse_fingerprint(fingerprint)?;
+    let server_address = settings.server_address();
+    known_servers_utils::pin_server(&settings.known_servers, &server_address, &fingerprint)?;
+    println!(
+        "Pinned certificate of {} to {}",
+        server_address, fingerprint
+    );
+    Ok(())
+}
+
 /// Logs into the FTP server.
+///
+/// The password signs the nonce the server sent for this login, so a
+/// captured password cannot be replayed. Accounts with a second factor also
+/// need the current TOTP code.
 pub async fn login(
-    stream: &mut TcpStream,
+    stream: &mut ControlStream,
     username: &str,
-    password: &str,
-) -> Result<String, Box<dyn Error>> {
-    send_command(stream, &format!("USER {}\r\n", username)).await;
-    get_response(stream).await;
+    signer: &LoginSigner,
+    totp_code: Option<&str>,
+) -> Result<Reply, Box<dyn Error>> {
+    send_command(stream, &format!("USER {}\r\n", username)).await?;
+    let reply = get_response(stream).await?.expect("USER", &[230, 331])?;
+    if reply.code == 230 {
+        return Ok(reply);
+    }
 
-    send_command(stream, &format!("PASS {}\r\n", password)).await;
-    let login_response = get_response(stream).await;
-    Ok(login_response)
+    let nonce = login_nonce(&reply)?;
+    let password = openssl_utils::sign_login(signer, username, nonce, totp_code).await?;
+    send_command(stream, &format!("PASS {}\r\n", password)).await?;
+    get_response(stream).await?.expect("Login", &[202, 230])
 }
 
-/// Lists files on the FTP server.
-pub async fn list_files(stream: &mut TcpStream) -> Result<Vec<FileEntry>, Box<dyn Error>> {
-    send_command(stream, "PASV\r\n").await;
-    let pasv_response = get_response(stream).await;
+/// Finds the nonce to sign in the reply to USER, the hexadecimal word after
+/// `nonce`.
+fn login_nonce(reply: &Reply) -> Result<&str, Box<dyn Error>> {
+    let mut words = reply.lines.iter().flat_map(|line| line.split_whitespace());
+    let nonce = words
+        .find(|word| word.eq_ignore_ascii_case("nonce"))
+        .and_then(|_| words.next())
+        .ok_or_else(|| format!("Server did not send a login nonce: {}", reply))?;
+    if !(MIN_NONCE_LENGTH..=MAX_NONCE_LENGTH).contains(&nonce.len())
+        || !nonce.bytes().all(|byte| byte.is_ascii_hexdigit())
+    {
+        return Err(Box::from(format!(
+            "Invalid login nonce from the server: {}",
+            reply
+        )));
+    }
+    Ok(nonce)
+}
+
+/// Waits for the reply accepting a transfer command, sent before the data
+/// connection is used.
+async fn start_transfer(stream: &mut ControlStream, command: &str) -> Result<(), Box<dyn Error>> {
+    get_response(stream).await?.expect(command, &[125, 150])?;
+    Ok(())
+}
 
-    let (ip, port) = parse_pasv_response(&pasv_response)?;
+/// Waits for the reply confirming the transfer, once the data connection
+/// is closed.
+async fn finish_transfer(
+    stream: &mut ControlStream,
+    command: &str,
+) -> Result<Reply, Box<dyn Error>> {
+    get_response(stream).await?.expect(command, &[226, 250])
+}
 
-    let mut data_stream = TcpStream::connect(format!("{}:{}", ip, port)).await?;
+/// Lists files on the FTP server.
+pub async fn list_files(stream: &mut ControlStream) -> Result<Vec<FileEntry>, Box<dyn Error>> {
+    let data_connection = open_data_connection(stream).await?;
 
-    send_command(stream, "LIST\r\n").await;
+    send_command(stream, "LIST\r\n").await?;
+    start_transfer(stream, "LIST").await?;
+    let mut data_stream = secure_data_connection(stream, data_connection).await?;
 
     let mut files = String::new();
     data_stream.read_to_string(&mut files).await?;
+    drop(data_stream);
+    finish_transfer(stream, "LIST").await?;
 
     let file_entries = parse_file_entries(&files)?;
     Ok(file_entries)
@@ -95,73 +538,344 @@ fn parse_file_entries(list_output: &str) -> Result<Vec<FileEntry>, Box<dyn Error
 
 /// Uploads a file to the FTP server.
 pub async fn upload_file(
-    stream: &mut TcpStream,
+    stream: &mut ControlStream,
     path: &str,
     content: &[u8],
 ) -> Result<String, Box<dyn Error>> {
-    send_command(stream, "PASV\r\n").await;
-    let pasv_response = get_response(stream).await;
+    let data_connection = open_data_connection(stream).await?;
 
-    let (ip, port) = parse_pasv_response(&pasv_response)?;
+    let filename = path.split('/').next_back().unwrap();
 
-    let mut data_stream = TcpStream::connect(format!("{}:{}", ip, port)).await?;
+    send_command(stream, &format!("STOR {}\r\n", filename)).await?;
+    start_transfer(stream, "Upload").await?;
+    let mut data_stream = secure_data_connection(stream, data_connection).await?;
 
-    let filename = path.split('/').last().unwrap();
+    // The server stops reading when the upload exceeds the quota, so a write
+    // error is only reported if the server did not explain it.
+    let written = match data_stream.write_all(content).await {
+        Ok(()) => data_stream.shutdown().await,
+        Err(e) => Err(e),
+    };
+    drop(data_stream);
 
-    send_command(stream, &format!("STOR {}\r\n", filename)).await;
-
-    let response = get_response(stream).await;
-    if !response.starts_with("150") {
-        return Err(Box::from("Failed to start upload"));
+    let reply = get_response(stream).await?;
+    if reply.code == 552 {
+        return Err(Box::from(format!("Storage quota exceeded: {}", reply)));
     }
-
-    data_stream.write_all(content).await?;
-    data_stream.flush().await?;
+    written?;
+    reply.expect("Upload", &[226, 250])?;
 
     Ok("Upload successful".to_string())
 }
 
 /// Downloads a file from the FTP server.
 pub async fn download_file(
-    stream: &mut TcpStream,
+    stream: &mut ControlStream,
     filename: &str,
 ) -> Result<Vec<u8>, Box<dyn Error>> {
-    send_command(stream, "PASV\r\n").await;
-    let pasv_response = get_response(stream).await;
+    let data_connection = open_data_connection(stream).await?;
 
-    let (ip, port) = parse_pasv_response(&pasv_response)?;
-
-    let mut data_stream = TcpStream::connect(format!("{}:{}", ip, port)).await?;
-
-    send_command(stream, &format!("RETR {}\r\n", filename)).await;
+    send_command(stream, &format!("RETR {}\r\n", filename)).await?;
+    start_transfer(stream, "Download").await?;
+    let mut data_stream = secure_data_connection(stream, data_connection).await?;
 
     let mut content = Vec::new();
     data_stream.read_to_end(&mut content).await?;
+    drop(data_stream);
+    finish_transfer(stream, "Download").await?;
 
     Ok(content)
 }
 
+/// Gets the storage usage and remaining quota of the user.
+///
+/// The server answers `SITE QUOTA` with one `key=value` per reply line.
+pub async fn get_quota(stream: &mut ControlStream) -> Result<QuotaUsage, Box<dyn Error>> {
+    send_command(stream, "SITE QUOTA\r\n").await?;
+    let reply = get_response(stream).await?.expect("Quota", &[211])?;
+    parse_quota_usage(&reply.lines.join("\n"))
+}
+
+fn parse_quota_usage(report: &str) -> Result<QuotaUsage, Box<dyn Error>> {
+    let mut used_bytes = None;
+    let mut used_files = None;
+    let mut max_bytes = None;
+    let mut max_files = None;
+    let limit = |value: &str| -> Result<Option<u64>, Box<dyn Error>> {
+        match value {
+            "none" => Ok(None),
+            value => Ok(Some(value.parse()?)),
+        }
+    };
+
+    for line in report.lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        match key {
+            "used_bytes" => used_bytes = Some(value.parse()?),
+            "used_files" => used_files = Some(value.parse()?),
+            "max_bytes" => max_bytes = limit(value)?,
+            "max_files" => max_files = limit(value)?,
+            _ => {}
+        }
+    }
+
+    let (Some(used_bytes), Some(used_files)) = (used_bytes, used_files) else {
+        return Err(Box::from("Invalid quota response"));
+    };
+    Ok(QuotaUsage {
+        used_bytes,
+        used_files,
+        max_bytes,
+        max_files,
+        remaining_bytes: max_bytes.map(|max| max.saturating_sub(used_bytes)),
+        remaining_files: max_files.map(|max| max.saturating_sub(used_files)),
+    })
+}
+
 /// Deletes a file from the FTP server.
-pub async fn delete_file(stream: &mut TcpStream, filename: &str) -> Result<String, Box<dyn Error>> {
-    send_command(stream, &format!("DELE {}\r\n", filename)).await;
-    let response = get_response(stream).await;
-    Ok(response)
+pub async fn delete_file(
+    stream: &mut ControlStream,
+    filename: &str,
+) -> Result<Reply, Box<dyn Error>> {
+    send_command(stream, &format!("DELE {}\r\n", filename)).await?;
+    get_response(stream).await?.expect("Delete", &[250])
+}
+
+/// Checks that the server still answers on the control connection.
+pub async fn noop(stream: &mut ControlStream) -> Result<Reply, Box<dyn Error>> {
+    send_command(stream, "NOOP\r\n").await?;
+    get_response(stream).await?.expect("NOOP", &[200])
+}
+
+/// Switches who opens the data connections for the rest of the session.
+pub fn set_data_mode(stream: &mut ControlStream, data_mode: DataMode) {
+    stream.data_mode = data_mode;
 }
 
 /// Sends the QUIT command to the FTP server.
-pub async fn quit(stream: &mut TcpStream) -> Result<String, Box<dyn Error>> {
-    send_command(stream, "QUIT\r\n").await;
-    let response = get_response(stream).await;
-    Ok(response)
+pub async fn quit(stream: &mut ControlStream) -> Result<Reply, Box<dyn Error>> {
+    send_command(stream, "QUIT\r\n").await?;
+    get_response(stream).await?.expect("QUIT", &[221])
 }
 
-fn parse_pasv_response(response: &str) -> Result<(String, u16), Box<dyn Error>> {
-    let start = response.find('(').expect("Invalid PASV response") + 1;
-    let end = response.rfind(')').expect("Invalid PASV response");
-    let fields: Vec<&str> = response[start..end].split(',').collect();
+/// Formats the `|1|ip|port|` argument of EPRT.
+fn format_eprt_argument(address: SocketAddr) -> String {
+    let protocol = if address.is_ipv4() { 1 } else { 2 };
+    format!("|{}|{}|{}|", protocol, address.ip(), address.port())
+}
 
-    let ip = format!("{}.{}.{}.{}", fields[0], fields[1], fields[2], fields[3]);
-    let port = (fields[4].parse::<u16>()? << 8) + fields[5].parse::<u16>()?;
+/// Formats the `h1,h2,h3,h4,p1,p2` argument of PORT.
+fn format_port_argument(ip: &Ipv4Addr, port: u16) -> String {
+    let [h1, h2, h3, h4] = ip.octets();
+    let [p1, p2] = port.to_be_bytes();
+    format!("{},{},{},{},{},{}", h1, h2, h3, h4, p1, p2)
+}
+
+/// Parses the text between the parentheses of a passive mode reply.
+fn reply_parameters(response: &str) -> Option<&str> {
+    let start = response.find('(')? + 1;
+    let end = response.rfind(')')?;
+    response.get(start..end)
+}
 
-    Ok((ip, port))
+/// Parses the address of a `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)`
+/// reply.
+fn parse_pasv_response(response: &str) -> Result<SocketAddr, Box<dyn Error>> {
+    let invalid = || format!("Invalid PASV response: {}", response.trim());
+    let fields: Vec<u8> = reply_parameters(response)
+        .ok_or_else(invalid)?
+        .split(',')
+        .map(|field| field.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid())?;
+    let [h1, h2, h3, h4, p1, p2] = fields[..] else {
+        return Err(Box::from(invalid()));
+    };
+
+    let ip = Ipv4Addr::new(h1, h2, h3, h4);
+    let port = u16::from_be_bytes([p1, p2]);
+    Ok(SocketAddr::new(IpAddr::V4(ip), port))
+}
+
+/// Parses the port of a `229 Entering Extended Passive Mode (|||port|)`
+/// reply. The delimiter is whatever character the server chose.
+fn parse_epsv_response(response: &str) -> Result<u16, Box<dyn Error>> {
+    let invalid = || format!("Invalid EPSV response: {}", response.trim());
+    let parameters = reply_parameters(response).ok_or_else(invalid)?;
+    let delimiter = parameters.chars().next().ok_or_else(invalid)?;
+    let fields: Vec<&str> = parameters.split(delimiter).collect();
+    let [_, "", "", port, ""] = fields[..] else {
+        return Err(Box::from(invalid()));
+    };
+    Ok(port.parse().map_err(|_| invalid())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    #[test]
+    fn parses_pasv_responses() {
+        for (response, address) in [
+            (
+                "227 Entering Passive Mode (192,168,1,2,195,80)",
+                "192.168.1.2:50000",
+            ),
+            ("227 Entering Passive Mode (10,0,0,1,0,21).", "10.0.0.1:21"),
+            (
+                "227 Entering Passive Mode ( 127, 0, 0, 1, 4, 1 )",
+                "127.0.0.1:1025",
+            ),
+            ("227 =(255,255,255,255,255,255)", "255.255.255.255:65535"),
+        ] {
+            assert_eq!(
+                parse_pasv_response(response).unwrap(),
+                address.parse::<SocketAddr>().unwrap(),
+                "{}",
+                response
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_pasv_responses() {
+        for response in [
+            "227 Entering Passive Mode",
+            "227 Entering Passive Mode ()",
+            "227 Entering Passive Mode (127,0,0,1,195)",
+            "227 Entering Passive Mode (127,0,0,1,195,80,1)",
+            "227 Entering Passive Mode (127,0,0,256,195,80)",
+            "227 Entering Passive Mode (127,0,0,-1,195,80)",
+            "227 Entering Passive Mode (a,b,c,d,e,f)",
+            "227 Entering Passive Mode (127,0,0,1,195,80",
+            "227 Entering Passive Mode )127,0,0,1,195,80(",
+            "227 Entering Passive Mode (|||50000|)",
+        ] {
+            assert!(parse_pasv_response(response).is_err(), "{}", response);
+        }
+    }
+
+    #[test]
+    fn parses_epsv_responses() {
+        for (response, port) in [
+            ("229 Entering Extended Passive Mode (|||50000|)", 50000),
+            ("229 Entering Extended Passive Mode (!!!21!)", 21),
+            ("229 Entering Extended Passive Mode (|||65535|).", 65535),
+        ] {
+            assert_eq!(parse_epsv_response(response).unwrap(), port, "{}", response);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_epsv_responses() {
+        for response in [
+            "229 Entering Extended Passive Mode",
+            "229 Entering Extended Passive Mode ()",
+            "229 Entering Extended Passive Mode (|||)",
+            "229 Entering Extended Passive Mode (||||)",
+            "229 Entering Extended Passive Mode (|||50000)",
+            "229 Entering Extended Passive Mode (|1|::1|50000|)",
+            "229 Entering Extended Passive Mode (|||65536|)",
+            "229 Entering Extended Passive Mode (|||-1|)",
+            "229 Entering Extended Passive Mode (|||port|)",
+            "229 Entering Extended Passive Mode (|!|50000|)",
+            "229 Entering Extended Passive Mode (127,0,0,1,195,80)",
+        ] {
+            assert!(parse_epsv_response(response).is_err(), "{}", response);
+        }
+    }
+
+    #[test]
+    fn formats_eprt_arguments() {
+        for (address, argument) in [
+            (
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 50000),
+                "|1|192.0.2.1|50000|",
+            ),
+            (
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 21),
+                "|2|::1|21|",
+            ),
+            (
+                SocketAddr::new(
+                    IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+                    65535,
+                ),
+                "|2|2001:db8::1|65535|",
+            ),
+        ] {
+            assert_eq!(format_eprt_argument(address), argument);
+        }
+    }
+
+    #[test]
+    fn formats_port_arguments() {
+        assert_eq!(
+            format_port_argument(&Ipv4Addr::new(192, 0, 2, 1), 50000),
+            "192,0,2,1,195,80"
+        );
+    }
+
+    #[test]
+    fn parses_hosts() {
+        for (host, parsed) in [
+            ("127.0.0.1", "127.0.0.1"),
+            (" ftp.example.com ", "ftp.example.com"),
+            ("::1", "::1"),
+            ("[::1]", "::1"),
+            ("[2001:db8::1]", "2001:db8::1"),
+        ] {
+            assert_eq!(parse_host(host).unwrap(), parsed, "{}", host);
+        }
+        for host in ["", "  ", "[]"] {
+            assert!(parse_host(host).is_err(), "{:?}", host);
+        }
+    }
+
+    #[test]
+    fn formats_server_addresses() {
+        for (host, address) in [
+            ("127.0.0.1", "127.0.0.1:2121"),
+            ("ftp.example.com", "ftp.example.com:2121"),
+            ("::1", "[::1]:2121"),
+        ] {
+            let settings = ConnectionSettings {
+                host: host.to_string(),
+                port: DEFAULT_PORT,
+                data_mode: DataMode::Passive,
+                known_servers: PathBuf::from("known_servers"),
+                ca_file: None,
+            };
+            assert_eq!(settings.server_address(), address);
+        }
+    }
+
+    #[test]
+    fn pins_the_fingerprint_given_on_the_command_line() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let settings = ConnectionSettings {
+            host: "::1".to_string(),
+            port: DEFAULT_PORT,
+            data_mode: DataMode::Passive,
+            known_servers: dir.path().join("known_servers"),
+            ca_file: None,
+        };
+        let pinned = format!("SHA256:{}", vec!["AB"; 32].join(":"));
+        let replaced = format!("SHA256:{}", vec!["CD"; 32].join(":"));
+
+        known_servers_utils::check_server(&settings.known_servers, "[::1]:2121", &pinned).unwrap();
+        pin_server(&settings, &"cd".repeat(32)).unwrap();
+        assert!(
+            known_servers_utils::check_server(&settings.known_servers, "[::1]:2121", &pinned)
+                .is_err()
+        );
+        known_servers_utils::check_server(&settings.known_servers, "[::1]:2121", &replaced)
+            .unwrap();
+
+        assert!(pin_server(&settings, "not a fingerprint").is_err());
+    }
 }
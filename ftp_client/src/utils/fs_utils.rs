@@ -18,18 +18,18 @@ pub fn get_private_key(private_key_path: String) -> String {
             match output {
                 Ok(output) => {
                     if output.status.success() {
-                        return String::from_utf8_lossy(&output.stdout)
+                        String::from_utf8_lossy(&output.stdout)
                             .to_string()
                             .trim()
-                            .to_string();
+                            .to_string()
                     } else {
                         println!("Error on give administrator permission: {}", output.status);
-                        return String::new();
+                        String::new()
                     }
                 }
                 Err(e) => {
                     println!("Error on give administrator permission: {}", e);
-                    return String::new();
+                    String::new()
                 }
             }
         }
@@ -54,15 +54,15 @@ pub fn get_file(file_path: String) -> String {
             match output {
                 Ok(output) => {
                     if output.status.success() {
-                        return String::from_utf8_lossy(&output.stdout).to_string();
+                        String::from_utf8_lossy(&output.stdout).to_string()
                     } else {
                         println!("Error on give administrator permission: {}", output.status);
-                        return String::new();
+                        String::new()
                     }
                 }
                 Err(e) => {
                     println!("Error on give administrator permission: {}", e);
-                    return String::new();
+                    String::new()
                 }
             }
         }
@@ -82,7 +82,7 @@ pub fn write_file_in_downloads(file_path: String, content: &[u8]) -> std::io::Re
             let root = root.to_str().unwrap();
             let downloads_path = format!("{}\\downloads", root);
 
-            if !fs::metadata(&downloads_path).is_ok() {
+            if fs::metadata(&downloads_path).is_err() {
                 fs::create_dir(&downloads_path)?;
             }
 
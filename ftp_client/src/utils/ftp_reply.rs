@@ -0,0 +1,266 @@
+use std::error::Error;
+use std::fmt;
+use std::io::ErrorKind;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Longest reply line accepted from the server, CRLF included.
+const MAX_LINE_LENGTH: usize = 8192;
+/// Most lines accepted in one multi-line reply.
+const MAX_LINES: usize = 1000;
+
+/// Kind of a reply, given by the first digit of its code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplyClass {
+    /// 1xx, the command was accepted and another reply will follow.
+    PositivePreliminary,
+    /// 2xx, the command succeeded.
+    PositiveCompletion,
+    /// 3xx, the command was accepted and waits for another one, like PASS
+    /// after USER.
+    PositiveIntermediate,
+    /// 4xx, the command failed but may succeed when sent again later.
+    TransientNegative,
+    /// 5xx, the command failed.
+    PermanentNegative,
+}
+
+/// A reply of the server on the control connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub code: u16,
+    /// Text of the reply without the codes, one entry per line.
+    pub lines: Vec<String>,
+}
+
+impl Reply {
+    pub fn class(&self) -> ReplyClass {
+        // The parser only accepts codes starting with 1 to 5.
+        match self.code / 100 {
+            1 => ReplyClass::PositivePreliminary,
+            2 => ReplyClass::PositiveCompletion,
+            3 => ReplyClass::PositiveIntermediate,
+            4 => ReplyClass::TransientNegative,
+            _ => ReplyClass::PermanentNegative,
+        }
+    }
+
+    /// The text of every line, joined with newlines.
+    pub fn text(&self) -> String {
+        self.lines.join("\n")
+    }
+
+    /// Checks that the reply has one of the expected codes. The error names
+    /// the command that failed and carries the whole reply.
+    pub fn expect(self, command: &str, codes: &[u16]) -> Result<Reply, Box<dyn Error>> {
+        if codes.contains(&self.code) {
+            Ok(self)
+        } else {
+            Err(Box::from(format!("{} failed: {}", command, self)))
+        }
+    }
+}
+
+/// Shows the reply as the server sent it, without the line endings.
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let last = self.lines.len().saturating_sub(1);
+        for (i, line) in self.lines.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            if i == last {
+                write!(f, "{} {}", self.code, line)?;
+            } else if i == 0 {
+                write!(f, "{}-{}", self.code, line)?;
+            } else {
+                write!(f, "{}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads one reply, following a multi-line reply up to the line with its
+/// code and a space.
+///
+/// Bytes are read one at a time so nothing sent after the reply is
+/// consumed, which matters on the plain connection before AUTH TLS.
+pub async fn read_reply<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Reply, Box<dyn Error>> {
+    let first = read_line(reader).await?;
+    let (code, separator, text) =
+        parse_line(&first).ok_or_else(|| format!("Invalid reply from the server: {}", first))?;
+    let mut lines = vec![text.to_string()];
+
+    if separator == '-' {
+        loop {
+            if lines.len() == MAX_LINES {
+                return Err(Box::from("Reply from the server has too many lines"));
+            }
+            let line = read_line(reader).await?;
+            match parse_line(&line) {
+                Some((last_code, ' ', text)) if last_code == code => {
+                    lines.push(text.to_string());
+                    break;
+                }
+                // Lines in between may repeat the code with a hyphen.
+                Some((line_code, '-', text)) if line_code == code => lines.push(text.to_string()),
+                _ => lines.push(line),
+            }
+        }
+    }
+    Ok(Reply { code, lines })
+}
+
+/// Reads a line up to LF, returned without its line ending.
+async fn read_line<R: AsyncRead + Unpin>(reader: &mut R) -> Result<String, Box<dyn Error>> {
+    let mut line = Vec::new();
+    while !line.ends_with(b"\n") {
+        if line.len() == MAX_LINE_LENGTH {
+            return Err(Box::from("Reply line from the server is too long"));
+        }
+        match reader.read_u8().await {
+            Ok(byte) => line.push(byte),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(Box::from("Connection closed by the server"))
+            }
+            Err(e) => return Err(Box::from(e)),
+        }
+    }
+    let line = String::from_utf8_lossy(&line);
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Splits a reply line into its code, the separator after it and its text.
+/// A line holding only the code counts as ending with a space.
+fn parse_line(line: &str) -> Option<(u16, char, &str)> {
+    let digits = line.get(..3)?;
+    if !digits.bytes().all(|byte| byte.is_ascii_digit())
+        || !(b'1'..=b'5').contains(&digits.as_bytes()[0])
+    {
+        return None;
+    }
+    let code = digits.parse().ok()?;
+    match line[3..].chars().next() {
+        None => Some((code, ' ', "")),
+        Some(separator @ (' ' | '-')) => Some((code, separator, &line[4..])),
+        Some(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read one reply from the given bytes, returning what is left after it.
+    async fn read(bytes: &[u8]) -> (Result<Reply, Box<dyn Error>>, &[u8]) {
+        let mut reader = bytes;
+        let reply = read_reply(&mut reader).await;
+        (reply, reader)
+    }
+
+    #[tokio::test]
+    async fn reads_single_line_reply() {
+        let (reply, rest) = read(b"220 welcome\r\n331 next\r\n").await;
+        let reply = reply.unwrap();
+        assert_eq!(reply.code, 220);
+        assert_eq!(reply.lines, vec!["welcome"]);
+        assert_eq!(reply.class(), ReplyClass::PositiveCompletion);
+        assert_eq!(rest, b"331 next\r\n");
+
+        let (reply, _) = read(b"200\n").await;
+        assert_eq!(reply.unwrap().lines, vec![""]);
+    }
+
+    #[tokio::test]
+    async fn reads_multi_line_reply() {
+        let bytes =
+            b"211-Features:\r\n EPSV\r\n211-UTF8\r\n200 not the end\r\n211 End\r\n226 next\r\n";
+        let (reply, rest) = read(bytes).await;
+        let reply = reply.unwrap();
+        assert_eq!(reply.code, 211);
+        assert_eq!(
+            reply.lines,
+            vec!["Features:", " EPSV", "UTF8", "200 not the end", "End"]
+        );
+        assert_eq!(rest, b"226 next\r\n");
+        assert_eq!(
+            reply.to_string(),
+            "211-Features:\n EPSV\nUTF8\n200 not the end\n211 End"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_replies() {
+        for bytes in [
+            &b"hello\r\n"[..],
+            b"22 short\r\n",
+            b"600 unknown class\r\n",
+            b"099 unknown class\r\n",
+            b"2x0 not a code\r\n",
+            b"220:bad separator\r\n",
+        ] {
+            let (reply, _) = read(bytes).await;
+            assert!(reply.is_err(), "{:?}", bytes);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_replies() {
+        for bytes in [
+            &b""[..],
+            b"220 no line ending",
+            b"211-Features:\r\n EPSV\r\n",
+            b"211-Features:\r\n211 End",
+        ] {
+            let (reply, _) = read(bytes).await;
+            assert_eq!(
+                reply.unwrap_err().to_string(),
+                "Connection closed by the server",
+                "{:?}",
+                bytes
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_over_long_lines() {
+        let mut bytes = b"220 ".to_vec();
+        bytes.resize(MAX_LINE_LENGTH + 10, b'a');
+        bytes.extend_from_slice(b"\r\n");
+        let (reply, _) = read(&bytes).await;
+        assert_eq!(
+            reply.unwrap_err().to_string(),
+            "Reply line from the server is too long"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_too_many_lines() {
+        let mut bytes = b"211-start\r\n".to_vec();
+        bytes.extend_from_slice(&b" line\r\n".repeat(MAX_LINES));
+        bytes.extend_from_slice(b"211 End\r\n");
+        let (reply, _) = read(&bytes).await;
+        assert_eq!(
+            reply.unwrap_err().to_string(),
+            "Reply from the server has too many lines"
+        );
+
+        let mut bytes = b"211-start\r\n".to_vec();
+        bytes.extend_from_slice(&b" line\r\n".repeat(MAX_LINES - 2));
+        bytes.extend_from_slice(b"211 End\r\n");
+        let (reply, _) = read(&bytes).await;
+        assert_eq!(reply.unwrap().lines.len(), MAX_LINES);
+    }
+
+    #[test]
+    fn checks_expected_codes() {
+        let reply = Reply {
+            code: 550,
+            lines: vec!["No such file".to_string()],
+        };
+        assert_eq!(reply.class(), ReplyClass::PermanentNegative);
+        let error = reply.clone().expect("Delete", &[250]).unwrap_err();
+        assert_eq!(error.to_string(), "Delete failed: 550 No such file");
+        assert!(reply.expect("Delete", &[250, 550]).is_ok());
+    }
+}
@@ -0,0 +1,245 @@
+use openssl::hash::MessageDigest;
+use openssl::x509::X509Ref;
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// Default path of the file holding the pinned server certificates.
+pub const DEFAULT_KNOWN_SERVERS_PATH: &str = "known_servers";
+
+/// Computes the SHA-256 fingerprint of a certificate, in the
+/// `SHA256:AB:CD:...` form printed by `openssl x509 -fingerprint -sha256`.
+pub fn certificate_fingerprint(certificate: &X509Ref) -> Result<String, Box<dyn Error>> {
+    let digest = certificate.digest(MessageDigest::sha256())?;
+    let hex: Vec<String> = digest.iter().map(|byte| format!("{:02X}", byte)).collect();
+    Ok(format!("SHA256:{}", hex.join(":")))
+}
+
+/// Normalizes a fingerprint given by the user, accepting it with or without
+/// the `SHA256:` prefix, colons and in any case.
+pub fn parse_fingerprint(fingerprint: &str) -> Result<String, Box<dyn Error>> {
+    let fingerprint = fingerprint.trim();
+    let fingerprint = fingerprint
+        .strip_prefix("SHA256:")
+        .or_else(|| fingerprint.strip_prefix("sha256:"))
+        .or_else(|| fingerprint.strip_prefix("sha256 Fingerprint="))
+        .unwrap_or(fingerprint);
+    let hex: String = fingerprint.chars().filter(|c| *c != ':').collect();
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Box::from(format!(
+            "Invalid SHA-256 fingerprint: {}",
+            fingerprint
+        )));
+    }
+    let bytes: Vec<String> = hex
+        .to_ascii_uppercase()
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| String::from_utf8_lossy(pair).to_string())
+        .collect();
+    Ok(format!("SHA256:{}", bytes.join(":")))
+}
+
+/// Reads the pinned fingerprints, one `<host:port> <fingerprint>` per line.
+///
+/// A missing file means no server is pinned yet.
+fn read_known_servers(path: &Path) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Box::new(e)),
+    };
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .map(|(server, fingerprint)| (server.to_string(), fingerprint.trim().to_string()))
+        .collect())
+}
+
+/// Pins the certificate fingerprint of a server, replacing any previous pin.
+pub fn pin_server(path: &Path, server: &str, fingerprint: &str) -> Result<(), Box<dyn Error>> {
+    let mut known_servers = read_known_servers(path)?;
+    known_servers.retain(|(known_server, _)| known_server != server);
+    known_servers.push((server.to_string(), fingerprint.to_string()));
+
+    let content: String = known_servers
+        .iter()
+        .map(|(server, fingerprint)| format!("{} {}\n", server, fingerprint))
+        .collect();
+    fs::write(path, content)?;
+    Ok(())
+}
+
+/// Checks the certificate fingerprint of a server against its pin.
+///
+/// The first certificate seen for a server is pinned (trust on first use).
+/// A certificate that does not match the pin is refused.
+pub fn check_server(path: &Path, server: &str, fingerprint: &str) -> Result<(), Box<dyn Error>> {
+    let known_servers = read_known_servers(path)?;
+    let pinned = known_servers
+        .iter()
+        .find(|(known_server, _)| known_server == server)
+        .map(|(_, pinned)| parse_fingerprint(pinned))
+        .transpose()?;
+
+    match pinned {
+        Some(pinned) if pinned == fingerprint => Ok(()),
+        Some(pinned) => {
+            eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+            eprintln!("@    WARNING: SERVER CERTIFICATE HAS CHANGED!           @");
+            eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+            eprintln!(
+                "Someone could be intercepting the connection to {}.",
+                server
+            );
+            eprintln!("Pinned fingerprint:   {}", pinned);
+            eprintln!("Received fingerprint: {}", fingerprint);
+            eprintln!(
+                "If the server certificate was replaced on purpose, update the pin in {} or with --pin-fingerprint.",
+                path.display()
+            );
+            Err(Box::from(format!(
+                "Certificate of {} does not match the pinned fingerprint",
+                server
+            )))
+        }
+        None => {
+            pin_server(path, server, fingerprint)?;
+            println!(
+                "Pinned certificate of {} with fingerprint {} in {}",
+                server,
+                fingerprint,
+                path.display()
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::asn1::Asn1Time;
+    use openssl::pkey::PKey;
+    use openssl::x509::{X509NameBuilder, X509};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const SERVER: &str = "127.0.0.1:2121";
+
+    fn fingerprint(byte: &str) -> String {
+        format!("SHA256:{}", vec![byte; 32].join(":"))
+    }
+
+    fn self_signed_certificate() -> X509 {
+        let key = PKey::generate_ed25519().unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::null()).unwrap();
+        builder.build()
+    }
+
+    #[test]
+    fn fingerprints_certificates() {
+        let certificate = self_signed_certificate();
+        let fingerprint = certificate_fingerprint(&certificate).unwrap();
+        assert_eq!(fingerprint.len(), "SHA256:".len() + 32 * 3 - 1);
+        assert_eq!(parse_fingerprint(&fingerprint).unwrap(), fingerprint);
+        assert_ne!(
+            certificate_fingerprint(&self_signed_certificate()).unwrap(),
+            fingerprint
+        );
+    }
+
+    #[test]
+    fn parses_fingerprints() {
+        let expected = fingerprint("AB");
+        let bare = "ab".repeat(32);
+        for input in [
+            expected.clone(),
+            expected.to_lowercase(),
+            bare.clone(),
+            format!("SHA256:{}", bare),
+            format!(
+                "sha256 Fingerprint={}",
+                expected.trim_start_matches("SHA256:")
+            ),
+            format!("  {}\n", expected),
+        ] {
+            assert_eq!(parse_fingerprint(&input).unwrap(), expected, "{}", input);
+        }
+        for input in ["", "SHA256:", &"ab".repeat(31), &"zz".repeat(32), "SHA1:AB"] {
+            assert!(parse_fingerprint(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn pins_the_first_certificate_seen() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("known_servers");
+
+        check_server(&path, SERVER, &fingerprint("AB")).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{} {}\n", SERVER, fingerprint("AB"))
+        );
+        check_server(&path, SERVER, &fingerprint("AB")).unwrap();
+
+        // Every server is pinned on its own.
+        check_server(&path, "[::1]:2121", &fingerprint("CD")).unwrap();
+        check_server(&path, SERVER, &fingerprint("AB")).unwrap();
+        check_server(&path, "[::1]:2121", &fingerprint("CD")).unwrap();
+    }
+
+    #[test]
+    fn refuses_a_changed_certificate() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("known_servers");
+        let content = format!(
+            "# pinned servers\n\n{} {}\n",
+            SERVER,
+            fingerprint("AB").to_lowercase()
+        );
+        fs::write(&path, &content).unwrap();
+
+        check_server(&path, SERVER, &fingerprint("AB")).unwrap();
+        assert!(check_server(&path, SERVER, &fingerprint("CD")).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), content);
+    }
+
+    #[test]
+    fn rejects_an_invalid_pin() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("known_servers");
+        fs::write(&path, format!("{} not-a-fingerprint\n", SERVER)).unwrap();
+        assert!(check_server(&path, SERVER, &fingerprint("AB")).is_err());
+    }
+
+    #[test]
+    fn pinning_replaces_the_previous_pin() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("known_servers");
+        pin_server(&path, SERVER, &fingerprint("AB")).unwrap();
+        pin_server(&path, "[::1]:2121", &fingerprint("CD")).unwrap();
+        pin_server(&path, SERVER, &fingerprint("EF")).unwrap();
+
+        assert!(check_server(&path, SERVER, &fingerprint("AB")).is_err());
+        check_server(&path, SERVER, &fingerprint("EF")).unwrap();
+        check_server(&path, "[::1]:2121", &fingerprint("CD")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+    }
+}
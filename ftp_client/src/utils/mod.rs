@@ -1,10 +1,18 @@
 /// Command-line utilities for parsing and executing commands.
 pub mod cli_utils;
+/// Settings of the client read from its config file.
+pub mod client_config;
 /// FTP connection commands.
 pub mod connection_commands;
 /// File system utilities.
 pub mod fs_utils;
+/// Parsing of the replies of the FTP server.
+pub mod ftp_reply;
+/// Pinning of the server certificate fingerprints.
+pub mod known_servers_utils;
 /// OpenSSL utilities for signing messages.
 pub mod openssl_utils;
 /// Rocket web server utilities for handling HTTP requests.
 pub mod rocket_utils;
+/// ssh-agent utilities for signing with keys held by the agent.
+pub mod ssh_agent_utils;
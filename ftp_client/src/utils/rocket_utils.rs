@@ -1,126 +1,166 @@
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::status::Custom;
 use rocket::serde::json::Json;
 use rocket::serde::Deserialize;
-use rocket::{delete, fs, get, post, routes, State};
+use rocket::{delete, get, post, State};
+use std::convert::Infallible;
+use std::error::Error;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
 use crate::UserContext;
 
-use super::connection_commands::{self, FileEntry};
+use super::connection_commands::{self, ControlStream, FileEntry, QuotaUsage};
 use super::fs_utils::{self, get_file};
 
+/// Header carrying the TOTP code of users with a second factor, kept out of
+/// the URL so it does not end up in logs and browser history.
+const TOTP_HEADER: &str = "X-TOTP-Code";
+
 /// Represents the data for uploading a file.
 #[derive(Deserialize)]
 pub struct UploadFileData {
     path: String,
 }
 
+/// The TOTP code sent with a request, if any.
+pub struct TotpCode(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TotpCode {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(TotpCode(
+            request.headers().get_one(TOTP_HEADER).map(str::to_string),
+        ))
+    }
+}
+
+/// Connects and logs into the FTP server for one request.
+///
+/// A server that cannot be reached is answered with 502 Bad Gateway and a
+/// refused login with 401 Unauthorized.
+async fn log_in(
+    user_context: &UserContext,
+    totp: &TotpCode,
+) -> Result<ControlStream, Custom<String>> {
+    let mut stream = connection_commands::connect(&user_context.connection_settings)
+        .await
+        .map_err(|e| Custom(Status::BadGateway, e.to_string()))?;
+    let logged_in = connection_commands::login(
+        &mut stream,
+        &user_context.username,
+        &user_context.signer,
+        totp.0.as_deref(),
+    )
+    .await
+    .map_err(|e| Custom(Status::Unauthorized, e.to_string()));
+    if let Err(refused) = logged_in {
+        log_out(stream).await;
+        return Err(refused);
+    }
+    Ok(stream)
+}
+
+/// Ends the session of a request with QUIT, so the server does not keep it
+/// open until it times out.
+async fn log_out(mut stream: ControlStream) {
+    if let Err(e) = connection_commands::quit(&mut stream).await {
+        eprintln!("Error on quit: {}", e);
+    }
+}
+
+/// Answers a failed FTP command with 502 Bad Gateway.
+fn bad_gateway(e: Box<dyn Error>) -> Custom<String> {
+    Custom(Status::BadGateway, e.to_string())
+}
+
 /// Handles the request to list files.
 #[get("/list")]
 pub async fn list_files_handler(
+    totp: TotpCode,
     user_context: &State<Arc<Mutex<UserContext>>>,
-) -> Json<Vec<FileEntry>> {
+) -> Result<Json<Vec<FileEntry>>, Custom<String>> {
     let user_context = user_context.lock().await;
-    let username = &user_context.username;
-    let text_signed = &user_context.text_signed;
-
-    let mut stream = connection_commands::connect()
-        .await
-        .map_err(|e| e.to_string())
-        .unwrap();
+    let mut stream = log_in(&user_context, &totp).await?;
 
-    connection_commands::login(&mut stream, &username, &text_signed)
+    let files = connection_commands::list_files(&mut stream)
         .await
-        .unwrap();
-
-    let files = connection_commands::list_files(&mut stream).await;
-    match files {
-        Ok(files) => Json(files),
-        Err(e) => {
-            eprintln!("Error listing files: {}", e);
-            Json(Vec::new())
-        }
-    }
+        .map_err(bad_gateway);
+    log_out(stream).await;
+    files.map(Json)
 }
 
 /// Handles the request to upload a file.
 #[post("/upload-file", format = "json", data = "<data>")]
 pub async fn upload_file_handler(
     data: Json<UploadFileData>,
+    totp: TotpCode,
     user_context: &State<Arc<Mutex<UserContext>>>,
-) -> Json<String> {
+) -> Result<Json<String>, Custom<String>> {
     let user_context = user_context.lock().await;
-    let username = &user_context.username;
-    let text_signed = &user_context.text_signed;
-
-    let mut stream = connection_commands::connect()
-        .await
-        .map_err(|e| e.to_string())
-        .unwrap();
-
-    connection_commands::login(&mut stream, &username, &text_signed)
-        .await
-        .unwrap();
+    let mut stream = log_in(&user_context, &totp).await?;
 
     let content: String = get_file(data.path.clone());
 
-    let response =
-        connection_commands::upload_file(&mut stream, &data.path, content.as_bytes()).await;
-    match response {
-        Ok(response) => Json(response),
-        Err(e) => Json(e.to_string()),
-    }
+    let response = connection_commands::upload_file(&mut stream, &data.path, content.as_bytes())
+        .await
+        .map_err(bad_gateway);
+    log_out(stream).await;
+    response.map(Json)
 }
 
 /// Handles the request to download a file.
 #[get("/download/<filename>")]
 pub async fn download_file_handler(
     filename: String,
+    totp: TotpCode,
     user_context: &State<Arc<Mutex<UserContext>>>,
-) -> Result<Json<String>, String> {
+) -> Result<Json<String>, Custom<String>> {
     let user_context = user_context.lock().await;
-    let username = &user_context.username;
-    let text_signed = &user_context.text_signed;
-
-    let mut stream = connection_commands::connect()
-        .await
-        .map_err(|e| e.to_string())?;
+    let mut stream = log_in(&user_context, &totp).await?;
 
-    connection_commands::login(&mut stream, username, text_signed)
+    let content = connection_commands::download_file(&mut stream, &filename)
         .await
-        .map_err(|e| e.to_string())?;
-
-    let content = connection_commands::download_file(&mut stream, &filename).await;
-    match content {
-        Ok(content) => {
-            fs_utils::write_file_in_downloads(filename.clone(), &content).unwrap();
-            Ok(Json(String::from_utf8_lossy(&content).to_string()))
-        }
-        Err(e) => Err(e.to_string()),
-    }
+        .map_err(bad_gateway);
+    log_out(stream).await;
+    let content = content?;
+    fs_utils::write_file_in_downloads(filename.clone(), &content)
+        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))?;
+    Ok(Json(String::from_utf8_lossy(&content).to_string()))
 }
 
 /// Handles the request to delete a file.
 #[delete("/delete/<filename>")]
 pub async fn delete_file_handler(
     filename: String,
+    totp: TotpCode,
     user_context: &State<Arc<Mutex<UserContext>>>,
-) -> Result<Json<String>, String> {
+) -> Result<Json<String>, Custom<String>> {
     let user_context = user_context.lock().await;
-    let username = &user_context.username;
-    let text_signed = &user_context.text_signed;
+    let mut stream = log_in(&user_context, &totp).await?;
 
-    let mut stream = connection_commands::connect()
+    let response = connection_commands::delete_file(&mut stream, &filename)
         .await
-        .map_err(|e| e.to_string())?;
+        .map_err(bad_gateway);
+    log_out(stream).await;
+    response.map(|response| Json(response.to_string()))
+}
 
-    connection_commands::login(&mut stream, username, text_signed)
-        .await
-        .map_err(|e| e.to_string())?;
+/// Handles the request to show the storage usage and remaining quota.
+#[get("/quota")]
+pub async fn quota_handler(
+    totp: TotpCode,
+    user_context: &State<Arc<Mutex<UserContext>>>,
+) -> Result<Json<QuotaUsage>, Custom<String>> {
+    let user_context = user_context.lock().await;
+    let mut stream = log_in(&user_context, &totp).await?;
 
-    let response = connection_commands::delete_file(&mut stream, &filename).await;
-    match response {
-        Ok(response) => Ok(Json(response)),
-        Err(e) => Err(e.to_string()),
-    }
+    let usage = connection_commands::get_quota(&mut stream)
+        .await
+        .map_err(bad_gateway);
+    log_out(stream).await;
+    usage.map(Json)
 }
@@ -0,0 +1,399 @@
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine as _};
+use openssl::sha::sha256;
+use std::error::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Environment variable holding the path of the ssh-agent socket.
+pub const SSH_AUTH_SOCK_ENV: &str = "SSH_AUTH_SOCK";
+
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+const SSH_AGENT_RSA_SHA2_256: u32 = 2;
+
+/// Longest response accepted from the ssh-agent, so a broken agent cannot
+/// make the client allocate gigabytes.
+const MAX_AGENT_MESSAGE_LEN: usize = 256 * 1024;
+
+/// Key types the server can verify signatures of.
+const SUPPORTED_KEY_TYPES: [&str; 5] = [
+    "ssh-rsa",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "ssh-ed25519",
+];
+
+/// A key held by the ssh-agent.
+#[derive(Debug, Clone)]
+pub struct AgentIdentity {
+    /// The public key in SSH wire format.
+    pub key_blob: Vec<u8>,
+    pub comment: String,
+}
+
+impl AgentIdentity {
+    /// The SSH key type, e.g. `ssh-ed25519`.
+    pub fn key_type(&self) -> String {
+        let mut reader = WireReader::new(&self.key_blob);
+        reader
+            .read_string()
+            .map(|key_type| String::from_utf8_lossy(key_type).to_string())
+            .unwrap_or_default()
+    }
+
+    /// The SHA-256 fingerprint of the key, as printed by `ssh-add -l`.
+    pub fn fingerprint(&self) -> String {
+        format!("SHA256:{}", STANDARD_NO_PAD.encode(sha256(&self.key_blob)))
+    }
+
+    /// Checks whether the server can verify signatures made with this key.
+    pub fn is_supported(&self) -> bool {
+        SUPPORTED_KEY_TYPES.contains(&self.key_type().as_str())
+    }
+}
+
+/// Finds the agent key to sign logins with.
+///
+/// `selector` matches the fingerprint or the comment of a key. Without it,
+/// the first key of a supported type is used.
+pub async fn find_identity(selector: Option<&str>) -> Result<AgentIdentity, Box<dyn Error>> {
+    let identities = request_identities().await?;
+    let identity = match selector {
+        Some(selector) => identities
+            .into_iter()
+            .find(|identity| identity.fingerprint() == selector || identity.comment == selector)
+            .ok_or_else(|| format!("No key matching {} in ssh-agent", selector))?,
+        None => identities
+            .into_iter()
+            .find(AgentIdentity::is_supported)
+            .ok_or("No supported key in ssh-agent")?,
+    };
+    if !identity.is_supported() {
+        return Err(Box::from(format!(
+            "Unsupported ssh-agent key type: {}",
+            identity.key_type()
+        )));
+    }
+    Ok(identity)
+}
+
+/// Lists the keys held by the ssh-agent.
+pub async fn request_identities() -> Result<Vec<AgentIdentity>, Box<dyn Error>> {
+    let response = agent_request(&[SSH_AGENTC_REQUEST_IDENTITIES]).await?;
+    parse_identities(&response)
+}
+
+/// Parses the answer of the ssh-agent to a request for its keys.
+fn parse_identities(response: &[u8]) -> Result<Vec<AgentIdentity>, Box<dyn Error>> {
+    let mut reader = WireReader::new(response);
+    if reader.read_byte()? != SSH_AGENT_IDENTITIES_ANSWER {
+        return Err(Box::from("ssh-agent refused to list its keys"));
+    }
+
+    let count = reader.read_u32()?;
+    let mut identities = Vec::new();
+    for _ in 0..count {
+        let key_blob = reader.read_string()?.to_vec();
+        let comment = String::from_utf8_lossy(reader.read_string()?).to_string();
+        identities.push(AgentIdentity { key_blob, comment });
+    }
+    Ok(identities)
+}
+
+/// Asks the ssh-agent to sign data with one of its keys.
+///
+/// Returns the signature in SSH wire format (algorithm name followed by the
+/// signature blob). RSA keys sign with SHA-256.
+pub async fn sign(identity: &AgentIdentity, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let response = agent_request(&sign_request(identity, data)).await?;
+    parse_signature(identity, &response)
+}
+
+/// Builds the request asking the ssh-agent to sign data with a key.
+fn sign_request(identity: &AgentIdentity, data: &[u8]) -> Vec<u8> {
+    let flags = if identity.key_type() == "ssh-rsa" {
+        SSH_AGENT_RSA_SHA2_256
+    } else {
+        0
+    };
+
+    let mut request = vec![SSH_AGENTC_SIGN_REQUEST];
+    write_string(&mut request, &identity.key_blob);
+    write_string(&mut request, data);
+    request.extend_from_slice(&flags.to_be_bytes());
+    request
+}
+
+/// Parses the answer of the ssh-agent to a sign request.
+fn parse_signature(identity: &AgentIdentity, response: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut reader = WireReader::new(response);
+    if reader.read_byte()? != SSH_AGENT_SIGN_RESPONSE {
+        return Err(Box::from(format!(
+            "ssh-agent refused to sign with key {}",
+            identity.fingerprint()
+        )));
+    }
+    Ok(reader.read_string()?.to_vec())
+}
+
+/// Sends one request to the ssh-agent and returns its response.
+#[cfg(unix)]
+async fn agent_request(request: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    use std::env;
+    use tokio::net::UnixStream;
+
+    let socket_path = env::var(SSH_AUTH_SOCK_ENV)
+        .map_err(|_| format!("{} is not set, is ssh-agent running?", SSH_AUTH_SOCK_ENV))?;
+    let mut stream = UnixStream::connect(&socket_path)
+        .await
+        .map_err(|e| format!("Cannot connect to ssh-agent at {}: {}", socket_path, e))?;
+    exchange(&mut stream, request).await
+}
+
+/// Sends one request to the ssh-agent and returns its response.
+#[cfg(not(unix))]
+async fn agent_request(_request: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    Err(Box::from("ssh-agent signing is only supported on Unix"))
+}
+
+/// Sends one length-prefixed message to the ssh-agent and reads the one it
+/// answers with.
+async fn exchange<S>(stream: &mut S, request: &[u8]) -> Result<Vec<u8>, Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut message = Vec::with_capacity(request.len() + 4);
+    write_string(&mut message, request);
+    stream.write_all(&message).await?;
+    stream.flush().await?;
+
+    let length = stream.read_u32().await? as usize;
+    if length > MAX_AGENT_MESSAGE_LEN {
+        return Err(Box::from(format!(
+            "ssh-agent response of {} bytes is too long",
+            length
+        )));
+    }
+    let mut response = vec![0u8; length];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+fn write_string(buffer: &mut Vec<u8>, data: &[u8]) {
+    buffer.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(data);
+}
+
+/// Reads the fields of an SSH wire format message.
+struct WireReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> WireReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        WireReader { data }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        if self.data.len() < len {
+            return Err(Box::from("Truncated ssh-agent message"));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_string(&mut self) -> Result<&'a [u8], Box<dyn Error>> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::engine::general_purpose::STANDARD;
+
+    use super::*;
+
+    /// Public key blob of `ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIPgnzkFJpXB3Oi4cH10S70auznvcxxv6Hxv69wkc+Z6V`.
+    const ED25519_BLOB: &str =
+        "AAAAC3NzaC1lZDI1NTE5AAAAIPgnzkFJpXB3Oi4cH10S70auznvcxxv6Hxv69wkc+Z6V";
+    const ED25519_FINGERPRINT: &str = "SHA256:jf1QmROpn5Wa+2RUFzFR2nzq085ka6mXpi2f4vnS7Gc";
+
+    fn agent_identity(key_blob: Vec<u8>, comment: &str) -> AgentIdentity {
+        AgentIdentity {
+            key_blob,
+            comment: comment.to_string(),
+        }
+    }
+
+    fn ed25519_identity() -> AgentIdentity {
+        agent_identity(STANDARD.decode(ED25519_BLOB).unwrap(), "edtest")
+    }
+
+    /// A key blob holding only the key type.
+    fn key_blob(key_type: &str) -> Vec<u8> {
+        let mut blob = Vec::new();
+        write_string(&mut blob, key_type.as_bytes());
+        blob
+    }
+
+    fn identities_answer(identities: &[AgentIdentity]) -> Vec<u8> {
+        let mut answer = vec![SSH_AGENT_IDENTITIES_ANSWER];
+        answer.extend_from_slice(&(identities.len() as u32).to_be_bytes());
+        for identity in identities {
+            write_string(&mut answer, &identity.key_blob);
+            write_string(&mut answer, identity.comment.as_bytes());
+        }
+        answer
+    }
+
+    #[test]
+    fn reads_wire_format() {
+        let mut message = vec![7];
+        message.extend_from_slice(&42u32.to_be_bytes());
+        write_string(&mut message, b"ssh-ed25519");
+        write_string(&mut message, b"");
+
+        let mut reader = WireReader::new(&message);
+        assert_eq!(reader.read_byte().unwrap(), 7);
+        assert_eq!(reader.read_u32().unwrap(), 42);
+        assert_eq!(reader.read_string().unwrap(), b"ssh-ed25519");
+        assert_eq!(reader.read_string().unwrap(), b"");
+        assert!(reader.read_byte().is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_wire_format() {
+        assert!(WireReader::new(&[0, 0, 0]).read_u32().is_err());
+        assert!(WireReader::new(&[0, 0, 0, 5, b'a']).read_string().is_err());
+        assert!(WireReader::new(&[0xff, 0xff, 0xff, 0xff])
+            .read_string()
+            .is_err());
+    }
+
+    #[test]
+    fn describes_identities() {
+        let identity = ed25519_identity();
+        assert_eq!(identity.key_type(), "ssh-ed25519");
+        assert_eq!(identity.fingerprint(), ED25519_FINGERPRINT);
+        assert!(identity.is_supported());
+
+        assert!(agent_identity(key_blob("ecdsa-sha2-nistp256"), "").is_supported());
+        assert!(!agent_identity(key_blob("ssh-dss"), "").is_supported());
+        assert!(!agent_identity(Vec::new(), "").is_supported());
+    }
+
+    #[test]
+    fn parses_identities() {
+        let sent = [
+            ed25519_identity(),
+            agent_identity(key_blob("ssh-dss"), "old"),
+        ];
+        let identities = parse_identities(&identities_answer(&sent)).unwrap();
+        assert_eq!(identities.len(), 2);
+        assert_eq!(identities[0].key_blob, sent[0].key_blob);
+        assert_eq!(identities[0].comment, "edtest");
+        assert_eq!(identities[1].key_type(), "ssh-dss");
+        assert_eq!(identities[1].comment, "old");
+
+        assert!(parse_identities(&identities_answer(&[]))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_identities_answer() {
+        // SSH_AGENT_FAILURE
+        assert!(parse_identities(&[5]).is_err());
+        assert!(parse_identities(&[]).is_err());
+
+        let mut answer = identities_answer(&[ed25519_identity()]);
+        answer.truncate(answer.len() - 1);
+        assert!(parse_identities(&answer).is_err());
+        let mut answer = identities_answer(&[]);
+        answer[4] = 1;
+        assert!(parse_identities(&answer).is_err());
+    }
+
+    #[test]
+    fn builds_sign_requests() {
+        for (identity, flags) in [
+            (ed25519_identity(), 0),
+            (
+                agent_identity(key_blob("ssh-rsa"), ""),
+                SSH_AGENT_RSA_SHA2_256,
+            ),
+        ] {
+            let request = sign_request(&identity, b"fk:nonce:1700000000");
+            let mut reader = WireReader::new(&request);
+            assert_eq!(reader.read_byte().unwrap(), SSH_AGENTC_SIGN_REQUEST);
+            assert_eq!(reader.read_string().unwrap(), identity.key_blob);
+            assert_eq!(reader.read_string().unwrap(), b"fk:nonce:1700000000");
+            assert_eq!(reader.read_u32().unwrap(), flags);
+            assert!(reader.read_byte().is_err());
+        }
+    }
+
+    #[test]
+    fn parses_signatures() {
+        let identity = ed25519_identity();
+        let mut response = vec![SSH_AGENT_SIGN_RESPONSE];
+        write_string(&mut response, b"signature");
+        assert_eq!(parse_signature(&identity, &response).unwrap(), b"signature");
+
+        assert!(parse_signature(&identity, &[5]).is_err());
+        assert!(parse_signature(&identity, &response[..response.len() - 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn exchanges_messages_with_the_agent() {
+        let (mut client, mut agent) = tokio::io::duplex(1024);
+        let answer = identities_answer(&[ed25519_identity()]);
+        let fake_agent = async {
+            let length = agent.read_u32().await.unwrap();
+            let mut request = vec![0; length as usize];
+            agent.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, [SSH_AGENTC_REQUEST_IDENTITIES]);
+            let mut message = Vec::new();
+            write_string(&mut message, &answer);
+            agent.write_all(&message).await.unwrap();
+        };
+        let (response, ()) = tokio::join!(
+            exchange(&mut client, &[SSH_AGENTC_REQUEST_IDENTITIES]),
+            fake_agent
+        );
+        assert_eq!(response.unwrap(), answer);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_agent_response() {
+        let (mut client, mut agent) = tokio::io::duplex(1024);
+        agent
+            .write_all(&(MAX_AGENT_MESSAGE_LEN as u32 + 1).to_be_bytes())
+            .await
+            .unwrap();
+        let error = exchange(&mut client, &[SSH_AGENTC_REQUEST_IDENTITIES])
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("too long"), "{}", error);
+
+        let (mut client, mut agent) = tokio::io::duplex(1024);
+        agent.write_all(&[0, 0, 0, 8, 1, 2]).await.unwrap();
+        drop(agent);
+        assert!(exchange(&mut client, &[SSH_AGENTC_REQUEST_IDENTITIES])
+            .await
+            .is_err());
+    }
+}
@@ -1,53 +1,296 @@
 use async_trait::async_trait;
-use libunftp::auth::{AuthenticationError, Authenticator, Credentials, DefaultUser};
-use libunftp::Server;
-use openssl::x509::verify;
-use std::env;
-use std::error::Error;
+use chrono::Utc;
+use clap::Parser;
+use libunftp::auth::{AuthenticationError, Credentials};
+use libunftp::ServerBuilder;
 use std::fs;
-use std::process::{Command, Stdio};
+use std::path::PathBuf;
+use std::process;
 use std::sync::Arc;
-use unftp_sbe_fs::ServerExt;
+use unftp_sbe_fs::Filesystem;
 
 /// This struct is used to authenticate users with public keys.
 #[derive(Debug)]
-struct PublicKeyAuthenticator;
+struct PublicKeyAuthenticator {
+    home_root: PathBuf,
+    keys: Arc<dyn KeyStore>,
+    throttle: LoginThrottle,
+    audit_log: Option<AuditLog>,
+}
 
 mod utils;
-use crate::utils::{fs_utils, openssl_utils};
+use crate::utils::audit_log::{AuditEvent, AuditLog, Outcome};
+use crate::utils::authorized_keys::RegisteredUser;
+use crate::utils::cli_utils::Cli;
+use crate::utils::fs_utils;
+use crate::utils::ftp_user::{FtpUser, Role};
+use crate::utils::ftps_relay::{
+    self, FtpsRelay, SessionAuthenticator, SessionAuthenticatorHandle, SharedAuthenticator,
+};
+use crate::utils::key_registry::KeyRegistry;
+use crate::utils::key_store::{KeyStore, KeyStoreKind};
+use crate::utils::lockout_utils::{LockoutPolicy, LoginThrottle};
+use crate::utils::login_challenge::LoginChallenge;
+use crate::utils::openssl_utils;
+use crate::utils::quota_storage::{QuotaReport, QuotaReporter, QuotaStorage, UploadLocks};
+use crate::utils::role_storage::RoleStorage;
+use crate::utils::server_config::ServerConfig;
+use crate::utils::session_limits::{SessionState, SessionTracker};
+use crate::utils::sqlite_key_store::SqliteKeyStore;
+use crate::utils::tls_policy::TlsPolicy;
+use crate::utils::totp_utils;
+
+impl PublicKeyAuthenticator {
+    /// Create an authenticator that looks keys up in the given store and
+    /// gives every user a home directory under `home_root`.
+    fn new(
+        home_root: PathBuf,
+        keys: Arc<dyn KeyStore>,
+        lockout_policy: LockoutPolicy,
+        audit_log: Option<AuditLog>,
+    ) -> Self {
+        PublicKeyAuthenticator {
+            home_root,
+            keys,
+            throttle: LoginThrottle::new(lockout_policy),
+            audit_log,
+        }
+    }
+
+    /// Build the user handed to the FTP session.
+    ///
+    /// Admins see the whole storage root, everyone else is jailed to their
+    /// own home directory, which is created on first login.
+    fn session_user(
+        &self,
+        username: &str,
+        user: &RegisteredUser,
+    ) -> Result<FtpUser, AuthenticationError> {
+        let home = if user.role == Role::Admin {
+            None
+        } else {
+            Some(
+                fs_utils::create_home_dir(&self.home_root, username).map_err(|e| {
+                    AuthenticationError::with_source("could not create home directory", e)
+                })?,
+            )
+        };
+        Ok(FtpUser {
+            username: username.to_string(),
+            home,
+            role: user.role,
+            quota: user.quota,
+        })
+    }
+
+    /// Check that the password is the nonce sent to the session and a recent
+    /// timestamp, signed by one of the user's currently valid keys. Users
+    /// with a TOTP secret must also append a valid code.
+    ///
+    /// Returns the session user and the fingerprint of the key that signed
+    /// the challenge.
+    async fn verify_login(
+        &self,
+        username: &str,
+        password: &Credentials,
+        nonce: Option<String>,
+    ) -> Result<(FtpUser, String), LoginFailure> {
+        let challenge = match password.password.as_deref().map(LoginChallenge::parse) {
+            Some(Ok(challenge)) => challenge,
+            Some(Err(e)) => return Err(LoginFailure::new(e.to_string())),
+            None => return Err(LoginFailure::new("missing login challenge")),
+        };
+        if nonce.as_deref() != Some(challenge.nonce.as_str()) {
+            return Err(LoginFailure::new(
+                "login challenge does not sign the nonce of the session",
+            ));
+        }
+
+        let now = Utc::now();
+        let now_secs = now.timestamp().max(0) as u64;
+        if !challenge.is_fresh(now_secs) {
+            return Err(LoginFailure::new("login challenge has expired"));
+        }
+
+        let user = match self.keys.user(username).await {
+            Some(user) if !user.keys.is_empty() => user,
+            _ => return Err(LoginFailure::new("no keys registered")),
+        };
+
+        let message = challenge.message(username);
+        let mut skipped = Vec::new();
+        for key in &user.keys {
+            if self.keys.is_revoked(&key.fingerprint).await {
+                println!("Rejected revoked key {} for {}", key.describe(), username);
+                skipped.push(format!("{} is revoked", key.fingerprint));
+                continue;
+            }
+            if let Err(reason) = key.check_options(now, password.source_ip) {
+                println!(
+                    "Skipping key {} for {}: {}",
+                    key.describe(),
+                    username,
+                    reason
+                );
+                skipped.push(format!("{} {}", key.fingerprint, reason));
+                continue;
+            }
+            match openssl_utils::verify_signature(&key.public_key, &message, &challenge.signature) {
+                Ok(true) => {
+                    let failure = |reason: &str| LoginFailure {
+                        fingerprint: Some(key.fingerprint.clone()),
+                        ..LoginFailure::new(reason)
+                    };
+                    if let Some(totp) = &user.totp {
+                        match challenge.totp_code.as_deref() {
+                            None => return Err(failure("missing TOTP code")),
+                            Some(code) if !totp_utils::check_code(totp, code, now_secs) => {
+                                return Err(failure("invalid TOTP code"))
+                            }
+                            Some(_) => {}
+                        }
+                    }
+                    println!(
+                        "User {} logged in as {} with key {}",
+                        username,
+                        user.role,
+                        key.describe()
+                    );
+                    let session_user = self.session_user(username, &user).map_err(|e| {
+                        println!("Error on create home directory of {}: {}", username, e);
+                        failure("could not create home directory")
+                    })?;
+                    return Ok((session_user, key.fingerprint.clone()));
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    println!(
+                        "Error on verify signature with key {}: {}",
+                        key.describe(),
+                        e
+                    );
+                    skipped.push(format!("{} could not be used: {}", key.fingerprint, e));
+                }
+            }
+        }
+
+        let mut reason = "signature does not match any usable key".to_string();
+        if !skipped.is_empty() {
+            reason = format!("{} ({})", reason, skipped.join("; "));
+        }
+        Err(LoginFailure::new(reason))
+    }
+
+    /// Write the outcome of a login attempt to the audit log.
+    fn audit(
+        &self,
+        username: &str,
+        password: &Credentials,
+        result: &Result<(FtpUser, String), LoginFailure>,
+    ) {
+        let (outcome, fingerprint, reason) = match result {
+            Ok((_, fingerprint)) => (Outcome::Success, Some(fingerprint.clone()), None),
+            Err(failure) => (
+                failure.outcome,
+                failure.fingerprint.clone(),
+                Some(failure.reason.clone()),
+            ),
+        };
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(&AuditEvent {
+                timestamp: Utc::now(),
+                source_ip: password.source_ip,
+                username: username.to_string(),
+                fingerprint,
+                outcome,
+                reason,
+            });
+        }
+    }
+}
+
+/// Why a login attempt was rejected.
+#[derive(Debug)]
+struct LoginFailure {
+    reason: String,
+    /// Fingerprint of the key that signed the challenge, if one did.
+    fingerprint: Option<String>,
+    /// `Refused` when the login was verified but the user has too many
+    /// sessions.
+    outcome: Outcome,
+}
+
+impl LoginFailure {
+    fn new(reason: impl Into<String>) -> Self {
+        LoginFailure {
+            reason: reason.into(),
+            fingerprint: None,
+            outcome: Outcome::Failure,
+        }
+    }
+
+    fn refused(reason: impl Into<String>) -> Self {
+        LoginFailure {
+            outcome: Outcome::Refused,
+            ..LoginFailure::new(reason)
+        }
+    }
+}
 
 #[async_trait]
-impl Authenticator<DefaultUser> for PublicKeyAuthenticator {
+impl SessionAuthenticator for PublicKeyAuthenticator {
     /// Authenticate the user with the public key.
+    ///
+    /// Logins from a locked out username or address are refused, and every
+    /// recent failure adds a growing delay before the signature is checked.
+    /// The session only counts against the user once the login is verified,
+    /// logins of a user with too many sessions are refused then. Every
+    /// attempt is written to the audit log.
     async fn authenticate(
         &self,
         username: &str,
         password: &Credentials,
-    ) -> Result<DefaultUser, AuthenticationError> {
-        match fs_utils::get_public_key(username) {
-            Ok(public_key) => {
-                match openssl_utils::verify_signature(
-                    &public_key,
-                    username,
-                    password.password.to_owned().unwrap().as_str(),
-                ) {
-                    Ok(is_valid) => {
-                        if is_valid {
-                            return Ok(DefaultUser);
-                        }
+        session: &SessionState,
+        nonce: Option<String>,
+    ) -> Result<FtpUser, AuthenticationError> {
+        let result = match self.throttle.locked_for(username, password.source_ip) {
+            Some(remaining) => Err(LoginFailure::new(format!(
+                "locked out for another {}s",
+                remaining.as_secs()
+            ))),
+            None => {
+                let delay = self.throttle.delay_for(username, password.source_ip);
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                match self.verify_login(username, password, nonce).await {
+                    Ok((_, fingerprint)) if !session.login(username) => Err(LoginFailure {
+                        fingerprint: Some(fingerprint),
+                        ..LoginFailure::refused("too many sessions for this user")
+                    }),
+                    Ok(verified) => {
+                        self.throttle.record_success(username);
+                        Ok(verified)
                     }
-                    Err(e) => {
-                        println!("Error on verify signature: {}", e);
-                        return Err(AuthenticationError::BadPassword);
+                    Err(failure) => {
+                        self.throttle.record_failure(username, password.source_ip);
+                        Err(failure)
                     }
                 }
             }
-            Err(e) => {
-                println!("Error on get public key: {}", e);
-                return Err(AuthenticationError::BadPassword);
+        };
+
+        self.audit(username, password, &result);
+        match result {
+            Ok((user, _)) => Ok(user),
+            Err(failure) => {
+                println!(
+                    "Rejected login for {} from {}: {}",
+                    username, password.source_ip, failure.reason
+                );
+                Err(AuthenticationError::BadPassword)
             }
         }
-        Err(AuthenticationError::BadPassword)
     }
 }
 
@@ -55,11 +298,133 @@ impl Authenticator<DefaultUser> for PublicKeyAuthenticator {
 #[tokio::main]
 async fn main() {
     println!("Starting FTP server...");
-    let ftp_home = env::current_dir().unwrap().join("resources");
-    let server: Server<unftp_sbe_fs::Filesystem, DefaultUser> = Server::with_fs(ftp_home)
-        .greeting("welcome to my FTP server!")
-        .passive_ports(50000..65535)
-        .authenticator(Arc::new(PublicKeyAuthenticator))
-        .ftps("server.certs", "server.key");
-    let _ = server.listen("127.0.0.1:2121").await;
+    let args = Cli::parse();
+    let config = match ServerConfig::load(args.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Error on load config: {}", e);
+            process::exit(1);
+        }
+    };
+    let ftp_home = config.home.clone();
+    if let Err(e) = fs::create_dir_all(&ftp_home) {
+        println!(
+            "Error on load config: cannot create home {}: {}",
+            ftp_home.display(),
+            e
+        );
+        process::exit(1);
+    }
+    let mut _keys_watcher = None;
+    let keys: Arc<dyn KeyStore> = match config.key_store.clone() {
+        KeyStoreKind::Files => {
+            if !config.keys_dir.is_dir() {
+                println!(
+                    "Error on load config: keys_dir {} is not a directory",
+                    config.keys_dir.display()
+                );
+                process::exit(1);
+            }
+            let registry = KeyRegistry::load(config.keys_dir.clone());
+            match registry.watch() {
+                Ok(watcher) => _keys_watcher = Some(watcher),
+                Err(e) => println!(
+                    "Error on watch keys directory, key changes need a restart: {}",
+                    e
+                ),
+            }
+            registry
+        }
+        KeyStoreKind::Sqlite(path) => match SqliteKeyStore::open(&path) {
+            Ok(store) => Arc::new(store),
+            Err(e) => {
+                println!(
+                    "Error on load config: cannot open key store {}: {}",
+                    path.display(),
+                    e
+                );
+                process::exit(1);
+            }
+        },
+    };
+    let audit_log = match AuditLog::new(config.audit_log.clone()) {
+        Ok(audit_log) => Some(audit_log),
+        Err(e) => {
+            println!("Error on open audit log, logins will not be audited: {}", e);
+            None
+        }
+    };
+    let tls_policy = TlsPolicy::required(config.require_tls);
+    if !config.require_tls {
+        println!("TLS is not required, clients may log in and transfer in plaintext");
+    }
+    let authenticator: SharedAuthenticator = Arc::new(PublicKeyAuthenticator::new(
+        ftp_home.clone(),
+        keys,
+        config.lockout.clone(),
+        audit_log,
+    ));
+    // Both listeners build their sessions from the same settings.
+    // libunftp wants a greeting that lives as long as the program.
+    let greeting: &'static str = Box::leak(config.greeting.clone().into_boxed_str());
+    let server_config = config.clone();
+    let upload_locks = Arc::new(UploadLocks::default());
+    let quota_home = ftp_home.clone();
+    let quota_report: Arc<dyn QuotaReport> = Arc::new(QuotaReporter::new(move || {
+        Filesystem::new(quota_home.clone())
+    }));
+    let make_server = move |authenticator: SessionAuthenticatorHandle| {
+        let ftp_home = ftp_home.clone();
+        let upload_locks = Arc::clone(&upload_locks);
+        ServerBuilder::with_authenticator(
+            Box::new(move || {
+                RoleStorage::new(QuotaStorage::new(
+                    Filesystem::new(ftp_home.clone()),
+                    Arc::clone(&upload_locks),
+                ))
+            }),
+            authenticator,
+        )
+        .greeting(greeting)
+        .passive_ports(server_config.passive_ports.clone())
+        .ftps(&server_config.certificate_path, &server_config.key_path)
+    };
+
+    // Both listeners go through the relay, which enforces the session
+    // limits and passes the client address from a PROXY header on to the
+    // sessions.
+    let sessions = SessionTracker::new(config.limits.clone());
+    let relay_settings = |address: &str, implicit: bool| FtpsRelay {
+        address: address.to_string(),
+        implicit,
+        certificate_path: config.certificate_path.clone(),
+        key_path: config.key_path.clone(),
+        passive_ports: config.passive_ports.clone(),
+        control_tls: tls_policy.control,
+        data_tls: tls_policy.data,
+        proxy_protocol: config.proxy_protocol.clone(),
+        sessions: Arc::clone(&sessions),
+        quota_report: Arc::clone(&quota_report),
+    };
+    let explicit = async {
+        if let Some(address) = &config.bind_address {
+            let settings = relay_settings(address, false);
+            let listener =
+                ftps_relay::listen(settings, Arc::clone(&authenticator), make_server.clone());
+            if let Err(e) = listener.await {
+                println!("Error on explicit FTPS listener {}: {}", address, e);
+            }
+        }
+    };
+    let implicit = async {
+        if let Some(address) = &config.implicit_bind_address {
+            let settings = relay_settings(address, true);
+            let listener =
+                ftps_relay::listen(settings, Arc::clone(&authenticator), make_server.clone());
+            if let Err(e) = listener.await {
+                println!("Error on implicit FTPS listener {}: {}", address, e);
+            }
+        }
+    };
+    tokio::join!(explicit, implicit);
 }
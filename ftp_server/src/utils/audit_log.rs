@@ -0,0 +1,379 @@
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Where the audit log is written, when it is rotated and how many old
+/// files are kept, from the `[audit_log]` table of the config file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditLogConfig {
+    /// Path of the current file, relative to the working directory unless
+    /// absolute.
+    pub path: PathBuf,
+    /// Rotate once the current file would grow past this size.
+    pub max_bytes: u64,
+    /// Rotate once the current file is older than this.
+    pub max_age_secs: u64,
+    /// Number of rotated files kept next to the current one.
+    pub max_files: usize,
+}
+
+impl Default for AuditLogConfig {
+    fn default() -> Self {
+        AuditLogConfig {
+            path: PathBuf::from("logs").join("auth_audit.jsonl"),
+            max_bytes: 10 * 1024 * 1024,
+            max_age_secs: 24 * 60 * 60,
+            max_files: 7,
+        }
+    }
+}
+
+impl AuditLogConfig {
+    /// Check the settings before the server starts.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.path.as_os_str().is_empty() || self.path.is_dir() {
+            return Err(Box::from(format!(
+                "audit_log.path {:?} must be a file path",
+                self.path
+            )));
+        }
+        if self.max_bytes == 0 {
+            return Err(Box::from("audit_log.max_bytes must be at least 1"));
+        }
+        if self.max_age_secs == 0 {
+            return Err(Box::from("audit_log.max_age_secs must be at least 1"));
+        }
+        Ok(())
+    }
+
+    fn max_age(&self) -> Duration {
+        Duration::from_secs(self.max_age_secs)
+    }
+}
+
+/// Result of a login attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    Failure,
+    /// The login was verified but the user has too many sessions.
+    Refused,
+}
+
+/// One line of the audit log.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEvent {
+    pub timestamp: DateTime<Utc>,
+    pub source_ip: IpAddr,
+    pub username: String,
+    pub fingerprint: Option<String>,
+    pub outcome: Outcome,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug)]
+struct LogFile {
+    file: File,
+    size: u64,
+    opened_at: SystemTime,
+}
+
+/// Append-only JSON-lines log of every login attempt.
+///
+/// The current file is `<path>`, rotated files are `<path>.1` (newest) to
+/// `<path>.<max_files>` (oldest).
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    config: AuditLogConfig,
+    current: Mutex<Option<LogFile>>,
+}
+
+impl AuditLog {
+    /// Create an audit log writing to the configured path, creating its
+    /// directory if needed.
+    pub fn new(config: AuditLogConfig) -> io::Result<Self> {
+        let path = config.path.clone();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let current = open_log_file(&path)?;
+        Ok(AuditLog {
+            path,
+            config,
+            current: Mutex::new(Some(current)),
+        })
+    }
+
+    /// Append an event to the log, rotating the file first if needed.
+    ///
+    /// Errors are printed rather than returned, so a full disk does not
+    /// prevent users from logging in.
+    pub fn record(&self, event: &AuditEvent) {
+        self.record_at(event, SystemTime::now())
+    }
+
+    fn record_at(&self, event: &AuditEvent, now: SystemTime) {
+        let mut line = match serde_json::to_string(event) {
+            Ok(line) => line,
+            Err(e) => {
+                println!("Error on serialize audit event: {}", e);
+                return;
+            }
+        };
+        line.push('\n');
+
+        let mut current = self.current.lock().unwrap();
+        if let Err(e) = self.write_line(&mut current, line.as_bytes(), now) {
+            println!("Error on write audit log {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn write_line(
+        &self,
+        current: &mut Option<LogFile>,
+        line: &[u8],
+        now: SystemTime,
+    ) -> io::Result<()> {
+        let needs_rotation = match current.as_ref() {
+            Some(log_file) => {
+                let too_big =
+                    log_file.size > 0 && log_file.size + line.len() as u64 > self.config.max_bytes;
+                let too_old = now
+                    .duration_since(log_file.opened_at)
+                    .is_ok_and(|age| age >= self.config.max_age());
+                too_big || too_old
+            }
+            None => false,
+        };
+        if needs_rotation {
+            *current = None;
+            self.rotate()?;
+        }
+        if current.is_none() {
+            *current = Some(open_log_file(&self.path)?);
+        }
+
+        let log_file = current.as_mut().unwrap();
+        log_file.file.write_all(line)?;
+        log_file.file.flush()?;
+        log_file.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shift every rotated file up by one, dropping the oldest, and move
+    /// the current file to `<path>.1`.
+    fn rotate(&self) -> io::Result<()> {
+        if self.config.max_files == 0 {
+            return fs::remove_file(&self.path);
+        }
+        let _ = fs::remove_file(self.rotated_path(self.config.max_files));
+        for index in (1..self.config.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+}
+
+/// Open the log file for appending.
+///
+/// The age of an existing file is taken from its creation time when the
+/// platform records it, so restarting the server does not postpone
+/// rotation.
+fn open_log_file(path: &Path) -> io::Result<LogFile> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    Ok(LogFile {
+        size: metadata.len(),
+        opened_at: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+        file,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn config(dir: &TempDir) -> AuditLogConfig {
+        AuditLogConfig {
+            path: dir.path().join("logs").join("audit.jsonl"),
+            ..AuditLogConfig::default()
+        }
+    }
+
+    fn event(username: &str, outcome: Outcome) -> AuditEvent {
+        AuditEvent {
+            timestamp: "2026-10-18T12:00:00Z".parse().unwrap(),
+            source_ip: "10.0.0.1".parse().unwrap(),
+            username: username.to_string(),
+            fingerprint: Some("SHA256:abc".to_string()),
+            outcome,
+            reason: None,
+        }
+    }
+
+    /// Usernames of the events in a log file, in order.
+    fn usernames(path: &Path) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let event: serde_json::Value = serde_json::from_str(line).unwrap();
+                event["username"].as_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn writes_one_json_object_per_line() {
+        let dir = TempDir::new().unwrap();
+        let config = config(&dir);
+        let log = AuditLog::new(config.clone()).unwrap();
+
+        log.record(&event("fk", Outcome::Success));
+        log.record(&AuditEvent {
+            source_ip: "::1".parse().unwrap(),
+            fingerprint: None,
+            reason: Some("no keys registered".to_string()),
+            ..event("nobody", Outcome::Failure)
+        });
+        log.record(&AuditEvent {
+            reason: Some("too many sessions for this user".to_string()),
+            ..event("fk", Outcome::Refused)
+        });
+
+        let content = fs::read_to_string(&config.path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"timestamp":"2026-10-18T12:00:00Z","source_ip":"10.0.0.1","username":"fk","fingerprint":"SHA256:abc","outcome":"success","reason":null}"#,
+                r#"{"timestamp":"2026-10-18T12:00:00Z","source_ip":"::1","username":"nobody","fingerprint":null,"outcome":"failure","reason":"no keys registered"}"#,
+                r#"{"timestamp":"2026-10-18T12:00:00Z","source_ip":"10.0.0.1","username":"fk","fingerprint":"SHA256:abc","outcome":"refused","reason":"too many sessions for this user"}"#,
+            ]
+        );
+        assert!(content.ends_with('\n'));
+    }
+
+    #[test]
+    fn appends_to_existing_log() {
+        let dir = TempDir::new().unwrap();
+        let config = config(&dir);
+        AuditLog::new(config.clone())
+            .unwrap()
+            .record(&event("first", Outcome::Success));
+        AuditLog::new(config.clone())
+            .unwrap()
+            .record(&event("second", Outcome::Success));
+        assert_eq!(usernames(&config.path), ["first", "second"]);
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = TempDir::new().unwrap();
+        let line_len = serde_json::to_string(&event("u0", Outcome::Success))
+            .unwrap()
+            .len() as u64
+            + 1;
+        let config = AuditLogConfig {
+            max_bytes: 2 * line_len,
+            max_files: 2,
+            ..config(&dir)
+        };
+        let log = AuditLog::new(config.clone()).unwrap();
+        for username in ["u0", "u1", "u2", "u3", "u4", "u5", "u6"] {
+            log.record(&event(username, Outcome::Success));
+        }
+
+        let rotated = |index: usize| log.rotated_path(index);
+        assert_eq!(usernames(&config.path), ["u6"]);
+        assert_eq!(usernames(&rotated(1)), ["u4", "u5"]);
+        assert_eq!(usernames(&rotated(2)), ["u2", "u3"]);
+        assert!(!rotated(3).exists());
+    }
+
+    #[test]
+    fn rotates_by_age() {
+        let dir = TempDir::new().unwrap();
+        let config = AuditLogConfig {
+            max_age_secs: 60,
+            ..config(&dir)
+        };
+        let log = AuditLog::new(config.clone()).unwrap();
+        let start = SystemTime::now();
+        log.record_at(&event("first", Outcome::Success), start);
+        log.record_at(
+            &event("second", Outcome::Success),
+            start + Duration::from_secs(30),
+        );
+        assert_eq!(usernames(&config.path), ["first", "second"]);
+
+        log.record_at(
+            &event("third", Outcome::Success),
+            start + Duration::from_secs(61),
+        );
+        assert_eq!(usernames(&config.path), ["third"]);
+        assert_eq!(usernames(&log.rotated_path(1)), ["first", "second"]);
+    }
+
+    #[test]
+    fn rotation_without_kept_files_drops_the_log() {
+        let dir = TempDir::new().unwrap();
+        let config = AuditLogConfig {
+            max_bytes: 1,
+            max_files: 0,
+            ..config(&dir)
+        };
+        let log = AuditLog::new(config.clone()).unwrap();
+        log.record(&event("first", Outcome::Success));
+        log.record(&event("second", Outcome::Success));
+        assert_eq!(usernames(&config.path), ["second"]);
+        assert!(!log.rotated_path(1).exists());
+    }
+
+    #[test]
+    fn validates_config() {
+        let dir = TempDir::new().unwrap();
+        assert!(config(&dir).validate().is_ok());
+        for invalid in [
+            AuditLogConfig {
+                path: PathBuf::new(),
+                ..config(&dir)
+            },
+            AuditLogConfig {
+                path: dir.path().to_path_buf(),
+                ..config(&dir)
+            },
+            AuditLogConfig {
+                max_bytes: 0,
+                ..config(&dir)
+            },
+            AuditLogConfig {
+                max_age_secs: 0,
+                ..config(&dir)
+            },
+        ] {
+            assert!(invalid.validate().is_err(), "{:?}", invalid);
+        }
+    }
+}
@@ -0,0 +1,591 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::path::Path;
+
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+    Engine as _,
+};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use openssl::pkey::{Id, PKey, Public};
+use openssl::sha::sha256;
+use totp_rs::TOTP;
+
+use super::fs_utils;
+use super::ftp_user::{Quota, Role};
+use super::openssl_utils;
+use super::totp_utils;
+
+/// Key type names of key lines holding a base64 DER public key.
+const KEY_TYPES: [&str; 4] = ["rsa", "ecdsa", "ed25519", "ed448"];
+
+/// Key type names of OpenSSH key lines, holding a base64 SSH public key.
+const SSH_KEY_TYPES: [&str; 6] = [
+    "ssh-rsa",
+    "ssh-ed25519",
+    "ssh-ed448",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+];
+
+/// Everything registered for a user: their keys and account settings.
+///
+/// Besides key lines, `keys/<username>.keys` may contain settings lines
+/// starting with `@`: `@role <role>`, `@quota-bytes <size>` (with an optional
+/// K, M or G suffix), `@quota-files <count>` and `@totp-secret <base32>`.
+pub struct RegisteredUser {
+    pub keys: Vec<AuthorizedKey>,
+    pub role: Role,
+    pub quota: Quota,
+    /// When set, logins also need a valid TOTP code.
+    pub totp: Option<TOTP>,
+}
+
+/// A public key registered for a user, with its options and comment.
+///
+/// Each key line of `keys/<username>.keys` has the form
+/// `[options] <key-type> <base64 DER public key> [comment]`, where options is
+/// a comma separated list without spaces, e.g.
+/// `not-before="20260101",not-after="20261231",from="10.0.0.1,10.0.0.2"`.
+/// `expiry-time` is accepted as an alias of `not-after`. A `not-after` date
+/// without a time of day keeps the key valid until the end of that day.
+///
+/// OpenSSH public key lines, e.g. the content of `id_ed25519.pub`, are
+/// accepted as well, with the same options.
+pub struct AuthorizedKey {
+    pub public_key: PKey<Public>,
+    pub fingerprint: String,
+    pub comment: Option<String>,
+    pub options: KeyOptions,
+}
+
+/// Restrictions attached to an authorized key.
+#[derive(Debug, Default)]
+pub struct KeyOptions {
+    /// The key is not accepted before this time (UTC).
+    pub not_before: Option<DateTime<Utc>>,
+    /// The key is not accepted after this time (UTC), to the second.
+    pub not_after: Option<DateTime<Utc>>,
+    /// The key is only accepted from these source addresses. IPv4-mapped
+    /// IPv6 addresses are stored, and matched, as IPv4.
+    pub from: Option<Vec<IpAddr>>,
+}
+
+impl AuthorizedKey {
+    /// Build an authorized key without options or comment.
+    pub fn new(public_key: PKey<Public>) -> Result<Self, Box<dyn Error>> {
+        let fingerprint = fingerprint(&public_key)?;
+        Ok(AuthorizedKey {
+            public_key,
+            fingerprint,
+            comment: None,
+            options: KeyOptions::default(),
+        })
+    }
+
+    /// A short description of the key for log messages.
+    pub fn describe(&self) -> String {
+        match &self.comment {
+            Some(comment) => format!("{} ({})", self.fingerprint, comment),
+            None => self.fingerprint.clone(),
+        }
+    }
+
+    /// Check the key options against the time and address of a login.
+    ///
+    /// Returns the reason the key cannot be used, if any.
+    pub fn check_options(&self, now: DateTime<Utc>, source_ip: IpAddr) -> Result<(), String> {
+        if let Some(not_before) = self.options.not_before {
+            if now < not_before {
+                return Err(format!("not valid before {}", not_before));
+            }
+        }
+        if let Some(not_after) = self.options.not_after {
+            // Times are given to the second, the last second counts as a whole.
+            if now.timestamp() > not_after.timestamp() {
+                return Err(format!("expired at {}", not_after));
+            }
+        }
+        if let Some(from) = &self.options.from {
+            if !from.contains(&source_ip.to_canonical()) {
+                return Err(format!("not allowed from {}", source_ip));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compute the SHA-256 fingerprint of a public key, as printed by
+/// `ssh-keygen -l` and `ssh-add -l`.
+///
+/// Keys without an SSH representation, like EC keys on other curves than
+/// the NIST ones, have no fingerprint and cannot be registered.
+pub fn fingerprint(public_key: &PKey<Public>) -> Result<String, Box<dyn Error>> {
+    let blob = openssl_utils::ssh_public_key_blob(public_key)?;
+    Ok(format!("SHA256:{}", STANDARD_NO_PAD.encode(sha256(&blob))))
+}
+
+/// Load every key and setting registered for a user.
+///
+/// Keys are read from `<username>.keys` in the keys directory, falling back
+/// to the single key in `<username>.pem`, with default settings, when no
+/// such file exists.
+pub fn load_user(keys_dir: &Path, username: &str) -> Result<RegisteredUser, Box<dyn Error>> {
+    match fs_utils::get_authorized_keys(keys_dir, username) {
+        Ok(content) => parse_authorized_keys(&content),
+        Err(_) => {
+            let public_key_pem = fs_utils::get_public_key(keys_dir, username)
+                .map_err(|e| format!("no keys registered for {}: {:?}", username, e))?;
+            let public_key = PKey::public_key_from_pem(public_key_pem.as_bytes())?;
+            Ok(RegisteredUser {
+                keys: vec![AuthorizedKey::new(public_key)?],
+                role: Role::default(),
+                quota: Quota::default(),
+                totp: None,
+            })
+        }
+    }
+}
+
+/// Load the fingerprints of revoked keys.
+///
+/// The list is read from `revoked_keys` in the keys directory. A missing
+/// file means nothing is revoked, any other error is returned.
+pub fn load_revoked_fingerprints(keys_dir: &Path) -> Result<HashSet<String>, ErrorKind> {
+    match fs_utils::get_revoked_keys(keys_dir) {
+        Ok(content) => Ok(parse_revoked_fingerprints(&content)),
+        Err(ErrorKind::NotFound) => Ok(HashSet::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Parse a revocation list with one fingerprint per line.
+///
+/// Anything after the fingerprint on a line, and lines starting with `#`,
+/// are treated as comments.
+pub fn parse_revoked_fingerprints(content: &str) -> HashSet<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_whitespace().next())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parse the content of an authorized keys file.
+///
+/// Blank lines and lines starting with `#` are ignored. Invalid key lines
+/// are reported and skipped, so one bad entry does not lock the user out.
+/// An invalid settings line fails the whole file instead, so that a typo
+/// never grants more access than intended.
+pub fn parse_authorized_keys(content: &str) -> Result<RegisteredUser, Box<dyn Error>> {
+    let mut keys = Vec::new();
+    let mut role = Role::default();
+    let mut quota = Quota::default();
+    let mut totp = None;
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(setting) = line.strip_prefix('@') {
+            let (name, value) = setting
+                .split_once(char::is_whitespace)
+                .map(|(name, value)| (name, value.trim()))
+                .unwrap_or((setting, ""));
+            match name {
+                "role" => role = value.parse()?,
+                "quota-bytes" => quota.max_bytes = Some(parse_size(value)?),
+                "quota-files" => quota.max_files = Some(value.parse()?),
+                "totp-secret" => totp = Some(totp_utils::parse_secret(value)?),
+                other => {
+                    return Err(Box::from(format!(
+                        "unknown setting {} on line {}",
+                        other,
+                        index + 1
+                    )))
+                }
+            }
+            continue;
+        }
+        match parse_key_line(line) {
+            Ok(key) => keys.push(key),
+            Err(e) => println!(
+                "Skipping invalid authorized key on line {}: {}",
+                index + 1,
+                e
+            ),
+        }
+    }
+
+    Ok(RegisteredUser {
+        keys,
+        role,
+        quota,
+        totp,
+    })
+}
+
+/// Parse a byte count with an optional `K`, `M` or `G` (binary) suffix.
+fn parse_size(value: &str) -> Result<u64, Box<dyn Error>> {
+    let (digits, multiplier) = match value.char_indices().last() {
+        Some((index, 'K' | 'k')) => (&value[..index], 1 << 10),
+        Some((index, 'M' | 'm')) => (&value[..index], 1 << 20),
+        Some((index, 'G' | 'g')) => (&value[..index], 1 << 30),
+        _ => (value, 1),
+    };
+    let size: u64 = digits.parse()?;
+    size.checked_mul(multiplier)
+        .ok_or_else(|| Box::from(format!("size {} is too large", value)))
+}
+
+/// Parse one key line in the format described on [`AuthorizedKey`].
+pub fn parse_key_line(line: &str) -> Result<AuthorizedKey, Box<dyn Error>> {
+    let mut tokens = line.split_whitespace();
+    let first = tokens.next().ok_or("empty line")?;
+
+    let is_key_type = |token: &str| KEY_TYPES.contains(&token) || SSH_KEY_TYPES.contains(&token);
+    let (options, key_type) = if is_key_type(first) {
+        (KeyOptions::default(), first)
+    } else {
+        let key_type = tokens.next().ok_or("missing key type")?;
+        (parse_options(first)?, key_type)
+    };
+    let key_data = tokens.next().ok_or("missing key data")?;
+    let comment = tokens.collect::<Vec<_>>().join(" ");
+
+    let key_data = STANDARD.decode(key_data)?;
+    let public_key = if SSH_KEY_TYPES.contains(&key_type) {
+        let (blob_type, public_key) = openssl_utils::public_key_from_ssh_blob(&key_data)?;
+        if blob_type != key_type {
+            return Err(Box::from(format!(
+                "key data does not match key type {}",
+                key_type
+            )));
+        }
+        public_key
+    } else {
+        let public_key = PKey::public_key_from_der(&key_data)?;
+        let expected_id = match key_type {
+            "rsa" => Id::RSA,
+            "ecdsa" => Id::EC,
+            "ed25519" => Id::ED25519,
+            "ed448" => Id::ED448,
+            other => return Err(Box::from(format!("unknown key type {}", other))),
+        };
+        if public_key.id() != expected_id {
+            return Err(Box::from(format!(
+                "key data does not match key type {}",
+                key_type
+            )));
+        }
+        public_key
+    };
+
+    Ok(AuthorizedKey {
+        fingerprint: fingerprint(&public_key)?,
+        public_key,
+        comment: if comment.is_empty() {
+            None
+        } else {
+            Some(comment)
+        },
+        options,
+    })
+}
+
+fn parse_options(options: &str) -> Result<KeyOptions, Box<dyn Error>> {
+    let mut parsed = KeyOptions::default();
+
+    for option in split_options(options) {
+        let (name, value) = option
+            .split_once('=')
+            .ok_or_else(|| format!("option {} has no value", option))?;
+        let value = value.trim_matches('"');
+        match name {
+            "not-before" => parsed.not_before = Some(parse_time(value)?),
+            "not-after" | "expiry-time" => parsed.not_after = Some(parse_end_time(value)?),
+            "from" => {
+                let addresses = value
+                    .split(',')
+                    .map(|address| address.parse::<IpAddr>().map(|ip| ip.to_canonical()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                parsed.from = Some(addresses);
+            }
+            other => return Err(Box::from(format!("unknown option {}", other))),
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Split an options string on commas that are not inside double quotes.
+fn split_options(options: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+
+    for (index, character) in options.char_indices() {
+        match character {
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                parts.push(&options[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&options[start..]);
+
+    parts
+}
+
+/// Parse a `YYYYMMDD[HHMM[SS]]` timestamp, interpreted as UTC.
+///
+/// A date alone means the start of that day.
+pub fn parse_time(value: &str) -> Result<DateTime<Utc>, Box<dyn Error>> {
+    parse_time_with_default(value, NaiveTime::MIN)
+}
+
+/// Parse the end of a validity period, like [`parse_time`] except that a
+/// date alone means the last second of that day.
+fn parse_end_time(value: &str) -> Result<DateTime<Utc>, Box<dyn Error>> {
+    let end_of_day = NaiveTime::from_hms_opt(23, 59, 59).ok_or("invalid time")?;
+    parse_time_with_default(value, end_of_day)
+}
+
+/// Parse a timestamp, using `time_of_day` for dates without a time.
+fn parse_time_with_default(
+    value: &str,
+    time_of_day: NaiveTime,
+) -> Result<DateTime<Utc>, Box<dyn Error>> {
+    if !value.chars().all(|character| character.is_ascii_digit()) {
+        return Err(Box::from(format!("invalid time {}", value)));
+    }
+    let time = match value.len() {
+        8 => NaiveDate::parse_from_str(value, "%Y%m%d")?.and_time(time_of_day),
+        12 => NaiveDateTime::parse_from_str(value, "%Y%m%d%H%M")?,
+        14 => NaiveDateTime::parse_from_str(value, "%Y%m%d%H%M%S")?,
+        _ => return Err(Box::from(format!("invalid time {}", value))),
+    };
+    Ok(time.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::Private;
+
+    use super::*;
+
+    /// An OpenSSH public key line and the fingerprint `ssh-keygen -lf`
+    /// prints for it.
+    const ED25519_LINE: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIPgnzkFJpXB3Oi4cH10S70auznvcxxv6Hxv69wkc+Z6V edtest";
+    const ED25519_FINGERPRINT: &str = "SHA256:jf1QmROpn5Wa+2RUFzFR2nzq085ka6mXpi2f4vnS7Gc";
+
+    fn der_line(key_type: &str, key: &PKey<Private>) -> String {
+        format!(
+            "{} {}",
+            key_type,
+            STANDARD.encode(key.public_key_to_der().unwrap())
+        )
+    }
+
+    fn ec_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn parses_openssh_key_lines() {
+        let key = parse_key_line(ED25519_LINE).unwrap();
+        assert_eq!(key.fingerprint, ED25519_FINGERPRINT);
+        assert_eq!(key.comment.as_deref(), Some("edtest"));
+        assert_eq!(key.public_key.id(), Id::ED25519);
+    }
+
+    #[test]
+    fn parses_der_key_lines() {
+        let ed25519 = PKey::generate_ed25519().unwrap();
+        let line = format!("{} laptop of fk", der_line("ed25519", &ed25519));
+        let key = parse_key_line(&line).unwrap();
+        assert!(key.public_key.public_eq(&ed25519));
+        assert_eq!(key.comment.as_deref(), Some("laptop of fk"));
+
+        // The fingerprint is the same as for the OpenSSH form of the key.
+        let blob = openssl_utils::ssh_public_key_blob(&key.public_key).unwrap();
+        let ssh_line = format!("ssh-ed25519 {}", STANDARD.encode(blob));
+        assert_eq!(
+            parse_key_line(&ssh_line).unwrap().fingerprint,
+            key.fingerprint
+        );
+
+        let key = parse_key_line(&der_line("ecdsa", &ec_key())).unwrap();
+        assert_eq!(key.comment, None);
+    }
+
+    #[test]
+    fn checks_source_address() {
+        let line = format!("from=\"10.0.0.1,::1,::ffff:10.0.0.3\" {}", ED25519_LINE);
+        let key = parse_key_line(&line).unwrap();
+        assert_eq!(key.fingerprint, ED25519_FINGERPRINT);
+        assert_eq!(
+            key.options.from,
+            Some(vec![
+                "10.0.0.1".parse().unwrap(),
+                "::1".parse().unwrap(),
+                "10.0.0.3".parse().unwrap(),
+            ])
+        );
+
+        let now = Utc::now();
+        for allowed in ["10.0.0.1", "::1", "::ffff:10.0.0.1", "10.0.0.3"] {
+            let source_ip: IpAddr = allowed.parse().unwrap();
+            assert!(key.check_options(now, source_ip).is_ok(), "{}", allowed);
+        }
+        for denied in ["10.0.0.2", "127.0.0.1", "::ffff:10.0.0.2", "::2"] {
+            let source_ip: IpAddr = denied.parse().unwrap();
+            assert!(key.check_options(now, source_ip).is_err(), "{}", denied);
+        }
+    }
+
+    #[test]
+    fn parses_key_options() {
+        let line = format!(
+            "not-before=\"20260101\",expiry-time=\"202612311200\",from=\"10.0.0.1\" {}",
+            ED25519_LINE
+        );
+        let key = parse_key_line(&line).unwrap();
+        assert_eq!(key.fingerprint, ED25519_FINGERPRINT);
+        let options = &key.options;
+        assert_eq!(options.not_before, Some(parse_time("20260101").unwrap()));
+        assert_eq!(
+            options.not_after,
+            Some(parse_time("20261231120000").unwrap())
+        );
+
+        let allowed: IpAddr = "10.0.0.1".parse().unwrap();
+        let in_range = parse_time("20260601").unwrap();
+        assert!(key.check_options(in_range, allowed).is_ok());
+        assert!(key
+            .check_options(parse_time("20251231235959").unwrap(), allowed)
+            .is_err());
+        assert!(key
+            .check_options(parse_time("202612311200").unwrap(), allowed)
+            .is_ok());
+        assert!(key
+            .check_options(parse_time("20261231120001").unwrap(), allowed)
+            .is_err());
+    }
+
+    #[test]
+    fn date_only_expiry_lasts_the_whole_day() {
+        let line = format!("not-after=\"20261231\" {}", ED25519_LINE);
+        let key = parse_key_line(&line).unwrap();
+        assert_eq!(
+            key.options.not_after,
+            Some(parse_time("20261231235959").unwrap())
+        );
+
+        let source_ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let last_moment =
+            parse_time("20261231235959").unwrap() + chrono::Duration::milliseconds(999);
+        assert!(key
+            .check_options(parse_time("20261231").unwrap(), source_ip)
+            .is_ok());
+        assert!(key.check_options(last_moment, source_ip).is_ok());
+        assert!(key
+            .check_options(parse_time("20270101").unwrap(), source_ip)
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_bad_key_lines() {
+        let ed25519 = PKey::generate_ed25519().unwrap();
+        let (_, ssh_key) = ED25519_LINE.split_once(' ').unwrap();
+        let ssh_key = ssh_key.trim_end_matches(" edtest");
+        let truncated = &ssh_key[..ssh_key.len() - 8];
+        for line in [
+            "ssh-ed25519".to_string(),
+            format!("ssh-rsa {}", ssh_key),
+            format!("ssh-ed25519 {}", truncated),
+            "ssh-ed25519 not-base64!".to_string(),
+            der_line("rsa", &ed25519),
+            der_line("dsa", &ed25519),
+            format!("unknown=\"1\" {}", ED25519_LINE),
+            format!("not-after=\"2026-12-31\" {}", ED25519_LINE),
+            format!("not-after=\"202612\" {}", ED25519_LINE),
+            format!("from=\"10.0.0.300\" {}", ED25519_LINE),
+            format!("from {}", ED25519_LINE),
+        ] {
+            assert!(parse_key_line(&line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn parses_settings_and_skips_bad_keys() {
+        let content = format!(
+            "# keys of fk\n\n@role read-only\n@quota-bytes 10M\n@quota-files 100\n\
+             @totp-secret JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP\n{}\nssh-ed25519 broken\n",
+            ED25519_LINE
+        );
+        let user = parse_authorized_keys(&content).unwrap();
+        assert_eq!(user.keys.len(), 1);
+        assert_eq!(user.keys[0].fingerprint, ED25519_FINGERPRINT);
+        assert_eq!(user.role, Role::ReadOnly);
+        assert_eq!(user.quota.max_bytes, Some(10 << 20));
+        assert_eq!(user.quota.max_files, Some(100));
+        assert!(user.totp.is_some());
+
+        let user = parse_authorized_keys(ED25519_LINE).unwrap();
+        assert_eq!(user.role, Role::default());
+        assert_eq!(user.quota, Quota::default());
+        assert!(user.totp.is_none());
+    }
+
+    #[test]
+    fn rejects_bad_settings() {
+        for setting in [
+            "@role root",
+            "@role",
+            "@quota-bytes lots",
+            "@quota-bytes 99999999999G",
+            "@quota-files -1",
+            "@totp-secret not base32!",
+            "@totp-secret JBSWY3DP",
+            "@unknown 1",
+        ] {
+            let content = format!("{}\n{}\n", ED25519_LINE, setting);
+            assert!(parse_authorized_keys(&content).is_err(), "{}", setting);
+        }
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("4k").unwrap(), 4 << 10);
+        assert_eq!(parse_size("10M").unwrap(), 10 << 20);
+        assert_eq!(parse_size("2G").unwrap(), 2 << 30);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("K").is_err());
+        assert!(parse_size("-1").is_err());
+    }
+
+    #[test]
+    fn parses_revocation_list() {
+        let revoked = parse_revoked_fingerprints(&format!(
+            "# revoked keys\n\n{} lost laptop\n  SHA256:other  \n",
+            ED25519_FINGERPRINT
+        ));
+        assert_eq!(revoked.len(), 2);
+        assert!(revoked.contains(ED25519_FINGERPRINT));
+        assert!(revoked.contains("SHA256:other"));
+    }
+}
@@ -0,0 +1,12 @@
+use clap_derive::Parser;
+use std::path::PathBuf;
+
+/// Represents the command-line arguments.
+#[derive(Parser)]
+#[command(name = "FTP Server")]
+#[command(about = "An FTPS server authenticating users with public keys", long_about = None)]
+pub struct Cli {
+    /// TOML config file, the built-in defaults are used when omitted.
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+}
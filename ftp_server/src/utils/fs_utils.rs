@@ -1,12 +1,80 @@
-use std::{env, io::ErrorKind, process::Command};
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// List the users that have a `.keys` or `.pem` file in the keys directory.
+pub fn list_key_users(keys_dir: &Path) -> std::io::Result<BTreeSet<String>> {
+    let mut usernames = BTreeSet::new();
+    for entry in fs::read_dir(keys_dir)? {
+        let path = entry?.path();
+        let is_key_file = matches!(
+            path.extension().and_then(|extension| extension.to_str()),
+            Some("keys") | Some("pem")
+        );
+        if let (true, Some(username)) = (is_key_file, path.file_stem()) {
+            usernames.insert(username.to_string_lossy().to_string());
+        }
+    }
+    Ok(usernames)
+}
 
 /// Get the public key from the file system.
-pub fn get_public_key(username: &str) -> Result<String, ErrorKind> {
-    let root = env::current_dir().unwrap();
-    let public_key_path = root.join(format!("keys\\{}.pem", &username));
-    let result = std::fs::read_to_string(&public_key_path);
+pub fn get_public_key(keys_dir: &Path, username: &str) -> Result<String, ErrorKind> {
+    check_username(username)?;
+    let public_key_path = keys_dir.join(format!("{}.pem", username));
+    read_file(&public_key_path)
+}
+
+/// Get the authorized keys file of a user from the file system.
+pub fn get_authorized_keys(keys_dir: &Path, username: &str) -> Result<String, ErrorKind> {
+    check_username(username)?;
+    let authorized_keys_path = keys_dir.join(format!("{}.keys", username));
+    if !authorized_keys_path.exists() {
+        return Err(ErrorKind::NotFound);
+    }
+    read_file(&authorized_keys_path)
+}
+
+/// Get the list of revoked key fingerprints from the file system.
+///
+/// Only a missing file is reported as `NotFound`, any other failure means
+/// the list exists but could not be read.
+pub fn get_revoked_keys(keys_dir: &Path) -> Result<String, ErrorKind> {
+    let revoked_keys_path = keys_dir.join("revoked_keys");
+    fs::metadata(&revoked_keys_path).map_err(|e| e.kind())?;
+    read_file(&revoked_keys_path).map_err(|_| ErrorKind::Other)
+}
+
+/// Get the home directory of a user under the given root, creating it on
+/// first login.
+pub fn create_home_dir(home_root: &Path, username: &str) -> std::io::Result<PathBuf> {
+    check_username(username)?;
+    let home = home_root.join(username);
+    if !home.is_dir() {
+        fs::create_dir_all(&home)?;
+        println!("Created home directory {}", home.display());
+    }
+    Ok(home)
+}
+
+/// Reject usernames that could escape the keys or home directories.
+fn check_username(username: &str) -> Result<(), ErrorKind> {
+    if username.is_empty() || username.starts_with('.') || username.contains(['/', '\\', ':']) {
+        println!("Rejected invalid username: {:?}", username);
+        return Err(ErrorKind::InvalidInput);
+    }
+    Ok(())
+}
+
+/// Read a file, retrying with administrator permission if needed.
+fn read_file(path: &Path) -> Result<String, ErrorKind> {
+    let result = std::fs::read_to_string(path);
     match result {
-        Ok(public_key_content) => Ok(public_key_content),
+        Ok(content) => Ok(content),
 
         Err(_) => {
             let output = Command::new("cmd")
@@ -14,21 +82,21 @@ pub fn get_public_key(username: &str) -> Result<String, ErrorKind> {
                 .arg("/user:Administrator")
                 .arg("/c")
                 .arg("type")
-                .arg(&public_key_path)
+                .arg(path)
                 .output();
 
             match output {
                 Ok(output) => {
                     if output.status.success() {
-                        return Ok(String::from_utf8_lossy(&output.stdout).to_string());
+                        Ok(String::from_utf8_lossy(&output.stdout).to_string())
                     } else {
                         println!("Error on give administrator permission: {}", output.status);
-                        return Err(ErrorKind::NotFound);
+                        Err(ErrorKind::NotFound)
                     }
                 }
                 Err(e) => {
                     println!("Error on give administrator permission: {}", e);
-                    return Err(ErrorKind::NotFound);
+                    Err(ErrorKind::NotFound)
                 }
             }
         }
@@ -0,0 +1,98 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use libunftp::auth::UserDetail;
+
+/// What a user is allowed to do once logged in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Role {
+    /// Can list and download files.
+    ReadOnly,
+    /// Can only upload new files (a drop box), not list, download or overwrite.
+    UploadOnly,
+    /// Can do everything inside their own home directory.
+    #[default]
+    ReadWrite,
+    /// Can do everything, in every user's directory.
+    Admin,
+}
+
+/// The kind of access a storage operation needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Download files or read their metadata.
+    Read,
+    /// List directories.
+    List,
+    /// Upload files and create directories.
+    Write,
+    /// Delete, rename or overwrite existing files and directories.
+    Delete,
+}
+
+impl Role {
+    /// Check whether the role grants a permission.
+    pub fn allows(&self, permission: Permission) -> bool {
+        match self {
+            Role::ReadOnly => matches!(permission, Permission::Read | Permission::List),
+            Role::UploadOnly => permission == Permission::Write,
+            Role::ReadWrite | Role::Admin => true,
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role {
+            "read-only" => Ok(Role::ReadOnly),
+            "upload-only" => Ok(Role::UploadOnly),
+            "read-write" => Ok(Role::ReadWrite),
+            "admin" => Ok(Role::Admin),
+            other => Err(format!("unknown role {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let role = match self {
+            Role::ReadOnly => "read-only",
+            Role::UploadOnly => "upload-only",
+            Role::ReadWrite => "read-write",
+            Role::Admin => "admin",
+        };
+        write!(f, "{}", role)
+    }
+}
+
+/// Storage limits of a user. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+}
+
+/// An authenticated user, jailed to their own home directory unless `home`
+/// is `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FtpUser {
+    pub username: String,
+    pub home: Option<PathBuf>,
+    pub role: Role,
+    pub quota: Quota,
+}
+
+impl UserDetail for FtpUser {
+    fn home(&self) -> Option<&Path> {
+        self.home.as_deref()
+    }
+}
+
+impl fmt::Display for FtpUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.username)
+    }
+}
@@ -0,0 +1,1587 @@
+use std::error::Error;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use libunftp::auth::{AuthenticationError, Authenticator, Credentials};
+use libunftp::options::{ActivePassiveMode, FtpsRequired};
+use libunftp::storage::{Metadata, StorageBackend};
+use libunftp::ServerBuilder;
+use openssl::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::mpsc;
+use tokio_openssl::SslStream;
+
+use super::ftp_user::FtpUser;
+use super::login_challenge::LoginNonce;
+use super::proxy_protocol::{ProxiedConnection, ProxyProtocol};
+use super::quota_storage::QuotaReport;
+use super::session_limits::{SessionState, SessionTracker};
+
+/// Checks the logins of relayed sessions, with access to the state of the
+/// session the login happens in.
+#[async_trait]
+pub trait SessionAuthenticator: std::fmt::Debug + Send + Sync {
+    /// `nonce` is the nonce sent to the client for this login, `None` when
+    /// it was never sent, was already used or is too old.
+    async fn authenticate(
+        &self,
+        username: &str,
+        creds: &Credentials,
+        session: &SessionState,
+        nonce: Option<String>,
+    ) -> Result<FtpUser, AuthenticationError>;
+}
+
+/// Authenticator shared by every session of the server.
+pub type SharedAuthenticator = Arc<dyn SessionAuthenticator>;
+
+/// Authenticator handed to a libunftp session.
+pub type SessionAuthenticatorHandle = Arc<dyn Authenticator<FtpUser> + Send + Sync>;
+
+/// How long a client has to open the data connection after PASV, and the
+/// relay has to connect to the client after PORT or EPRT.
+const DATA_CONNECTION_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Longest command line the relay reads at once, longer lines are copied
+/// in pieces.
+const MAX_COMMAND_LENGTH: u64 = 4096;
+
+/// How much later than the relay the idle timeout of libunftp fires.
+const LIBUNFTP_IDLE_MARGIN_SECS: u64 = 60;
+
+/// Settings of a relayed FTPS listener.
+#[derive(Debug, Clone)]
+pub struct FtpsRelay {
+    pub address: String,
+    /// Whether clients start the TLS handshake right away, instead of
+    /// upgrading with `AUTH TLS`.
+    pub implicit: bool,
+    pub certificate_path: PathBuf,
+    pub key_path: PathBuf,
+    /// Range the passive data ports offered to clients are taken from.
+    pub passive_ports: Range<u16>,
+    /// Whether the control channel must be secured before USER and PASS,
+    /// only checked for explicit FTPS.
+    pub control_tls: FtpsRequired,
+    /// Whether data channels must be secured with `PROT P`.
+    pub data_tls: FtpsRequired,
+    /// Read a PROXY header at the start of every control and data
+    /// connection.
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// Session limits shared by every listener.
+    pub sessions: Arc<SessionTracker>,
+    /// Usage and quota of the users, for `SITE QUOTA`.
+    pub quota_report: Arc<dyn QuotaReport>,
+}
+
+impl FtpsRelay {
+    fn name(&self) -> &'static str {
+        if self.implicit {
+            "implicit FTPS"
+        } else {
+            "explicit FTPS"
+        }
+    }
+}
+
+/// Accept FTPS connections and relay them to libunftp sessions.
+///
+/// libunftp only speaks explicit FTPS and only knows the address of the
+/// socket it reads from, so the TLS handshake is done here and the
+/// decrypted control channel is relayed to a libunftp session over a
+/// loopback connection. This is how implicit FTPS is served, how the
+/// client address from a PROXY header reaches the authenticator, and where
+/// the session limits are enforced with 421 replies. Every
+/// session is built with `make_server`, so it shares the authenticator,
+/// storage and certificates of the other listeners.
+///
+/// The relay also answers USER itself, with a 331 reply carrying a fresh
+/// nonce that the client signs as its password. USER is passed on to the
+/// session together with the PASS that follows. `SITE QUOTA` is answered
+/// by the relay as well, with the storage usage and quota of the user.
+///
+/// Passive data connections are relayed as well: the PASV replies of the
+/// session are rewritten to a port on the address the client connected to,
+/// and the data, still encrypted by libunftp after `PROT P`, is copied to
+/// the port of the session. libunftp has no EPSV, so the relay passes EPSV
+/// on as PASV and answers with a 229 reply, which is how IPv6 clients get
+/// passive transfers.
+///
+/// Active mode goes through the relay too. For `PORT` and `EPRT` the relay
+/// connects to the client itself, only ever to the address of the control
+/// channel, and hands the session a loopback port in a `PORT` command of
+/// its own. Behind a PROXY protocol proxy the relay cannot reach the
+/// client, so active mode is refused there.
+///
+/// `AUTH` is only answered before the login, by `negotiate_tls`. Later on
+/// the relay refuses it, since the session cannot secure a channel it only
+/// sees through the loopback connection.
+pub async fn listen<S, F>(
+    settings: FtpsRelay,
+    authenticator: SharedAuthenticator,
+    make_server: F,
+) -> Result<(), Box<dyn Error>>
+where
+    S: StorageBackend<FtpUser> + 'static,
+    S::Metadata: Metadata,
+    F: Fn(SessionAuthenticatorHandle) -> ServerBuilder<S, FtpUser> + Send + Sync + 'static,
+{
+    let acceptor = Arc::new(tls_acceptor(
+        &settings.certificate_path,
+        &settings.key_path,
+    )?);
+    let settings = Arc::new(settings);
+    let make_server = Arc::new(make_server);
+    let listener = TcpListener::bind(&settings.address).await?;
+    println!(
+        "Listening for {}{} on {}",
+        settings.name(),
+        if settings.proxy_protocol.is_some() {
+            " behind a PROXY protocol proxy"
+        } else {
+            ""
+        },
+        settings.address
+    );
+
+    loop {
+        let (tcp, peer_addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                println!("Error on accept {} connection: {}", settings.name(), e);
+                continue;
+            }
+        };
+        let acceptor = Arc::clone(&acceptor);
+        let settings = Arc::clone(&settings);
+        let make_server = Arc::clone(&make_server);
+        let authenticator = Arc::clone(&authenticator);
+        tokio::spawn(async move {
+            let session = serve_connection(
+                tcp,
+                &acceptor,
+                &settings,
+                authenticator,
+                make_server.as_ref(),
+            );
+            if let Err(e) = session.await {
+                println!(
+                    "Error on {} session from {}: {}",
+                    settings.name(),
+                    peer_addr,
+                    e
+                );
+            }
+        });
+    }
+}
+
+/// Build the TLS acceptor from the certificate chain and key of the server.
+fn tls_acceptor(certificate_path: &Path, key_path: &Path) -> Result<SslAcceptor, Box<dyn Error>> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+    builder.set_certificate_chain_file(certificate_path)?;
+    builder.set_private_key_file(key_path, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    Ok(builder.build())
+}
+
+/// Secure the control channel of the client and relay it to a new libunftp
+/// session until the session ends.
+async fn serve_connection<S, F>(
+    mut tcp: TcpStream,
+    acceptor: &SslAcceptor,
+    settings: &FtpsRelay,
+    authenticator: SharedAuthenticator,
+    make_server: &F,
+) -> Result<(), Box<dyn Error>>
+where
+    S: StorageBackend<FtpUser> + 'static,
+    S::Metadata: Metadata,
+    F: Fn(SessionAuthenticatorHandle) -> ServerBuilder<S, FtpUser>,
+{
+    let local_addr = tcp.local_addr()?;
+    let connection = match &settings.proxy_protocol {
+        Some(proxy_protocol) => proxy_protocol.accept(&mut tcp).await?,
+        None => ProxiedConnection {
+            source: tcp.peer_addr()?,
+            destination: local_addr,
+        },
+    };
+
+    let idle_timeout = settings.sessions.limits().idle_timeout();
+    let login_timeout = settings.sessions.limits().login_timeout();
+    let slot = match settings.sessions.open(connection.source.ip()) {
+        Ok(slot) => slot,
+        Err(reason) => {
+            println!(
+                "Refusing {} session from {}: {}",
+                settings.name(),
+                connection.source.ip(),
+                reason
+            );
+            if settings.implicit {
+                let mut tls = tokio::time::timeout(login_timeout, accept_tls(acceptor, tcp))
+                    .await
+                    .map_err(|_| "timed out waiting for the TLS handshake")??;
+                refuse(&mut tls, reason).await?;
+            } else {
+                refuse(&mut tcp, reason).await?;
+            }
+            return Ok(());
+        }
+    };
+    let state = slot.state();
+    let nonce = Arc::new(LoginNonce::default());
+    let user = Arc::new(Mutex::new(None));
+
+    // The session only sees the loopback connection, so the authenticator
+    // is told the real address of the client.
+    let authenticator: SessionAuthenticatorHandle = Arc::new(ForwardedAuthenticator {
+        inner: authenticator,
+        source_ip: connection.source.ip(),
+        state: Arc::clone(&state),
+        nonce: Arc::clone(&nonce),
+        user: Arc::clone(&user),
+    });
+    // The relay closes idle sessions with a 421 reply, the timeout of
+    // libunftp only catches sessions the relay missed.
+    let server = make_server(authenticator)
+        .ftps_required(FtpsRequired::None, settings.data_tls)
+        .active_passive_mode(ActivePassiveMode::ActiveAndPassive)
+        .idle_session_timeout(idle_timeout.as_secs() + LIBUNFTP_IDLE_MARGIN_SECS)
+        .build()?;
+    let (relay_stream, session_stream) = loopback_pair().await?;
+    let session = tokio::spawn(server.service(session_stream));
+
+    let relay = ControlRelay {
+        client_ip: connection.source.ip(),
+        bind_ip: local_addr.ip(),
+        public_ip: connection.destination.ip(),
+        passive_ports: settings.passive_ports.clone(),
+        proxy_protocol: settings.proxy_protocol.clone(),
+        state: Arc::clone(&state),
+        nonce,
+        user,
+        quota_report: Arc::clone(&settings.quota_report),
+    };
+    let relayed = if settings.implicit {
+        let tls = tokio::time::timeout(login_timeout, accept_tls(acceptor, tcp))
+            .await
+            .map_err(|_| "timed out waiting for the TLS handshake")??;
+        relay.run(tls, relay_stream, b"").await
+    } else {
+        let mut client = BufReader::new(tcp);
+        let mut session_stream = BufReader::new(relay_stream);
+        let negotiated = tokio::select! {
+            negotiated = negotiate_tls(&mut client, &mut session_stream, settings.control_tls, &state) => Ok(negotiated?),
+            reason = state.expired() => Err(reason),
+        };
+        match negotiated {
+            Ok(Negotiated::Tls) => {
+                if !client.buffer().is_empty() {
+                    return Err(Box::from("client sent data before the TLS handshake"));
+                }
+                let tls =
+                    tokio::time::timeout(login_timeout, accept_tls(acceptor, client.into_inner()))
+                        .await
+                        .map_err(|_| "timed out waiting for the TLS handshake")??;
+                relay.run(tls, session_stream, b"").await
+            }
+            Ok(Negotiated::Plaintext(command)) => relay.run(client, session_stream, &command).await,
+            Ok(Negotiated::Closed) => Ok(()),
+            Err(reason) => {
+                println!(
+                    "Closing session from {}: {}",
+                    connection.source.ip(),
+                    reason
+                );
+                refuse(&mut client, reason).await
+            }
+        }
+    };
+    if let Err(e) = session.await? {
+        println!("Error on {} control channel: {}", settings.name(), e);
+    }
+    match relayed {
+        // Clients hanging up without QUIT are not worth reporting.
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe
+            ) =>
+        {
+            Ok(())
+        }
+        result => Ok(result?),
+    }
+}
+
+/// Send a 421 reply and close the control channel.
+async fn refuse<W: AsyncWrite + Unpin>(client: &mut W, reason: &str) -> io::Result<()> {
+    client
+        .write_all(format!("421 {}\r\n", reason).as_bytes())
+        .await?;
+    client.shutdown().await
+}
+
+/// Do the TLS handshake with the client.
+async fn accept_tls(
+    acceptor: &SslAcceptor,
+    tcp: TcpStream,
+) -> Result<SslStream<TcpStream>, Box<dyn Error>> {
+    let mut tls = SslStream::new(Ssl::new(acceptor.context())?, tcp)?;
+    Pin::new(&mut tls).accept().await?;
+    Ok(tls)
+}
+
+/// How the plaintext start of an explicit FTPS control channel ended.
+enum Negotiated {
+    /// The client sent `AUTH TLS` and is waiting for the handshake.
+    Tls,
+    /// TLS is optional and the client started to log in without it, with
+    /// the given USER or PASS command.
+    Plaintext(Vec<u8>),
+    /// The client quit or disconnected.
+    Closed,
+}
+
+/// Relay the plaintext start of an explicit FTPS control channel, one
+/// command and reply at a time, until the client asks for TLS.
+///
+/// `AUTH TLS` is answered here, since the TLS handshake is done by the
+/// relay, and logins before it are refused when TLS is required, as the
+/// session has no way to tell.
+async fn negotiate_tls<C, S>(
+    client: &mut C,
+    session: &mut S,
+    control_tls: FtpsRequired,
+    state: &SessionState,
+) -> io::Result<Negotiated>
+where
+    C: AsyncBufRead + AsyncWrite + Unpin,
+    S: AsyncBufRead + AsyncWrite + Unpin,
+{
+    let greeting = read_reply(session).await?;
+    client.write_all(&greeting).await?;
+    client.flush().await?;
+
+    let mut command = Vec::new();
+    loop {
+        command.clear();
+        if client.read_until(b'\n', &mut command).await? == 0 {
+            return Ok(Negotiated::Closed);
+        }
+        state.touch();
+        let line = String::from_utf8_lossy(&command)
+            .trim()
+            .to_ascii_uppercase();
+        let (verb, argument) = line.split_once(' ').unwrap_or((&line, ""));
+        match verb {
+            "AUTH" if matches!(argument, "TLS" | "TLS-C" | "SSL") => {
+                client
+                    .write_all(b"234 AUTH command OK. Initializing TLS connection.\r\n")
+                    .await?;
+                client.flush().await?;
+                return Ok(Negotiated::Tls);
+            }
+            "AUTH" => {
+                client.write_all(b"504 AUTH type not supported\r\n").await?;
+            }
+            "USER" | "PASS" if control_tls != FtpsRequired::None => {
+                client
+                    .write_all(b"534 A TLS connection is required on the control channel\r\n")
+                    .await?;
+            }
+            "USER" | "PASS" => return Ok(Negotiated::Plaintext(command)),
+            _ => {
+                session.write_all(&command).await?;
+                session.flush().await?;
+                let reply = read_reply(session).await?;
+                client.write_all(&reply).await?;
+                if verb == "QUIT" {
+                    client.flush().await?;
+                    return Ok(Negotiated::Closed);
+                }
+            }
+        }
+        client.flush().await?;
+    }
+}
+
+/// Read one reply, all lines of a multi-line reply included.
+async fn read_reply<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut reply = Vec::new();
+    let start = reader.read_until(b'\n', &mut reply).await?;
+    if start < 4 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "session closed the control channel",
+        ));
+    }
+    if reply[3] == b'-' {
+        // A multi-line reply ends with the code followed by a space.
+        let mut end = reply[..3].to_vec();
+        end.push(b' ');
+        let mut offset = 0;
+        while !reply[offset..].starts_with(&end) {
+            offset = reply.len();
+            if reader.read_until(b'\n', &mut reply).await? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "session closed the control channel",
+                ));
+            }
+        }
+    }
+    Ok(reply)
+}
+
+/// Open a connected pair of loopback sockets.
+async fn loopback_pair() -> io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let client = TcpStream::connect(listener.local_addr()?).await?;
+    let client_addr = client.local_addr()?;
+    loop {
+        let (server, peer_addr) = listener.accept().await?;
+        if peer_addr == client_addr {
+            return Ok((client, server));
+        }
+    }
+}
+
+/// Relays the control channel between a client and its libunftp session.
+struct ControlRelay {
+    client_ip: IpAddr,
+    /// Address the data ports are bound on.
+    bind_ip: IpAddr,
+    /// Address the client connected to, which PASV replies point to. This
+    /// is the address of the proxy when the connection came through one.
+    public_ip: IpAddr,
+    passive_ports: Range<u16>,
+    proxy_protocol: Option<ProxyProtocol>,
+    state: Arc<SessionState>,
+    /// Nonce sent in the 331 reply to USER.
+    nonce: Arc<LoginNonce>,
+    /// The user logged in on the session.
+    user: Arc<Mutex<Option<FtpUser>>>,
+    quota_report: Arc<dyn QuotaReport>,
+}
+
+impl ControlRelay {
+    /// Copy commands to the session and replies to the client until the
+    /// session closes the control channel, or the relay closes it with a
+    /// 421 reply because a session limit was hit.
+    ///
+    /// `first_command` is a command the client sent before the relay took
+    /// over, handled before anything else is read.
+    async fn run<C, S>(&self, client: C, session: S, first_command: &[u8]) -> io::Result<()>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (client_read, mut client_write) = tokio::io::split(client);
+        let (session_read, mut session_write) = tokio::io::split(session);
+        // Replies of the relay itself, sent between the replies of the session.
+        let (relay_replies, mut pending_replies) = mpsc::unbounded_channel();
+        // Replies of the session to USER commands the relay already answered.
+        let answered_replies = AtomicUsize::new(0);
+        // Whether the last passive command was an EPSV passed on as PASV.
+        let extended_passive = AtomicBool::new(false);
+        let commands = async {
+            let mut client_read = BufReader::new(first_command.chain(client_read));
+            let mut command = Vec::new();
+            let mut at_line_start = true;
+            let mut skipping = false;
+            // USER command answered by the relay, passed on with the PASS.
+            let mut pending_user = None;
+            loop {
+                command.clear();
+                let read = (&mut client_read)
+                    .take(MAX_COMMAND_LENGTH)
+                    .read_until(b'\n', &mut command)
+                    .await?;
+                if read == 0 {
+                    break;
+                }
+                self.state.touch();
+                let complete = command.ends_with(b"\n");
+                if at_line_start && is_active_command(&command) {
+                    let relayed = if complete {
+                        self.relay_active(&command).await
+                    } else {
+                        Err("500 Command line too long\r\n")
+                    };
+                    match relayed {
+                        Ok(port) => session_write.write_all(port.as_bytes()).await?,
+                        Err(reply) => {
+                            let _ = relay_replies.send(reply.to_string());
+                        }
+                    }
+                    skipping = !complete;
+                } else if at_line_start
+                    && command_is(&command, b"USER")
+                    && !self.state.is_logged_in()
+                {
+                    // The session answers USER with a fixed text, so the
+                    // relay answers it with the nonce to sign instead.
+                    let reply = if !complete {
+                        "500 Command line too long\r\n".to_string()
+                    } else {
+                        match self.nonce.issue() {
+                            Ok(nonce) => {
+                                pending_user = Some(command.clone());
+                                format!("331 Password required, sign nonce {}\r\n", nonce)
+                            }
+                            Err(e) => {
+                                println!("Error on issue login nonce: {}", e);
+                                "451 Can't start the login, try again\r\n".to_string()
+                            }
+                        }
+                    };
+                    let _ = relay_replies.send(reply);
+                    skipping = !complete;
+                } else if at_line_start && command_is(&command, b"AUTH") {
+                    let _ = relay_replies
+                        .send("503 AUTH is only accepted before the login\r\n".to_string());
+                    skipping = !complete;
+                } else if at_line_start && is_extended_passive_command(&command) {
+                    let relayed = if complete {
+                        self.check_extended_passive(&command)
+                    } else {
+                        Err("500 Command line too long\r\n")
+                    };
+                    match relayed {
+                        Ok(()) => {
+                            extended_passive.store(true, Ordering::SeqCst);
+                            session_write.write_all(b"PASV\r\n").await?;
+                        }
+                        Err(reply) => {
+                            let _ = relay_replies.send(reply.to_string());
+                        }
+                    }
+                    skipping = !complete;
+                } else if at_line_start && is_quota_command(&command) {
+                    let reply = if complete {
+                        self.report_quota().await
+                    } else {
+                        "500 Command line too long\r\n".to_string()
+                    };
+                    let _ = relay_replies.send(reply);
+                    skipping = !complete;
+                } else if skipping {
+                    skipping = !complete;
+                } else {
+                    if at_line_start && command_is(&command, b"PASS") {
+                        if let Some(user) = pending_user.take() {
+                            answered_replies.fetch_add(1, Ordering::SeqCst);
+                            session_write.write_all(&user).await?;
+                        }
+                    }
+                    if at_line_start && command_is(&command, b"PASV") {
+                        extended_passive.store(false, Ordering::SeqCst);
+                    }
+                    session_write.write_all(&command).await?;
+                }
+                at_line_start = complete;
+            }
+            session_write.shutdown().await
+        };
+        let replies = async {
+            let mut session_read = BufReader::new(session_read);
+            let mut line = Vec::new();
+            // Code of the multi-line reply being copied, if any.
+            let mut open_reply: Option<Vec<u8>> = None;
+            // Lines of the PASV reply being read, replaced as a whole.
+            let mut passive_reply = Vec::new();
+            loop {
+                let between_replies = line.is_empty() && open_reply.is_none();
+                let read = tokio::select! {
+                    read = session_read.read_until(b'\n', &mut line) => read?,
+                    Some(reply) = pending_replies.recv(), if between_replies => {
+                        client_write.write_all(reply.as_bytes()).await?;
+                        client_write.flush().await?;
+                        continue;
+                    }
+                    reason = self.state.expired() => {
+                        println!("Closing session from {}: {}", self.client_ip, reason);
+                        client_write
+                            .write_all(format!("421 {}\r\n", reason).as_bytes())
+                            .await?;
+                        break;
+                    }
+                };
+                if read == 0 {
+                    break;
+                }
+                self.state.touch();
+                // The client already got the 331 reply of the relay to USER.
+                let answered = open_reply.is_none()
+                    && answered_replies
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                            count.checked_sub(1)
+                        })
+                        .is_ok()
+                    && line.starts_with(b"331 ");
+                if (open_reply.is_none() && line.starts_with(b"227")) || !passive_reply.is_empty() {
+                    passive_reply.extend_from_slice(&line);
+                }
+                match &open_reply {
+                    Some(code) if line.starts_with(code) && line.get(3) == Some(&b' ') => {
+                        open_reply = None;
+                    }
+                    Some(_) => {}
+                    None if line.get(3) == Some(&b'-') => open_reply = Some(line[..3].to_vec()),
+                    None => {}
+                }
+                // A login over the limit of its user is answered with 530
+                // by the session, the client is told why instead.
+                if let Some(reason) = self.state.close_reason() {
+                    client_write
+                        .write_all(format!("421 {}\r\n", reason).as_bytes())
+                        .await?;
+                    break;
+                }
+                if !passive_reply.is_empty() {
+                    if open_reply.is_none() {
+                        let extended = extended_passive.load(Ordering::SeqCst);
+                        let reply = self.relay_passive(&passive_reply, extended).await;
+                        client_write.write_all(reply.as_bytes()).await?;
+                        passive_reply.clear();
+                    }
+                } else if !answered {
+                    client_write.write_all(&line).await?;
+                }
+                client_write.flush().await?;
+                line.clear();
+            }
+            client_write.shutdown().await
+        };
+        tokio::pin!(commands, replies);
+
+        // The session ends the control channel, the client closing its side
+        // only ends the commands.
+        tokio::select! {
+            result = &mut replies => result,
+            result = &mut commands => {
+                result?;
+                replies.await
+            }
+        }
+    }
+
+    /// Open a data port for the client in place of the passive port of the
+    /// session and rewrite the PASV reply to point to it, as a 229 reply
+    /// when the client sent EPSV.
+    ///
+    /// The whole reply is replaced, multi-line replies included. When no
+    /// data port can be opened the client gets a 425 reply, and the passive
+    /// port of the session is released so it does not wait for a connection
+    /// that never comes.
+    async fn relay_passive(&self, reply: &[u8], extended: bool) -> String {
+        let Some(session_addr) = parse_pasv_reply(&String::from_utf8_lossy(reply)) else {
+            println!("Error on parse PASV reply of relayed FTPS session");
+            return "425 Can't open data connection\r\n".to_string();
+        };
+        let session_port = SessionPassivePort::new(session_addr);
+        let public_ip = match self.public_ip.to_canonical() {
+            IpAddr::V4(public_ip) => Some(public_ip),
+            IpAddr::V6(_) if extended => None,
+            IpAddr::V6(_) => {
+                return "425 Passive mode needs an IPv4 connection, use EPSV\r\n".to_string()
+            }
+        };
+        let opened = bind_passive_port(self.bind_ip, &self.passive_ports)
+            .and_then(|listener| Ok((listener.local_addr()?.port(), listener)));
+        let (port, listener) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                println!("Error on bind relayed FTPS data port: {}", e);
+                return "425 Can't open data connection\r\n".to_string();
+            }
+        };
+        tokio::spawn(relay_data_connection(
+            listener,
+            self.client_ip,
+            session_port,
+            self.proxy_protocol.clone(),
+            Arc::clone(&self.state),
+        ));
+        match public_ip {
+            Some(public_ip) if !extended => format_pasv_reply(public_ip, port),
+            _ => format_epsv_reply(port),
+        }
+    }
+
+    /// Check the network protocol an EPSV command asks for, the data port
+    /// is opened on the address family of the control channel.
+    fn check_extended_passive(&self, command: &[u8]) -> Result<(), &'static str> {
+        let command = String::from_utf8_lossy(command);
+        let protocol = command
+            .trim()
+            .split_once(' ')
+            .map(|(_, protocol)| protocol.trim());
+        match (protocol, self.public_ip.to_canonical()) {
+            (None | Some("1"), IpAddr::V4(_)) | (None | Some("2"), IpAddr::V6(_)) => Ok(()),
+            (_, IpAddr::V4(_)) => Err("522 Network protocol not supported, use (1)\r\n"),
+            (_, IpAddr::V6(_)) => Err("522 Network protocol not supported, use (2)\r\n"),
+        }
+    }
+
+    /// Answer `SITE QUOTA` with the storage usage and quota of the user, one
+    /// `key=value` per line.
+    async fn report_quota(&self) -> String {
+        let Some(user) = self.user.lock().unwrap().clone() else {
+            return "530 Please log in first\r\n".to_string();
+        };
+        match self.quota_report.quota_report(&user).await {
+            Ok(lines) => {
+                let mut reply = format!("211-Storage usage of {}\r\n", user.username);
+                for line in lines {
+                    reply.push_str(&format!(" {}\r\n", line));
+                }
+                reply.push_str("211 End\r\n");
+                reply
+            }
+            Err(e) => {
+                println!("Error on report quota of {}: {}", user.username, e);
+                "451 Can't compute the storage usage\r\n".to_string()
+            }
+        }
+    }
+
+    /// Connect to the data port the client opened with PORT or EPRT and
+    /// give the session a loopback port to connect to in its place.
+    ///
+    /// Returns the PORT command for the session, or the reply to send the
+    /// client when the command is refused. Only the address of the control
+    /// channel is accepted, so the server cannot be used to connect to
+    /// other hosts.
+    async fn relay_active(&self, command: &[u8]) -> Result<String, &'static str> {
+        if self.proxy_protocol.is_some() {
+            return Err("502 Active mode is not available through the proxy, use PASV\r\n");
+        }
+        if !self.state.is_logged_in() {
+            return Err("530 Please log in first\r\n");
+        }
+        let Some(client_addr) = parse_active_command(&String::from_utf8_lossy(command)) else {
+            return Err("501 Syntax error in the data connection address\r\n");
+        };
+        if client_addr.ip().to_canonical() != self.client_ip.to_canonical()
+            || client_addr.port() == 0
+        {
+            println!(
+                "Refusing relayed FTPS data connection of {} to {}",
+                self.client_ip, client_addr
+            );
+            return Err("504 Data connections only go to the address of the client\r\n");
+        }
+        // Reach the client the way the control channel did, an IPv4 client
+        // on a dual-stack listener has a mapped IPv6 address.
+        let client_addr = SocketAddr::new(self.client_ip, client_addr.port());
+
+        let connected = tokio::time::timeout(
+            DATA_CONNECTION_TIMEOUT,
+            connect_from(self.bind_ip, client_addr),
+        )
+        .await;
+        let client = match connected {
+            Ok(Ok(client)) => client,
+            Ok(Err(e)) => {
+                println!(
+                    "Error on connect relayed FTPS data connection to {}: {}",
+                    client_addr, e
+                );
+                return Err("425 Can't open data connection\r\n");
+            }
+            Err(_) => {
+                println!(
+                    "Error on connect relayed FTPS data connection to {}: timed out",
+                    client_addr
+                );
+                return Err("425 Can't open data connection\r\n");
+            }
+        };
+        let listener = match TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                println!("Error on bind relayed FTPS data port: {}", e);
+                return Err("425 Can't open data connection\r\n");
+            }
+        };
+        let port = match listener.local_addr() {
+            Ok(addr) => addr.port(),
+            Err(e) => {
+                println!("Error on bind relayed FTPS data port: {}", e);
+                return Err("425 Can't open data connection\r\n");
+            }
+        };
+        tokio::spawn(relay_active_connection(
+            listener,
+            client,
+            Arc::clone(&self.state),
+        ));
+        let [p1, p2] = port.to_be_bytes();
+        Ok(format!("PORT 127,0,0,1,{},{}\r\n", p1, p2))
+    }
+}
+
+/// Wait for the client to connect to the data port and copy the data
+/// connection to the session.
+///
+/// Like libunftp, connections from another address than the control
+/// channel are refused, so nobody else can steal the transfer. Behind a
+/// proxy the address is taken from the PROXY header of the data connection.
+async fn relay_data_connection(
+    listener: TcpListener,
+    client_ip: IpAddr,
+    session_port: SessionPassivePort,
+    proxy_protocol: Option<ProxyProtocol>,
+    state: Arc<SessionState>,
+) {
+    let accepted = tokio::time::timeout(DATA_CONNECTION_TIMEOUT, listener.accept()).await;
+    let (mut client, peer_addr) = match accepted {
+        Ok(Ok(connection)) => connection,
+        Ok(Err(e)) => {
+            println!("Error on accept relayed FTPS data connection: {}", e);
+            return;
+        }
+        Err(_) => return,
+    };
+    drop(listener);
+    let source_ip = match &proxy_protocol {
+        Some(proxy_protocol) => match proxy_protocol.accept(&mut client).await {
+            Ok(connection) => connection.source.ip(),
+            Err(e) => {
+                println!(
+                    "Error on relayed FTPS data connection from {}: {}",
+                    peer_addr, e
+                );
+                return;
+            }
+        },
+        None => peer_addr.ip(),
+    };
+    if source_ip.to_canonical() != client_ip.to_canonical() {
+        println!(
+            "Closing relayed FTPS data connection from {} that does not match the control channel {}",
+            source_ip, client_ip
+        );
+        return;
+    }
+
+    let mut session = match session_port.connect().await {
+        Ok(session) => session,
+        Err(e) => {
+            println!("Error on connect relayed FTPS data connection: {}", e);
+            return;
+        }
+    };
+    let _transfer = state.transfer();
+    if let Err(e) = tokio::io::copy_bidirectional(&mut client, &mut session).await {
+        println!("Error on relay FTPS data connection: {}", e);
+    }
+}
+
+/// Passive port the session waits on for a data connection.
+///
+/// Unless the relay connected to it, the port is released when dropped: the
+/// relay connects and hangs up, so the session stops waiting for a data
+/// connection the client never made.
+struct SessionPassivePort {
+    addr: SocketAddr,
+    connected: bool,
+}
+
+impl SessionPassivePort {
+    fn new(addr: SocketAddr) -> Self {
+        SessionPassivePort {
+            addr,
+            connected: false,
+        }
+    }
+
+    /// Connect to the port to relay the data connection of the client.
+    async fn connect(mut self) -> io::Result<TcpStream> {
+        // Once the connection is tried the session stops waiting either way.
+        self.connected = true;
+        TcpStream::connect(self.addr).await
+    }
+}
+
+impl Drop for SessionPassivePort {
+    fn drop(&mut self) {
+        if self.connected {
+            return;
+        }
+        let addr = self.addr;
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                if let Err(e) = TcpStream::connect(addr).await {
+                    println!(
+                        "Error on release passive port of relayed FTPS session: {}",
+                        e
+                    );
+                }
+            });
+        }
+    }
+}
+
+/// Wait for the session to connect to the loopback port given to it in
+/// place of the data port of the client, and copy the data connection to
+/// the client.
+async fn relay_active_connection(
+    listener: TcpListener,
+    mut client: TcpStream,
+    state: Arc<SessionState>,
+) {
+    let accepted = tokio::time::timeout(DATA_CONNECTION_TIMEOUT, listener.accept()).await;
+    let (mut session, peer_addr) = match accepted {
+        Ok(Ok(connection)) => connection,
+        Ok(Err(e)) => {
+            println!("Error on accept relayed FTPS data connection: {}", e);
+            return;
+        }
+        Err(_) => return,
+    };
+    drop(listener);
+    if !peer_addr.ip().is_loopback() {
+        return;
+    }
+
+    let _transfer = state.transfer();
+    if let Err(e) = tokio::io::copy_bidirectional(&mut session, &mut client).await {
+        println!("Error on relay FTPS data connection: {}", e);
+    }
+}
+
+/// Connect to a client from the address the relay listens on, so the data
+/// connection comes from the same address as the control channel.
+async fn connect_from(bind_ip: IpAddr, addr: SocketAddr) -> io::Result<TcpStream> {
+    let socket = match bind_ip {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.bind(SocketAddr::new(bind_ip, 0))?;
+    socket.connect(addr).await
+}
+
+/// Bind a data port on the given address, picking a random port in range.
+fn bind_passive_port(ip: IpAddr, passive_ports: &Range<u16>) -> io::Result<TcpListener> {
+    let mut offset = [0u8; 2];
+    openssl::rand::rand_bytes(&mut offset).map_err(io::Error::other)?;
+    let count = u32::from(passive_ports.end.saturating_sub(passive_ports.start).max(1));
+    let first = u32::from(u16::from_be_bytes(offset)) % count;
+
+    for attempt in 0..count.min(10) {
+        let port = passive_ports.start + ((first + attempt) % count) as u16;
+        let socket = match ip {
+            IpAddr::V4(_) => TcpSocket::new_v4()?,
+            IpAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.set_reuseaddr(true)?;
+        if socket.bind(SocketAddr::new(ip, port)).is_ok() {
+            return socket.listen(1);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        "no free passive port",
+    ))
+}
+
+/// Parse the address of a `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)`
+/// reply.
+fn parse_pasv_reply(reply: &str) -> Option<SocketAddr> {
+    let start = reply.find('(')? + 1;
+    let end = start + reply[start..].find(')')?;
+    parse_host_port(&reply[start..end])
+}
+
+/// Parse the `h1,h2,h3,h4,p1,p2` address of PORT and of PASV replies.
+fn parse_host_port(address: &str) -> Option<SocketAddr> {
+    let numbers: Vec<u8> = address
+        .split(',')
+        .map(|number| number.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    let [h1, h2, h3, h4, p1, p2] = numbers[..] else {
+        return None;
+    };
+    let ip = Ipv4Addr::new(h1, h2, h3, h4);
+    Some(SocketAddr::new(
+        IpAddr::V4(ip),
+        u16::from_be_bytes([p1, p2]),
+    ))
+}
+
+/// Check whether a command line has the given verb.
+fn command_is(command: &[u8], verb: &[u8]) -> bool {
+    command
+        .split(|byte| byte.is_ascii_whitespace())
+        .next()
+        .is_some_and(|word| word.eq_ignore_ascii_case(verb))
+}
+
+/// Check whether a command line is `SITE QUOTA`.
+fn is_quota_command(command: &[u8]) -> bool {
+    let words: Vec<&[u8]> = command
+        .split(|byte| byte.is_ascii_whitespace())
+        .filter(|word| !word.is_empty())
+        .collect();
+    matches!(words[..], [site, quota] if site.eq_ignore_ascii_case(b"SITE") && quota.eq_ignore_ascii_case(b"QUOTA"))
+}
+
+/// Check whether a command line is an EPSV command asking for a data port,
+/// `EPSV ALL` is left to the session.
+fn is_extended_passive_command(command: &[u8]) -> bool {
+    let mut words = command
+        .split(|byte| byte.is_ascii_whitespace())
+        .filter(|word| !word.is_empty());
+    words
+        .next()
+        .is_some_and(|verb| verb.eq_ignore_ascii_case(b"EPSV"))
+        && !words
+            .next()
+            .is_some_and(|argument| argument.eq_ignore_ascii_case(b"ALL"))
+}
+
+/// Check whether a command line is a PORT or EPRT command.
+fn is_active_command(command: &[u8]) -> bool {
+    command_is(command, b"PORT") || command_is(command, b"EPRT")
+}
+
+/// Parse the address of a `PORT h1,h2,h3,h4,p1,p2` or
+/// `EPRT |protocol|address|port|` command.
+fn parse_active_command(command: &str) -> Option<SocketAddr> {
+    let (verb, argument) = command.trim().split_once(' ')?;
+    let argument = argument.trim();
+    if verb.eq_ignore_ascii_case("PORT") {
+        return parse_host_port(argument);
+    }
+    if !verb.eq_ignore_ascii_case("EPRT") {
+        return None;
+    }
+    let delimiter = argument.chars().next()?;
+    let fields: Vec<&str> = argument.split(delimiter).collect();
+    let ["", protocol, ip, port, ""] = fields[..] else {
+        return None;
+    };
+    let ip = match protocol {
+        "1" => IpAddr::V4(ip.parse().ok()?),
+        "2" => IpAddr::V6(ip.parse().ok()?),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port.parse().ok()?))
+}
+
+fn format_pasv_reply(ip: Ipv4Addr, port: u16) -> String {
+    let [h1, h2, h3, h4] = ip.octets();
+    let [p1, p2] = port.to_be_bytes();
+    format!(
+        "227 Entering Passive Mode ({},{},{},{},{},{})\r\n",
+        h1, h2, h3, h4, p1, p2
+    )
+}
+
+fn format_epsv_reply(port: u16) -> String {
+    format!("229 Entering Extended Passive Mode (|||{}|)\r\n", port)
+}
+
+/// Authenticator that passes the address of the relayed client instead of
+/// the loopback address the session sees, so lockouts and the
+/// audit log apply to the real client, along with the state of the session
+/// and the nonce sent for the login. The user of a successful login is
+/// kept for the relay.
+#[derive(Debug)]
+struct ForwardedAuthenticator {
+    inner: SharedAuthenticator,
+    source_ip: IpAddr,
+    state: Arc<SessionState>,
+    nonce: Arc<LoginNonce>,
+    user: Arc<Mutex<Option<FtpUser>>>,
+}
+
+#[async_trait]
+impl Authenticator<FtpUser> for ForwardedAuthenticator {
+    async fn authenticate(
+        &self,
+        username: &str,
+        creds: &Credentials,
+    ) -> Result<FtpUser, AuthenticationError> {
+        let creds = Credentials {
+            source_ip: self.source_ip,
+            ..creds.clone()
+        };
+        // The nonce is used up by the first attempt, so one signed challenge
+        // cannot be used to try several TOTP codes or be replayed.
+        let nonce = self.nonce.take();
+        let user = self
+            .inner
+            .authenticate(username, &creds, &self.state, nonce)
+            .await?;
+        *self.user.lock().unwrap() = Some(user.clone());
+        Ok(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::super::ftp_user::{Quota, Role};
+    use super::super::session_limits::{SessionLimits, SessionSlot};
+    use super::*;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[derive(Debug)]
+    struct FixedQuotaReport;
+
+    #[async_trait]
+    impl QuotaReport for FixedQuotaReport {
+        async fn quota_report(&self, user: &FtpUser) -> libunftp::storage::Result<Vec<String>> {
+            Ok(vec![format!("used_bytes={}", user.username.len())])
+        }
+    }
+
+    fn relay(client_ip: IpAddr, public_ip: IpAddr) -> (ControlRelay, SessionSlot) {
+        let slot = SessionTracker::new(SessionLimits::default())
+            .open(client_ip)
+            .unwrap();
+        let relay = ControlRelay {
+            client_ip,
+            bind_ip: public_ip,
+            public_ip,
+            passive_ports: 50000..65535,
+            proxy_protocol: None,
+            state: slot.state(),
+            nonce: Arc::default(),
+            user: Arc::default(),
+            quota_report: Arc::new(FixedQuotaReport),
+        };
+        (relay, slot)
+    }
+
+    /// Relay the given replies of a session and return what the client got.
+    async fn relay_replies(relay: &ControlRelay, replies: &str) -> String {
+        let (mut client, relay_client) = tokio::io::duplex(4096);
+        let (relay_session, mut session) = tokio::io::duplex(4096);
+        session.write_all(replies.as_bytes()).await.unwrap();
+        drop(session);
+        relay.run(relay_client, relay_session, b"").await.unwrap();
+        let mut received = String::new();
+        client.read_to_string(&mut received).await.unwrap();
+        received
+    }
+
+    /// Send one command through the relay and return the reply of the relay.
+    async fn relay_command(relay: &ControlRelay, command: &str) -> String {
+        let (client, relay_client) = tokio::io::duplex(4096);
+        let (relay_session, session) = tokio::io::duplex(4096);
+        let relayed = relay.run(relay_client, relay_session, command.as_bytes());
+        let exchange = async {
+            let reply = read_reply(&mut BufReader::new(client)).await.unwrap();
+            drop(session);
+            String::from_utf8(reply).unwrap()
+        };
+        let (relayed, reply) = tokio::join!(relayed, exchange);
+        relayed.unwrap();
+        reply
+    }
+
+    fn pasv_reply(addr: SocketAddr) -> String {
+        let IpAddr::V4(ip) = addr.ip() else {
+            panic!("not an IPv4 address");
+        };
+        format_pasv_reply(ip, addr.port())
+    }
+
+    #[test]
+    fn parses_pasv_reply() {
+        assert_eq!(
+            parse_pasv_reply("227 Entering Passive Mode (192,168,1,2,195,80)\r\n"),
+            Some("192.168.1.2:50000".parse().unwrap())
+        );
+        assert_eq!(
+            parse_pasv_reply("227 Entering Passive Mode ( 10, 0, 0, 1, 0, 21 ).\r\n"),
+            Some("10.0.0.1:21".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_pasv_reply() {
+        for reply in [
+            "227 Entering Passive Mode\r\n",
+            "227 Entering Passive Mode (127,0,0,1,195\r\n",
+            "227 Entering Passive Mode (127,0,0,1,195)\r\n",
+            "227 Entering Passive Mode (127,0,0,1,195,80,1)\r\n",
+            "227 Entering Passive Mode (127,0,0,256,195,80)\r\n",
+            "227 Entering Passive Mode (127,0,0,-1,195,80)\r\n",
+            "227 Entering Passive Mode (a,b,c,d,e,f)\r\n",
+            "227 Entering Passive Mode )127,0,0,1,195,80(\r\n",
+        ] {
+            assert_eq!(parse_pasv_reply(reply), None, "{}", reply);
+        }
+    }
+
+    #[test]
+    fn formats_pasv_reply() {
+        let reply = format_pasv_reply(Ipv4Addr::new(203, 0, 113, 7), 50001);
+        assert_eq!(reply, "227 Entering Passive Mode (203,0,113,7,195,81)\r\n");
+        assert_eq!(
+            parse_pasv_reply(&reply),
+            Some("203.0.113.7:50001".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn rewrites_pasv_reply_and_relays_data() {
+        let session_port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let session_addr = session_port.local_addr().unwrap();
+        let (relay, _slot) = relay(LOCALHOST, LOCALHOST);
+
+        let received = relay_replies(&relay, &pasv_reply(session_addr)).await;
+        let offered = parse_pasv_reply(&received).unwrap();
+        assert!(received.starts_with("227 Entering Passive Mode (127,0,0,1,"));
+        assert!(relay.passive_ports.contains(&offered.port()));
+        assert_ne!(offered, session_addr);
+
+        let mut client = TcpStream::connect(offered).await.unwrap();
+        let (mut session, _) = session_port.accept().await.unwrap();
+        client.write_all(b"data").await.unwrap();
+        let mut data = [0; 4];
+        session.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"data");
+    }
+
+    #[tokio::test]
+    async fn rewrites_multi_line_pasv_reply() {
+        let (relay, _slot) = relay(LOCALHOST, LOCALHOST);
+        let received = relay_replies(
+            &relay,
+            "227-Entering Passive Mode\r\n (127,0,0,1,195,80)\r\n227 Done\r\n",
+        )
+        .await;
+        assert!(received.starts_with("227 Entering Passive Mode (127,0,0,1,"));
+        assert_eq!(received.lines().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn answers_user_with_nonce() {
+        let (relay, _slot) = relay(LOCALHOST, LOCALHOST);
+        let (client, relay_client) = tokio::io::duplex(4096);
+        let (relay_session, mut session) = tokio::io::duplex(4096);
+        let relayed = relay.run(relay_client, relay_session, b"USER fk\r\n");
+        let exchange = async {
+            let mut client = BufReader::new(client);
+            let mut reply = String::new();
+            client.read_line(&mut reply).await.unwrap();
+            let nonce = reply
+                .strip_prefix("331 Password required, sign nonce ")
+                .unwrap()
+                .trim_end()
+                .to_string();
+
+            // USER only reaches the session with PASS, and the reply of the
+            // session to it is not passed on.
+            client.write_all(b"PASS signed\r\n").await.unwrap();
+            let mut received = [0; 22];
+            session.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, b"USER fk\r\nPASS signed\r\n");
+            session
+                .write_all(b"331 Password Required\r\n230 User logged in\r\n")
+                .await
+                .unwrap();
+            reply.clear();
+            client.read_line(&mut reply).await.unwrap();
+            assert_eq!(reply, "230 User logged in\r\n");
+            drop(session);
+            nonce
+        };
+        let (relayed, nonce) = tokio::join!(relayed, exchange);
+        relayed.unwrap();
+        assert_eq!(relay.nonce.take(), Some(nonce));
+    }
+
+    #[tokio::test]
+    async fn answers_site_quota() {
+        let (relay, _slot) = relay(LOCALHOST, LOCALHOST);
+        assert_eq!(
+            relay_command(&relay, "SITE QUOTA\r\n").await,
+            "530 Please log in first\r\n"
+        );
+
+        *relay.user.lock().unwrap() = Some(FtpUser {
+            username: "fk".to_string(),
+            home: None,
+            role: Role::ReadOnly,
+            quota: Quota::default(),
+        });
+        assert_eq!(
+            relay_command(&relay, "site  quota\r\n").await,
+            "211-Storage usage of fk\r\n used_bytes=2\r\n211 End\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn relays_epsv_as_pasv() {
+        for ip in [LOCALHOST, IpAddr::V6(Ipv6Addr::LOCALHOST)] {
+            let session_port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            let (relay, _slot) = relay(ip, ip);
+            let (client, relay_client) = tokio::io::duplex(4096);
+            let (relay_session, mut session) = tokio::io::duplex(4096);
+            let relayed = relay.run(relay_client, relay_session, b"EPSV\r\n");
+            let exchange = async {
+                let mut received = [0; 6];
+                session.read_exact(&mut received).await.unwrap();
+                assert_eq!(&received, b"PASV\r\n");
+                let reply = pasv_reply(session_port.local_addr().unwrap());
+                session.write_all(reply.as_bytes()).await.unwrap();
+                let reply = read_reply(&mut BufReader::new(client)).await.unwrap();
+                drop(session);
+                String::from_utf8(reply).unwrap()
+            };
+            let (relayed, reply) = tokio::join!(relayed, exchange);
+            relayed.unwrap();
+
+            let port = reply
+                .strip_prefix("229 Entering Extended Passive Mode (|||")
+                .and_then(|reply| reply.strip_suffix("|)\r\n"))
+                .unwrap();
+            let mut client = TcpStream::connect((ip, port.parse().unwrap()))
+                .await
+                .unwrap();
+            let (mut session, _) = session_port.accept().await.unwrap();
+            client.write_all(b"data").await.unwrap();
+            let mut data = [0; 4];
+            session.read_exact(&mut data).await.unwrap();
+            assert_eq!(&data, b"data");
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_epsv_for_another_protocol() {
+        for (ip, command, reply) in [
+            (
+                LOCALHOST,
+                "EPSV 2\r\n",
+                "522 Network protocol not supported, use (1)\r\n",
+            ),
+            (
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+                "epsv 1\r\n",
+                "522 Network protocol not supported, use (2)\r\n",
+            ),
+        ] {
+            let (relay, _slot) = relay(ip, ip);
+            assert_eq!(relay_command(&relay, command).await, reply);
+        }
+    }
+
+    #[test]
+    fn recognizes_epsv_commands() {
+        assert!(is_extended_passive_command(b"EPSV\r\n"));
+        assert!(is_extended_passive_command(b"epsv 2\r\n"));
+        assert!(!is_extended_passive_command(b"EPSV ALL\r\n"));
+        assert!(!is_extended_passive_command(b"EPSVX\r\n"));
+        assert!(!is_extended_passive_command(b"PASV\r\n"));
+    }
+
+    #[tokio::test]
+    async fn refuses_auth_after_login_started() {
+        let (relay, _slot) = relay(LOCALHOST, LOCALHOST);
+        assert_eq!(
+            relay_command(&relay, "AUTH TLS\r\n").await,
+            "503 AUTH is only accepted before the login\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn dropped_session_port_is_released() {
+        let session_port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        drop(SessionPassivePort::new(session_port.local_addr().unwrap()));
+        let accepted = tokio::time::timeout(Duration::from_secs(5), session_port.accept()).await;
+        let (mut session, _) = accepted.unwrap().unwrap();
+        assert_eq!(session.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn parses_active_commands() {
+        for (command, address) in [
+            ("PORT 192,0,2,1,195,80\r\n", "192.0.2.1:50000"),
+            ("port 127,0,0,1,0,21\r\n", "127.0.0.1:21"),
+            ("EPRT |1|192.0.2.1|50000|\r\n", "192.0.2.1:50000"),
+            ("EPRT |2|::1|21|\r\n", "[::1]:21"),
+            ("eprt !2!2001:db8::1!65535!\r\n", "[2001:db8::1]:65535"),
+        ] {
+            assert_eq!(
+                parse_active_command(command),
+                Some(address.parse().unwrap()),
+                "{}",
+                command
+            );
+        }
+        for command in [
+            "PORT\r\n",
+            "PORT 192,0,2,1,195\r\n",
+            "PORT 192,0,2,1,195,256\r\n",
+            "EPRT |1|::1|21|\r\n",
+            "EPRT |2|192.0.2.1|21|\r\n",
+            "EPRT |3|192.0.2.1|21|\r\n",
+            "EPRT |1|192.0.2.1|65536|\r\n",
+            "EPRT |1|192.0.2.1|21\r\n",
+            "PASV 192,0,2,1,195,80\r\n",
+        ] {
+            assert_eq!(parse_active_command(command), None, "{}", command);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_active_commands() {
+        let (relay, _slot) = relay(LOCALHOST, LOCALHOST);
+        assert_eq!(
+            relay.relay_active(b"PORT 127,0,0,1,195,80\r\n").await,
+            Err("530 Please log in first\r\n")
+        );
+
+        assert!(relay.state.login("fk"));
+        for (command, reply) in [
+            (
+                "PORT 127,0,0,1,195\r\n",
+                "501 Syntax error in the data connection address\r\n",
+            ),
+            (
+                "PORT 192,0,2,1,195,80\r\n",
+                "504 Data connections only go to the address of the client\r\n",
+            ),
+            (
+                "EPRT |2|::2|50000|\r\n",
+                "504 Data connections only go to the address of the client\r\n",
+            ),
+            (
+                "PORT 127,0,0,1,0,0\r\n",
+                "504 Data connections only go to the address of the client\r\n",
+            ),
+        ] {
+            assert_eq!(
+                relay.relay_active(command.as_bytes()).await,
+                Err(reply),
+                "{}",
+                command
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_active_mode_behind_proxy() {
+        let (mut relay, _slot) = relay(LOCALHOST, LOCALHOST);
+        relay.proxy_protocol = Some(ProxyProtocol {
+            trusted_proxies: vec![LOCALHOST],
+        });
+        assert!(relay.state.login("fk"));
+        assert_eq!(
+            relay.relay_active(b"PORT 127,0,0,1,195,80\r\n").await,
+            Err("502 Active mode is not available through the proxy, use PASV\r\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn relays_active_data_connection() {
+        let (relay, _slot) = relay(LOCALHOST, LOCALHOST);
+        assert!(relay.state.login("fk"));
+        let client_port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = client_port.local_addr().unwrap().port();
+
+        let command = format!("EPRT |1|127.0.0.1|{}|\r\n", port);
+        let session_command = relay.relay_active(command.as_bytes()).await.unwrap();
+        let session_addr = parse_active_command(&session_command).unwrap();
+        assert!(session_addr.ip().is_loopback());
+        assert_ne!(session_addr.port(), port);
+
+        let (mut client, _) = client_port.accept().await.unwrap();
+        let mut session = TcpStream::connect(session_addr).await.unwrap();
+        session.write_all(b"data").await.unwrap();
+        let mut data = [0; 4];
+        client.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"data");
+    }
+
+    #[tokio::test]
+    async fn unreachable_client_port_is_refused() {
+        let (relay, _slot) = relay(LOCALHOST, LOCALHOST);
+        assert!(relay.state.login("fk"));
+        let closed_port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let [p1, p2] = closed_port.local_addr().unwrap().port().to_be_bytes();
+        drop(closed_port);
+
+        let command = format!("PORT 127,0,0,1,{},{}\r\n", p1, p2);
+        assert_eq!(
+            relay.relay_active(command.as_bytes()).await,
+            Err("425 Can't open data connection\r\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_other_replies() {
+        let (relay, _slot) = relay(LOCALHOST, LOCALHOST);
+        let replies = "211-Features:\r\n227 not a PASV reply\r\n211 End\r\n200 OK\r\n";
+        assert_eq!(relay_replies(&relay, replies).await, replies);
+    }
+
+    #[tokio::test]
+    async fn malformed_pasv_reply_is_refused() {
+        let (relay, _slot) = relay(LOCALHOST, LOCALHOST);
+        assert_eq!(
+            relay_replies(&relay, "227 Entering Passive Mode\r\n").await,
+            "425 Can't open data connection\r\n"
+        );
+        assert_eq!(
+            relay_replies(
+                &relay,
+                "227-Entering Passive Mode\r\n (1,2,3)\r\n227 Done\r\n"
+            )
+            .await,
+            "425 Can't open data connection\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn refused_pasv_releases_session_port() {
+        let session_port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let (relay, _slot) = relay(LOCALHOST, IpAddr::V6(Ipv6Addr::LOCALHOST));
+
+        let received = relay_replies(&relay, &pasv_reply(session_port.local_addr().unwrap())).await;
+        assert_eq!(
+            received,
+            "425 Passive mode needs an IPv4 connection, use EPSV\r\n"
+        );
+        let accepted = tokio::time::timeout(Duration::from_secs(5), session_port.accept()).await;
+        let (mut session, _) = accepted.unwrap().unwrap();
+        assert_eq!(session.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn data_connection_from_another_address_releases_session_port() {
+        let session_port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let (relay, _slot) = relay(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), LOCALHOST);
+
+        let received = relay_replies(&relay, &pasv_reply(session_port.local_addr().unwrap())).await;
+        let mut client = TcpStream::connect(parse_pasv_reply(&received).unwrap())
+            .await
+            .unwrap();
+        let accepted = tokio::time::timeout(Duration::from_secs(5), session_port.accept()).await;
+        let (mut session, _) = accepted.unwrap().unwrap();
+        assert_eq!(session.read(&mut [0; 1]).await.unwrap(), 0);
+        assert_eq!(client.read(&mut [0; 1]).await.unwrap_or(0), 0);
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use super::authorized_keys::{self, RegisteredUser};
+use super::fs_utils;
+
+/// In-memory copy of the keys directory: the parsed keys and settings of
+/// every user and the revoked fingerprints.
+///
+/// The registry is loaded at startup and reloaded whenever a file in the
+/// keys directory changes, so lookups never touch the disk.
+pub struct KeyRegistry {
+    keys_dir: PathBuf,
+    loaded: RwLock<LoadedKeys>,
+}
+
+/// What was read from the keys directory, replaced as a whole on reload.
+#[derive(Default)]
+struct LoadedKeys {
+    users: HashMap<String, Arc<RegisteredUser>>,
+    /// `None` when the revocation list has never been read successfully, in
+    /// which case every key counts as revoked.
+    revoked: Option<HashSet<String>>,
+}
+
+impl std::fmt::Debug for KeyRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let loaded = self.loaded.read().unwrap();
+        f.debug_struct("KeyRegistry")
+            .field("keys_dir", &self.keys_dir)
+            .field("users", &loaded.users.len())
+            .field("revoked", &loaded.revoked.as_ref().map(HashSet::len))
+            .finish()
+    }
+}
+
+impl KeyRegistry {
+    /// Create a registry and load the given keys directory into it.
+    pub fn load(keys_dir: PathBuf) -> Arc<KeyRegistry> {
+        let registry = Arc::new(KeyRegistry {
+            keys_dir,
+            loaded: RwLock::default(),
+        });
+        registry.reload();
+        registry
+    }
+
+    /// Re-read every key file and the revocation list.
+    ///
+    /// The new state replaces the old one in a single step, so a login never
+    /// sees a partially loaded registry. When the revocation list cannot be
+    /// read, the previously loaded one is kept.
+    pub fn reload(&self) {
+        let usernames = match fs_utils::list_key_users(&self.keys_dir) {
+            Ok(usernames) => usernames,
+            Err(e) => {
+                println!("Error on list keys directory: {}", e);
+                return;
+            }
+        };
+
+        let mut users = HashMap::new();
+        for username in usernames {
+            match authorized_keys::load_user(&self.keys_dir, &username) {
+                Ok(user) => {
+                    users.insert(username, Arc::new(user));
+                }
+                Err(e) => println!("Error on load keys of {}: {}", username, e),
+            }
+        }
+
+        let mut loaded = self.loaded.write().unwrap();
+        let revoked = match authorized_keys::load_revoked_fingerprints(&self.keys_dir) {
+            Ok(revoked) => Some(revoked),
+            Err(e) => {
+                println!(
+                    "Error on load revoked keys, keeping the previous list: {:?}",
+                    e
+                );
+                loaded.revoked.take()
+            }
+        };
+        match &revoked {
+            Some(revoked) => println!(
+                "Loaded keys of {} users and {} revoked fingerprints",
+                users.len(),
+                revoked.len()
+            ),
+            None => println!(
+                "Loaded keys of {} users, refusing every key until the revoked keys can be read",
+                users.len()
+            ),
+        }
+        *loaded = LoadedKeys { users, revoked };
+    }
+
+    /// Get the keys and settings registered for a user.
+    pub fn user(&self, username: &str) -> Option<Arc<RegisteredUser>> {
+        self.loaded.read().unwrap().users.get(username).cloned()
+    }
+
+    /// Check whether a key fingerprint has been revoked.
+    ///
+    /// Every key counts as revoked while the revocation list is unreadable.
+    pub fn is_revoked(&self, fingerprint: &str) -> bool {
+        match &self.loaded.read().unwrap().revoked {
+            Some(revoked) => revoked.contains(fingerprint),
+            None => true,
+        }
+    }
+
+    /// Watch the keys directory and reload the registry when it changes.
+    ///
+    /// The returned watcher must be kept alive for as long as reloading is
+    /// wanted.
+    pub fn watch(self: &Arc<Self>) -> notify::Result<RecommendedWatcher> {
+        let registry = Arc::clone(self);
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+                Ok(event) => {
+                    if matches!(
+                        event.kind,
+                        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                    ) {
+                        registry.reload();
+                    }
+                }
+                Err(e) => println!("Error on watch keys directory: {}", e),
+            })?;
+        watcher.watch(&self.keys_dir, RecursiveMode::NonRecursive)?;
+        Ok(watcher)
+    }
+}
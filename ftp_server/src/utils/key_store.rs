@@ -0,0 +1,67 @@
+use std::error::Error;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Deserializer};
+
+use super::authorized_keys::RegisteredUser;
+use super::key_registry::KeyRegistry;
+
+/// Source of the keys and settings of every user.
+///
+/// Lookups are async so that stores backed by blocking I/O can run it off
+/// the runtime threads.
+#[async_trait]
+pub trait KeyStore: Send + Sync + Debug {
+    /// Get the keys and settings registered for a user.
+    async fn user(&self, username: &str) -> Option<Arc<RegisteredUser>>;
+
+    /// Check whether a key fingerprint has been revoked.
+    async fn is_revoked(&self, fingerprint: &str) -> bool;
+}
+
+#[async_trait]
+impl KeyStore for KeyRegistry {
+    async fn user(&self, username: &str) -> Option<Arc<RegisteredUser>> {
+        KeyRegistry::user(self, username)
+    }
+
+    async fn is_revoked(&self, fingerprint: &str) -> bool {
+        KeyRegistry::is_revoked(self, fingerprint)
+    }
+}
+
+/// The key store backends the server can use.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum KeyStoreKind {
+    /// Key files in the configured keys directory.
+    #[default]
+    Files,
+    /// An SQLite database at the given path.
+    Sqlite(PathBuf),
+}
+
+impl std::str::FromStr for KeyStoreKind {
+    type Err = Box<dyn Error>;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            _ if value == "files" => Ok(KeyStoreKind::Files),
+            Some(("sqlite", path)) if !path.is_empty() => Ok(KeyStoreKind::Sqlite(path.into())),
+            _ => Err(Box::from(format!(
+                "unknown key store {:?}, expected \"files\" or \"sqlite:<path>\"",
+                value
+            ))),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyStoreKind {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
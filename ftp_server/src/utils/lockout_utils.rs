@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+/// Thresholds used to slow down and lock out repeated failed logins, from
+/// the `[lockout]` table of the config file.
+///
+/// A count of `0` disables that lockout.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockoutPolicy {
+    /// Failed logins for one username before it is locked out.
+    pub max_failures_per_user: u32,
+    /// Failed logins from one source address before it is locked out.
+    pub max_failures_per_ip: u32,
+    /// Delay added after the first failure, doubled for each further failure.
+    pub base_delay_ms: u64,
+    /// Upper bound of the progressive delay.
+    pub max_delay_ms: u64,
+    /// How long a username or address stays locked out.
+    pub lockout_duration_secs: u64,
+    /// Failures older than this are forgotten.
+    pub failure_window_secs: u64,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        LockoutPolicy {
+            max_failures_per_user: 5,
+            max_failures_per_ip: 20,
+            base_delay_ms: 500,
+            max_delay_ms: 8000,
+            lockout_duration_secs: 15 * 60,
+            failure_window_secs: 15 * 60,
+        }
+    }
+}
+
+impl LockoutPolicy {
+    /// Check the settings before the server starts.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.failure_window_secs == 0 {
+            return Err(Box::from("lockout.failure_window_secs must be at least 1"));
+        }
+        if self.base_delay_ms > self.max_delay_ms {
+            return Err(Box::from(
+                "lockout.base_delay_ms must not be larger than lockout.max_delay_ms",
+            ));
+        }
+        Ok(())
+    }
+
+    fn base_delay(&self) -> Duration {
+        Duration::from_millis(self.base_delay_ms)
+    }
+
+    fn max_delay(&self) -> Duration {
+        Duration::from_millis(self.max_delay_ms)
+    }
+
+    fn lockout_duration(&self) -> Duration {
+        Duration::from_secs(self.lockout_duration_secs)
+    }
+
+    fn failure_window(&self) -> Duration {
+        Duration::from_secs(self.failure_window_secs)
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+enum ThrottleKey {
+    User(String),
+    Ip(IpAddr),
+}
+
+impl fmt::Display for ThrottleKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThrottleKey::User(username) => write!(f, "user {}", username),
+            ThrottleKey::Ip(ip) => write!(f, "address {}", ip),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FailureRecord {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Tracks failed logins per username and per source address.
+#[derive(Debug)]
+pub struct LoginThrottle {
+    policy: LockoutPolicy,
+    records: Mutex<HashMap<ThrottleKey, FailureRecord>>,
+}
+
+impl LoginThrottle {
+    /// Create a throttle applying the given policy.
+    pub fn new(policy: LockoutPolicy) -> Self {
+        LoginThrottle {
+            policy,
+            records: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check whether the username or the address is locked out.
+    ///
+    /// Returns how much longer the lockout lasts.
+    pub fn locked_for(&self, username: &str, ip: IpAddr) -> Option<Duration> {
+        self.locked_for_at(username, ip, Instant::now())
+    }
+
+    fn locked_for_at(&self, username: &str, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let records = self.records.lock().unwrap();
+        Self::keys(username, ip)
+            .iter()
+            .filter_map(|key| records.get(key)?.locked_until)
+            .filter(|locked_until| *locked_until > now)
+            .map(|locked_until| locked_until - now)
+            .max()
+    }
+
+    /// Get the delay to apply before checking a login, based on the recent
+    /// failures of the username and the address.
+    pub fn delay_for(&self, username: &str, ip: IpAddr) -> Duration {
+        self.delay_for_at(username, ip, Instant::now())
+    }
+
+    fn delay_for_at(&self, username: &str, ip: IpAddr, now: Instant) -> Duration {
+        let records = self.records.lock().unwrap();
+        let failures = Self::keys(username, ip)
+            .iter()
+            .filter_map(|key| records.get(key))
+            .filter(|record| self.is_recent(record, now))
+            .map(|record| record.failures)
+            .max()
+            .unwrap_or(0);
+        if failures == 0 {
+            return Duration::ZERO;
+        }
+        let factor = 2u32.saturating_pow(failures - 1);
+        self.policy
+            .base_delay()
+            .saturating_mul(factor)
+            .min(self.policy.max_delay())
+    }
+
+    /// Record a failed login, locking out the username or the address when
+    /// its threshold is reached.
+    pub fn record_failure(&self, username: &str, ip: IpAddr) {
+        self.record_failure_at(username, ip, Instant::now())
+    }
+
+    fn record_failure_at(&self, username: &str, ip: IpAddr, now: Instant) {
+        let mut records = self.records.lock().unwrap();
+        records.retain(|_, record| {
+            self.is_recent(record, now)
+                || record
+                    .locked_until
+                    .is_some_and(|locked_until| locked_until > now)
+        });
+
+        for key in Self::keys(username, ip) {
+            let max_failures = match key {
+                ThrottleKey::User(_) => self.policy.max_failures_per_user,
+                ThrottleKey::Ip(_) => self.policy.max_failures_per_ip,
+            };
+            let record = records.entry(key.clone()).or_insert(FailureRecord {
+                failures: 0,
+                last_failure: now,
+                locked_until: None,
+            });
+            if record
+                .locked_until
+                .is_some_and(|locked_until| locked_until <= now)
+            {
+                record.failures = 0;
+                record.locked_until = None;
+            }
+            record.failures += 1;
+            record.last_failure = now;
+            if max_failures > 0 && record.failures >= max_failures && record.locked_until.is_none()
+            {
+                record.locked_until = Some(now + self.policy.lockout_duration());
+                println!(
+                    "Locked out {} for {}s after {} failed logins",
+                    key, self.policy.lockout_duration_secs, record.failures
+                );
+            }
+        }
+    }
+
+    /// Forget the failures of a username after a successful login.
+    ///
+    /// Failures of the address are kept, so one valid account cannot be used
+    /// to reset the counter of an address that is guessing other accounts.
+    pub fn record_success(&self, username: &str) {
+        self.records
+            .lock()
+            .unwrap()
+            .remove(&ThrottleKey::User(username.to_string()));
+    }
+
+    fn is_recent(&self, record: &FailureRecord, now: Instant) -> bool {
+        now.saturating_duration_since(record.last_failure) <= self.policy.failure_window()
+    }
+
+    fn keys(username: &str, ip: IpAddr) -> [ThrottleKey; 2] {
+        [ThrottleKey::User(username.to_string()), ThrottleKey::Ip(ip)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> LockoutPolicy {
+        LockoutPolicy {
+            max_failures_per_user: 3,
+            max_failures_per_ip: 5,
+            base_delay_ms: 100,
+            max_delay_ms: 500,
+            lockout_duration_secs: 60,
+            failure_window_secs: 300,
+        }
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_maximum() {
+        let throttle = LoginThrottle::new(LockoutPolicy {
+            max_failures_per_user: 0,
+            ..policy()
+        });
+        let start = Instant::now();
+        assert_eq!(
+            throttle.delay_for_at("fk", ip("10.0.0.1"), start),
+            Duration::ZERO
+        );
+
+        for expected_ms in [100, 200, 400, 500, 500] {
+            throttle.record_failure_at("fk", ip("10.0.0.1"), start);
+            assert_eq!(
+                throttle.delay_for_at("fk", ip("10.0.0.1"), start),
+                Duration::from_millis(expected_ms)
+            );
+        }
+
+        // Failures are forgotten once they leave the window.
+        let later = start + Duration::from_secs(301);
+        assert_eq!(
+            throttle.delay_for_at("fk", ip("10.0.0.1"), later),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn locks_out_the_username_from_every_address() {
+        let throttle = LoginThrottle::new(policy());
+        let start = Instant::now();
+        for address in ["10.0.0.1", "10.0.0.2", "10.0.0.3"] {
+            assert_eq!(throttle.locked_for_at("fk", ip(address), start), None);
+            throttle.record_failure_at("fk", ip(address), start);
+        }
+
+        let now = start + Duration::from_secs(10);
+        assert_eq!(
+            throttle.locked_for_at("fk", ip("10.0.0.4"), now),
+            Some(Duration::from_secs(50))
+        );
+        assert_eq!(throttle.locked_for_at("other", ip("10.0.0.1"), now), None);
+
+        let expired = start + Duration::from_secs(60);
+        assert_eq!(throttle.locked_for_at("fk", ip("10.0.0.4"), expired), None);
+    }
+
+    #[test]
+    fn locks_out_the_address_for_every_username() {
+        let throttle = LoginThrottle::new(policy());
+        let start = Instant::now();
+        for username in ["a", "b", "c", "d", "e"] {
+            assert_eq!(
+                throttle.locked_for_at(username, ip("10.0.0.1"), start),
+                None
+            );
+            throttle.record_failure_at(username, ip("10.0.0.1"), start);
+        }
+
+        assert_eq!(
+            throttle.locked_for_at("f", ip("10.0.0.1"), start),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(throttle.locked_for_at("f", ip("10.0.0.2"), start), None);
+    }
+
+    #[test]
+    fn zero_thresholds_disable_lockout() {
+        let throttle = LoginThrottle::new(LockoutPolicy {
+            max_failures_per_user: 0,
+            max_failures_per_ip: 0,
+            ..policy()
+        });
+        let start = Instant::now();
+        for _ in 0..10 {
+            throttle.record_failure_at("fk", ip("10.0.0.1"), start);
+        }
+        assert_eq!(throttle.locked_for_at("fk", ip("10.0.0.1"), start), None);
+    }
+
+    #[test]
+    fn success_resets_the_username_only() {
+        let throttle = LoginThrottle::new(policy());
+        let start = Instant::now();
+        for username in ["fk", "fk", "other"] {
+            throttle.record_failure_at(username, ip("10.0.0.1"), start);
+        }
+        throttle.record_success("fk");
+
+        assert_eq!(
+            throttle.delay_for_at("fk", ip("10.0.0.2"), start),
+            Duration::ZERO
+        );
+        // The address keeps its three failures.
+        assert_eq!(
+            throttle.delay_for_at("fk", ip("10.0.0.1"), start),
+            Duration::from_millis(400)
+        );
+
+        // Two more failures for fk no longer reach the user threshold.
+        for _ in 0..2 {
+            throttle.record_failure_at("fk", ip("10.0.0.2"), start);
+        }
+        assert_eq!(throttle.locked_for_at("fk", ip("10.0.0.2"), start), None);
+    }
+
+    #[test]
+    fn failures_after_a_lockout_start_a_new_count() {
+        let throttle = LoginThrottle::new(policy());
+        let start = Instant::now();
+        for _ in 0..3 {
+            throttle.record_failure_at("fk", ip("10.0.0.1"), start);
+        }
+        assert!(throttle
+            .locked_for_at("fk", ip("10.0.0.2"), start)
+            .is_some());
+
+        let expired = start + Duration::from_secs(61);
+        throttle.record_failure_at("fk", ip("10.0.0.2"), expired);
+        assert_eq!(throttle.locked_for_at("fk", ip("10.0.0.2"), expired), None);
+        assert_eq!(
+            throttle.delay_for_at("fk", ip("10.0.0.2"), expired),
+            Duration::from_millis(100)
+        );
+    }
+
+    #[test]
+    fn validates_policy() {
+        assert!(LockoutPolicy::default().validate().is_ok());
+        assert!(LockoutPolicy {
+            failure_window_secs: 0,
+            ..policy()
+        }
+        .validate()
+        .is_err());
+        assert!(LockoutPolicy {
+            base_delay_ms: 1000,
+            ..policy()
+        }
+        .validate()
+        .is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use openssl::error::ErrorStack;
+
+/// Number of random bytes in a login nonce.
+const NONCE_LEN: usize = 16;
+
+/// Maximum difference, in seconds, between a login timestamp and the server
+/// clock. Nonces are only accepted for as long after they were issued.
+pub const MAX_LOGIN_AGE_SECS: u64 = 300;
+
+/// A signed login challenge sent by the client as the FTP password.
+pub struct LoginChallenge {
+    /// The nonce the server sent in its reply to USER.
+    pub nonce: String,
+    /// When the client signed the challenge, in seconds since the epoch.
+    pub timestamp: u64,
+    pub signature: String,
+    /// TOTP code appended by users with a second factor.
+    pub totp_code: Option<String>,
+}
+
+impl LoginChallenge {
+    /// Parse a password of the form `<nonce>:<timestamp>:<signature>`,
+    /// optionally followed by `:<totp code>`.
+    pub fn parse(password: &str) -> Result<Self, Box<dyn Error>> {
+        let mut parts = password.splitn(4, ':');
+        let nonce = parts.next().filter(|nonce| !nonce.is_empty());
+        let timestamp = parts.next();
+        let signature = parts.next().filter(|signature| !signature.is_empty());
+        let totp_code = parts.next().map(str::to_string);
+
+        match (nonce, timestamp, signature) {
+            (Some(nonce), Some(timestamp), Some(signature)) => Ok(LoginChallenge {
+                nonce: nonce.to_string(),
+                timestamp: timestamp.parse()?,
+                signature: signature.to_string(),
+                totp_code,
+            }),
+            _ => Err(Box::from("Malformed login challenge")),
+        }
+    }
+
+    /// The message the client is expected to have signed.
+    pub fn message(&self, username: &str) -> String {
+        format!("{}:{}:{}", username, self.nonce, self.timestamp)
+    }
+
+    /// Check that the challenge was signed within the accepted time window.
+    pub fn is_fresh(&self, now: u64) -> bool {
+        now.abs_diff(self.timestamp) <= MAX_LOGIN_AGE_SECS
+    }
+}
+
+/// The nonce a session sent for its next login.
+///
+/// The nonce is sent in the 331 reply to USER and used up by the PASS
+/// that follows, whether the login succeeds or not.
+#[derive(Debug, Default)]
+pub struct LoginNonce {
+    issued: Mutex<Option<(String, Instant)>>,
+}
+
+impl LoginNonce {
+    /// Make up the nonce the client has to sign for the next login,
+    /// replacing any earlier one.
+    pub fn issue(&self) -> Result<String, ErrorStack> {
+        let mut nonce = [0u8; NONCE_LEN];
+        openssl::rand::rand_bytes(&mut nonce)?;
+        let nonce: String = nonce.iter().map(|byte| format!("{:02x}", byte)).collect();
+        *self.issued.lock().unwrap() = Some((nonce.clone(), Instant::now()));
+        Ok(nonce)
+    }
+
+    /// Take the nonce of the login in progress, so that it can only be used
+    /// once. Nonces issued too long ago are dropped.
+    pub fn take(&self) -> Option<String> {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&self, now: Instant) -> Option<String> {
+        let (nonce, issued_at) = self.issued.lock().unwrap().take()?;
+        let age = now.saturating_duration_since(issued_at);
+        (age <= Duration::from_secs(MAX_LOGIN_AGE_SECS)).then_some(nonce)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_login_challenge() {
+        let challenge = LoginChallenge::parse("0123abcd:1700000000:c2lnbmF0dXJl").unwrap();
+        assert_eq!(challenge.nonce, "0123abcd");
+        assert_eq!(challenge.timestamp, 1700000000);
+        assert_eq!(challenge.signature, "c2lnbmF0dXJl");
+        assert_eq!(challenge.totp_code, None);
+        assert_eq!(challenge.message("fk"), "fk:0123abcd:1700000000");
+
+        let challenge = LoginChallenge::parse("0123abcd:1700000000:c2lnbmF0dXJl:123456").unwrap();
+        assert_eq!(challenge.totp_code.as_deref(), Some("123456"));
+    }
+
+    #[test]
+    fn rejects_malformed_login_challenge() {
+        for password in [
+            "",
+            "0123abcd",
+            "0123abcd:1700000000",
+            "0123abcd:1700000000:",
+            "0123abcd:c2lnbmF0dXJl",
+            "0123abcd:soon:c2lnbmF0dXJl",
+            ":1700000000:c2lnbmF0dXJl",
+            "::",
+        ] {
+            assert!(LoginChallenge::parse(password).is_err(), "{}", password);
+        }
+    }
+
+    #[test]
+    fn checks_challenge_timestamp() {
+        let challenge = LoginChallenge::parse("0123abcd:1700000000:c2lnbmF0dXJl").unwrap();
+        assert!(challenge.is_fresh(1700000000));
+        assert!(challenge.is_fresh(1700000000 + MAX_LOGIN_AGE_SECS));
+        assert!(challenge.is_fresh(1700000000 - MAX_LOGIN_AGE_SECS));
+        assert!(!challenge.is_fresh(1700000001 + MAX_LOGIN_AGE_SECS));
+        assert!(!challenge.is_fresh(1699999999 - MAX_LOGIN_AGE_SECS));
+    }
+
+    #[test]
+    fn nonce_is_used_once() {
+        let nonce = LoginNonce::default();
+        assert_eq!(nonce.take(), None);
+
+        let first = nonce.issue().unwrap();
+        assert_eq!(first.len(), 2 * NONCE_LEN);
+        let second = nonce.issue().unwrap();
+        assert_ne!(first, second);
+        assert_eq!(nonce.take(), Some(second));
+        assert_eq!(nonce.take(), None);
+    }
+
+    #[test]
+    fn old_nonce_is_dropped() {
+        let nonce = LoginNonce::default();
+        let issued = nonce.issue().unwrap();
+        let late = Instant::now() + Duration::from_secs(MAX_LOGIN_AGE_SECS + 1);
+        assert_eq!(nonce.take_at(late), None);
+        assert_eq!(nonce.take(), None);
+
+        let issued_again = nonce.issue().unwrap();
+        assert_ne!(issued, issued_again);
+        assert_eq!(nonce.take(), Some(issued_again));
+    }
+}
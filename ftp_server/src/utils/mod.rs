@@ -1,4 +1,38 @@
+/// This module writes the authentication audit log.
+pub mod audit_log;
+/// This module parses the authorized keys registered for each user.
+pub mod authorized_keys;
+/// This module parses the command-line arguments of the server.
+pub mod cli_utils;
 /// This module contains file system functions that are used in the project.
 pub mod fs_utils;
+/// This module contains the user type handed to the FTP sessions.
+pub mod ftp_user;
+/// This module relays the FTPS connections of clients to libunftp sessions.
+pub mod ftps_relay;
+/// This module keeps the parsed keys of every user in memory.
+pub mod key_registry;
+/// This module defines the interface of the key store backends.
+pub mod key_store;
+/// This module slows down and locks out repeated failed logins.
+pub mod lockout_utils;
+/// This module checks the signed login challenges and their nonces.
+pub mod login_challenge;
 /// This module contains crypto functions that are used in the project.
 pub mod openssl_utils;
+/// This module reads the client address sent by a PROXY protocol proxy.
+pub mod proxy_protocol;
+/// This module enforces per-user storage quotas.
+pub mod quota_storage;
+/// This module enforces user roles on top of a storage backend.
+pub mod role_storage;
+/// This module reads and validates the server config file.
+pub mod server_config;
+/// This module limits the number and the duration of sessions.
+pub mod session_limits;
+/// This module reads the users' keys from an SQLite database.
+pub mod sqlite_key_store;
+/// This module decides whether clients must use TLS.
+pub mod tls_policy;
+/// This module checks the TOTP codes of users with a second factor.
+pub mod totp_utils;
@@ -1,24 +1,520 @@
 use std::error::Error;
 
-use base64::decode;
-use openssl::{hash::MessageDigest, pkey::PKey, rsa::Rsa, sign::Verifier};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    ec::{EcGroup, EcKey, EcPoint, PointConversionForm},
+    ecdsa::EcdsaSig,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{Id, PKey, PKeyRef, Public},
+    rsa::Rsa,
+    sign::Verifier,
+};
+
+/// Smallest RSA modulus, in bits, accepted for verification.
+const MIN_RSA_BITS: u32 = 2048;
+
+/// Smallest elliptic curve size, in bits, accepted for verification.
+const MIN_EC_BITS: u32 = 256;
+
+/// Get the digest used to verify signatures made with this key, or `None` for
+/// key types that sign the message directly (Ed25519, Ed448).
+///
+/// Unsupported and weak keys are rejected with an error.
+fn verification_digest(pkey: &PKeyRef<Public>) -> Result<Option<MessageDigest>, Box<dyn Error>> {
+    match pkey.id() {
+        Id::RSA if pkey.bits() < MIN_RSA_BITS => Err(Box::from(format!(
+            "RSA key of {} bits is too weak, at least {} bits are required",
+            pkey.bits(),
+            MIN_RSA_BITS
+        ))),
+        Id::EC if pkey.bits() < MIN_EC_BITS => Err(Box::from(format!(
+            "EC key of {} bits is too weak, at least {} bits are required",
+            pkey.bits(),
+            MIN_EC_BITS
+        ))),
+        Id::RSA | Id::EC => Ok(Some(MessageDigest::sha256())),
+        Id::ED25519 | Id::ED448 => Ok(None),
+        id => Err(Box::from(format!("Unsupported key type: {:?}", id))),
+    }
+}
 
 /// Verify the signature of a message.
+///
+/// RSA (2048 bits or more), ECDSA (P-256 or larger) and Ed25519/Ed448 keys
+/// are supported. Besides the raw signatures made with OpenSSL, signatures
+/// in SSH wire format, as produced by an ssh-agent, are accepted.
 pub fn verify_signature(
-    public_key_pem: &str,
+    pkey: &PKeyRef<Public>,
     message: &str,
     signature_base64: &str,
 ) -> Result<bool, Box<dyn Error>> {
-    let rsa = Rsa::public_key_from_pem(public_key_pem.as_bytes())?;
-    let pkey = PKey::from_rsa(rsa)?;
+    let signature = STANDARD.decode(signature_base64)?;
+    let digest = verification_digest(pkey)?;
+    if let Some((algorithm, blob)) = parse_ssh_signature(&signature) {
+        return verify_ssh_signature(pkey, message, algorithm, blob);
+    }
 
-    let mut verifier = Verifier::new(MessageDigest::sha256(), &pkey)?;
+    // A signature that cannot even be decoded for this key type (e.g. an RSA
+    // signature checked against an EC key) is simply not a valid signature.
+    let is_valid = match digest {
+        Some(digest) => verify_with_digest(pkey, digest, message, &signature),
+        None => verify_without_digest(pkey, message, &signature),
+    };
 
-    verifier.update(message.as_bytes())?;
+    Ok(is_valid)
+}
 
-    let signature = decode(signature_base64)?;
+/// Verify a signature in SSH wire format.
+///
+/// The algorithm must match the key type (and curve for ECDSA). SHA-1 RSA
+/// signatures (`ssh-rsa`) are rejected.
+fn verify_ssh_signature(
+    pkey: &PKeyRef<Public>,
+    message: &str,
+    algorithm: &str,
+    blob: &[u8],
+) -> Result<bool, Box<dyn Error>> {
+    let curve = || pkey.ec_key().ok()?.group().curve_name();
+    let is_valid = match (algorithm, pkey.id()) {
+        ("rsa-sha2-256", Id::RSA) => {
+            verify_with_digest(pkey, MessageDigest::sha256(), message, blob)
+        }
+        ("rsa-sha2-512", Id::RSA) => {
+            verify_with_digest(pkey, MessageDigest::sha512(), message, blob)
+        }
+        ("ssh-rsa", Id::RSA) => return Err(Box::from("SHA-1 RSA signatures are not accepted")),
+        ("ecdsa-sha2-nistp256", Id::EC) if curve() == Some(Nid::X9_62_PRIME256V1) => {
+            verify_ssh_ecdsa(pkey, MessageDigest::sha256(), message, blob)
+        }
+        ("ecdsa-sha2-nistp384", Id::EC) if curve() == Some(Nid::SECP384R1) => {
+            verify_ssh_ecdsa(pkey, MessageDigest::sha384(), message, blob)
+        }
+        ("ecdsa-sha2-nistp521", Id::EC) if curve() == Some(Nid::SECP521R1) => {
+            verify_ssh_ecdsa(pkey, MessageDigest::sha512(), message, blob)
+        }
+        ("ssh-ed25519", Id::ED25519) => verify_without_digest(pkey, message, blob),
+        _ => false,
+    };
+    Ok(is_valid)
+}
 
-    let is_valid = verifier.verify(&signature)?;
+/// Verify an SSH ECDSA signature blob, made of the `r` and `s` integers,
+/// after converting it to the DER form OpenSSL expects.
+fn verify_ssh_ecdsa(
+    pkey: &PKeyRef<Public>,
+    digest: MessageDigest,
+    message: &str,
+    blob: &[u8],
+) -> bool {
+    let mut reader = SshReader::new(blob);
+    let signature = (|| {
+        let r = BigNum::from_slice(reader.read_string()?).ok()?;
+        let s = BigNum::from_slice(reader.read_string()?).ok()?;
+        if !reader.is_empty() {
+            return None;
+        }
+        EcdsaSig::from_private_components(r, s).ok()?.to_der().ok()
+    })();
+    match signature {
+        Some(signature) => verify_with_digest(pkey, digest, message, &signature),
+        None => false,
+    }
+}
 
-    Ok(is_valid)
+fn verify_with_digest(
+    pkey: &PKeyRef<Public>,
+    digest: MessageDigest,
+    message: &str,
+    signature: &[u8],
+) -> bool {
+    Verifier::new(digest, pkey)
+        .and_then(|mut verifier| {
+            verifier.update(message.as_bytes())?;
+            verifier.verify(signature)
+        })
+        .unwrap_or(false)
+}
+
+fn verify_without_digest(pkey: &PKeyRef<Public>, message: &str, signature: &[u8]) -> bool {
+    Verifier::new_without_digest(pkey)
+        .and_then(|mut verifier| verifier.verify_oneshot(signature, message.as_bytes()))
+        .unwrap_or(false)
+}
+
+/// Split a signature in SSH wire format into its algorithm name and blob.
+///
+/// Returns `None` when the data is not exactly one algorithm string
+/// followed by one blob string, as for raw OpenSSL signatures.
+fn parse_ssh_signature(signature: &[u8]) -> Option<(&str, &[u8])> {
+    let mut reader = SshReader::new(signature);
+    let algorithm = std::str::from_utf8(reader.read_string()?).ok()?;
+    let blob = reader.read_string()?;
+    if !reader.is_empty() || !SSH_SIGNATURE_ALGORITHMS.contains(&algorithm) {
+        return None;
+    }
+    Some((algorithm, blob))
+}
+
+/// Signature algorithm names that may appear in an SSH signature.
+const SSH_SIGNATURE_ALGORITHMS: [&str; 7] = [
+    "ssh-rsa",
+    "rsa-sha2-256",
+    "rsa-sha2-512",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "ssh-ed25519",
+];
+
+/// The SSH key type names of the curves with an OpenSSH representation.
+const SSH_CURVES: [(Nid, &str, &str); 3] = [
+    (Nid::X9_62_PRIME256V1, "ecdsa-sha2-nistp256", "nistp256"),
+    (Nid::SECP384R1, "ecdsa-sha2-nistp384", "nistp384"),
+    (Nid::SECP521R1, "ecdsa-sha2-nistp521", "nistp521"),
+];
+
+/// Encode a public key in SSH wire format, the blob found base64 encoded in
+/// OpenSSH public key lines and hashed for OpenSSH fingerprints.
+///
+/// EC keys are only supported on the NIST P-256, P-384 and P-521 curves.
+pub fn ssh_public_key_blob(pkey: &PKeyRef<Public>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut blob = Vec::new();
+    match pkey.id() {
+        Id::RSA => {
+            let rsa = pkey.rsa()?;
+            write_ssh_string(&mut blob, b"ssh-rsa");
+            write_ssh_mpint(&mut blob, &rsa.e().to_vec());
+            write_ssh_mpint(&mut blob, &rsa.n().to_vec());
+        }
+        Id::EC => {
+            let ec_key = pkey.ec_key()?;
+            let group = ec_key.group();
+            let (_, key_type, curve) = SSH_CURVES
+                .iter()
+                .find(|(nid, _, _)| group.curve_name() == Some(*nid))
+                .ok_or("EC key curve has no SSH representation")?;
+            let mut context = BigNumContext::new()?;
+            let point = ec_key.public_key().to_bytes(
+                group,
+                PointConversionForm::UNCOMPRESSED,
+                &mut context,
+            )?;
+            write_ssh_string(&mut blob, key_type.as_bytes());
+            write_ssh_string(&mut blob, curve.as_bytes());
+            write_ssh_string(&mut blob, &point);
+        }
+        Id::ED25519 => {
+            write_ssh_string(&mut blob, b"ssh-ed25519");
+            write_ssh_string(&mut blob, &pkey.raw_public_key()?);
+        }
+        Id::ED448 => {
+            write_ssh_string(&mut blob, b"ssh-ed448");
+            write_ssh_string(&mut blob, &pkey.raw_public_key()?);
+        }
+        id => return Err(Box::from(format!("Unsupported key type: {:?}", id))),
+    }
+    Ok(blob)
+}
+
+/// Decode a public key in SSH wire format.
+///
+/// Returns the SSH key type name along with the key.
+pub fn public_key_from_ssh_blob(blob: &[u8]) -> Result<(String, PKey<Public>), Box<dyn Error>> {
+    let mut reader = SshReader::new(blob);
+    let key_type = reader.read_string().ok_or("truncated SSH public key")?;
+    let key_type = std::str::from_utf8(key_type)?.to_string();
+    let key = match key_type.as_str() {
+        "ssh-rsa" => {
+            let e = reader.read_string().ok_or("truncated SSH public key")?;
+            let n = reader.read_string().ok_or("truncated SSH public key")?;
+            let rsa = Rsa::from_public_components(BigNum::from_slice(n)?, BigNum::from_slice(e)?)?;
+            PKey::from_rsa(rsa)?
+        }
+        "ssh-ed25519" | "ssh-ed448" => {
+            let raw = reader.read_string().ok_or("truncated SSH public key")?;
+            let id = if key_type == "ssh-ed25519" {
+                Id::ED25519
+            } else {
+                Id::ED448
+            };
+            PKey::public_key_from_raw_bytes(raw, id)?
+        }
+        _ => {
+            let (nid, _, curve) = SSH_CURVES
+                .iter()
+                .find(|(_, name, _)| *name == key_type)
+                .ok_or_else(|| format!("unknown SSH key type {}", key_type))?;
+            if reader.read_string() != Some(curve.as_bytes()) {
+                return Err(Box::from("SSH public key curve does not match its type"));
+            }
+            let point = reader.read_string().ok_or("truncated SSH public key")?;
+            let group = EcGroup::from_curve_name(*nid)?;
+            let mut context = BigNumContext::new()?;
+            let point = EcPoint::from_bytes(&group, point, &mut context)?;
+            let ec_key = EcKey::from_public_key(&group, &point)?;
+            ec_key.check_key()?;
+            PKey::from_ec_key(ec_key)?
+        }
+    };
+    if !reader.is_empty() {
+        return Err(Box::from("trailing data after SSH public key"));
+    }
+    Ok((key_type, key))
+}
+
+fn write_ssh_string(blob: &mut Vec<u8>, string: &[u8]) {
+    blob.extend_from_slice(&(string.len() as u32).to_be_bytes());
+    blob.extend_from_slice(string);
+}
+
+/// Write a positive integer given in big-endian bytes as an SSH mpint,
+/// which needs a leading zero byte when the high bit is set.
+fn write_ssh_mpint(blob: &mut Vec<u8>, integer: &[u8]) {
+    if integer.first().is_some_and(|byte| byte & 0x80 != 0) {
+        blob.extend_from_slice(&(integer.len() as u32 + 1).to_be_bytes());
+        blob.push(0);
+        blob.extend_from_slice(integer);
+    } else {
+        write_ssh_string(blob, integer);
+    }
+}
+
+/// Reads the length-prefixed strings of the SSH wire format.
+struct SshReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SshReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        SshReader { data }
+    }
+
+    fn read_string(&mut self) -> Option<&'a [u8]> {
+        let (length, rest) = self.data.split_first_chunk::<4>()?;
+        let length = u32::from_be_bytes(*length) as usize;
+        if rest.len() < length {
+            return None;
+        }
+        let (string, rest) = rest.split_at(length);
+        self.data = rest;
+        Some(string)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::pkey::Private;
+    use openssl::sign::Signer;
+
+    use super::*;
+
+    const MESSAGE: &str = "fk:0123456789abcdef0123456789abcdef";
+
+    fn public(key: &PKey<Private>) -> PKey<Public> {
+        PKey::public_key_from_der(&key.public_key_to_der().unwrap()).unwrap()
+    }
+
+    fn rsa_key(bits: u32) -> PKey<Private> {
+        PKey::from_rsa(Rsa::generate(bits).unwrap()).unwrap()
+    }
+
+    fn ec_key(nid: Nid) -> PKey<Private> {
+        let group = EcGroup::from_curve_name(nid).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn sign(key: &PKey<Private>, digest: MessageDigest) -> Vec<u8> {
+        let mut signer = Signer::new(digest, key).unwrap();
+        signer.update(MESSAGE.as_bytes()).unwrap();
+        signer.sign_to_vec().unwrap()
+    }
+
+    fn sign_ed25519(key: &PKey<Private>) -> Vec<u8> {
+        Signer::new_without_digest(key)
+            .unwrap()
+            .sign_oneshot_to_vec(MESSAGE.as_bytes())
+            .unwrap()
+    }
+
+    /// Wrap a signature blob in SSH wire format, as an ssh-agent returns it.
+    fn ssh_signature(algorithm: &str, blob: &[u8]) -> String {
+        let mut signature = Vec::new();
+        write_ssh_string(&mut signature, algorithm.as_bytes());
+        write_ssh_string(&mut signature, blob);
+        STANDARD.encode(signature)
+    }
+
+    /// Convert a DER ECDSA signature to the `r` and `s` mpints of SSH.
+    fn ssh_ecdsa_blob(der: &[u8]) -> Vec<u8> {
+        let signature = EcdsaSig::from_der(der).unwrap();
+        let mut blob = Vec::new();
+        write_ssh_mpint(&mut blob, &signature.r().to_vec());
+        write_ssh_mpint(&mut blob, &signature.s().to_vec());
+        blob
+    }
+
+    #[test]
+    fn verifies_raw_signatures() {
+        let rsa = rsa_key(2048);
+        let signature = STANDARD.encode(sign(&rsa, MessageDigest::sha256()));
+        assert!(verify_signature(&public(&rsa), MESSAGE, &signature).unwrap());
+        assert!(!verify_signature(&public(&rsa), "fk:other", &signature).unwrap());
+
+        let ec = ec_key(Nid::X9_62_PRIME256V1);
+        let signature = STANDARD.encode(sign(&ec, MessageDigest::sha256()));
+        assert!(verify_signature(&public(&ec), MESSAGE, &signature).unwrap());
+
+        let ed25519 = PKey::generate_ed25519().unwrap();
+        let signature = STANDARD.encode(sign_ed25519(&ed25519));
+        assert!(verify_signature(&public(&ed25519), MESSAGE, &signature).unwrap());
+        assert!(!verify_signature(&public(&rsa), MESSAGE, &signature).unwrap());
+    }
+
+    #[test]
+    fn rejects_weak_keys_and_bad_encoding() {
+        let rsa = rsa_key(1024);
+        let signature = STANDARD.encode(sign(&rsa, MessageDigest::sha256()));
+        assert!(verify_signature(&public(&rsa), MESSAGE, &signature).is_err());
+
+        let rsa = rsa_key(2048);
+        assert!(verify_signature(&public(&rsa), MESSAGE, "not base64!").is_err());
+    }
+
+    #[test]
+    fn verifies_ssh_signatures() {
+        let rsa = rsa_key(2048);
+        let signature = ssh_signature("rsa-sha2-256", &sign(&rsa, MessageDigest::sha256()));
+        assert!(verify_signature(&public(&rsa), MESSAGE, &signature).unwrap());
+        let signature = ssh_signature("rsa-sha2-512", &sign(&rsa, MessageDigest::sha512()));
+        assert!(verify_signature(&public(&rsa), MESSAGE, &signature).unwrap());
+
+        let ec = ec_key(Nid::X9_62_PRIME256V1);
+        let blob = ssh_ecdsa_blob(&sign(&ec, MessageDigest::sha256()));
+        let signature = ssh_signature("ecdsa-sha2-nistp256", &blob);
+        assert!(verify_signature(&public(&ec), MESSAGE, &signature).unwrap());
+
+        let ed25519 = PKey::generate_ed25519().unwrap();
+        let signature = ssh_signature("ssh-ed25519", &sign_ed25519(&ed25519));
+        assert!(verify_signature(&public(&ed25519), MESSAGE, &signature).unwrap());
+    }
+
+    #[test]
+    fn rejects_mismatched_ssh_signatures() {
+        let rsa = rsa_key(2048);
+        let sha1 = ssh_signature("ssh-rsa", &sign(&rsa, MessageDigest::sha1()));
+        assert!(verify_signature(&public(&rsa), MESSAGE, &sha1).is_err());
+
+        // The algorithm must match the digest, the key type and the curve.
+        let signature = ssh_signature("rsa-sha2-512", &sign(&rsa, MessageDigest::sha256()));
+        assert!(!verify_signature(&public(&rsa), MESSAGE, &signature).unwrap());
+        let signature = ssh_signature("ssh-ed25519", &sign(&rsa, MessageDigest::sha256()));
+        assert!(!verify_signature(&public(&rsa), MESSAGE, &signature).unwrap());
+
+        let ec = ec_key(Nid::SECP384R1);
+        let blob = ssh_ecdsa_blob(&sign(&ec, MessageDigest::sha256()));
+        let signature = ssh_signature("ecdsa-sha2-nistp256", &blob);
+        assert!(!verify_signature(&public(&ec), MESSAGE, &signature).unwrap());
+    }
+
+    #[test]
+    fn rejects_truncated_ssh_ecdsa_blob() {
+        let ec = ec_key(Nid::X9_62_PRIME256V1);
+        let blob = ssh_ecdsa_blob(&sign(&ec, MessageDigest::sha256()));
+        for blob in [&blob[..blob.len() - 1], &blob[..3], &[]] {
+            let signature = ssh_signature("ecdsa-sha2-nistp256", blob);
+            assert!(!verify_signature(&public(&ec), MESSAGE, &signature).unwrap());
+        }
+        let mut trailing = blob.clone();
+        trailing.push(0);
+        let signature = ssh_signature("ecdsa-sha2-nistp256", &trailing);
+        assert!(!verify_signature(&public(&ec), MESSAGE, &signature).unwrap());
+    }
+
+    #[test]
+    fn parses_ssh_signature_framing() {
+        let mut signature = Vec::new();
+        write_ssh_string(&mut signature, b"ssh-ed25519");
+        write_ssh_string(&mut signature, b"blob");
+        assert_eq!(
+            parse_ssh_signature(&signature),
+            Some(("ssh-ed25519", &b"blob"[..]))
+        );
+
+        // Truncated strings, trailing data and unknown algorithms are not
+        // SSH signatures.
+        assert_eq!(parse_ssh_signature(&signature[..signature.len() - 1]), None);
+        assert_eq!(parse_ssh_signature(&signature[..2]), None);
+        let mut trailing = signature.clone();
+        trailing.push(0);
+        assert_eq!(parse_ssh_signature(&trailing), None);
+        let mut unknown = Vec::new();
+        write_ssh_string(&mut unknown, b"ssh-dss");
+        write_ssh_string(&mut unknown, b"blob");
+        assert_eq!(parse_ssh_signature(&unknown), None);
+    }
+
+    #[test]
+    fn reads_ssh_strings() {
+        let mut reader = SshReader::new(b"\0\0\0\x03abc\0\0\0\0");
+        assert_eq!(reader.read_string(), Some(&b"abc"[..]));
+        assert_eq!(reader.read_string(), Some(&b""[..]));
+        assert!(reader.is_empty());
+        assert_eq!(reader.read_string(), None);
+
+        assert_eq!(SshReader::new(b"\0\0\0").read_string(), None);
+        assert_eq!(SshReader::new(b"\0\0\0\x04abc").read_string(), None);
+        assert_eq!(SshReader::new(b"\xff\xff\xff\xffabc").read_string(), None);
+    }
+
+    #[test]
+    fn round_trips_ssh_public_keys() {
+        let keys = [
+            ("ssh-rsa", rsa_key(2048)),
+            ("ssh-ed25519", PKey::generate_ed25519().unwrap()),
+            ("ssh-ed448", PKey::generate_ed448().unwrap()),
+            ("ecdsa-sha2-nistp256", ec_key(Nid::X9_62_PRIME256V1)),
+            ("ecdsa-sha2-nistp384", ec_key(Nid::SECP384R1)),
+            ("ecdsa-sha2-nistp521", ec_key(Nid::SECP521R1)),
+        ];
+        for (key_type, key) in keys {
+            let blob = ssh_public_key_blob(&public(&key)).unwrap();
+            let (decoded_type, decoded) = public_key_from_ssh_blob(&blob).unwrap();
+            assert_eq!(decoded_type, key_type);
+            assert!(decoded.public_eq(&key), "{}", key_type);
+        }
+    }
+
+    #[test]
+    fn rejects_keys_without_ssh_representation() {
+        let secp256k1 = ec_key(Nid::SECP256K1);
+        assert!(ssh_public_key_blob(&public(&secp256k1)).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_ssh_public_keys() {
+        let ec = ec_key(Nid::X9_62_PRIME256V1);
+        let blob = ssh_public_key_blob(&public(&ec)).unwrap();
+        assert!(public_key_from_ssh_blob(&blob[..blob.len() - 1]).is_err());
+        assert!(public_key_from_ssh_blob(&blob[..10]).is_err());
+        assert!(public_key_from_ssh_blob(&[]).is_err());
+        let mut trailing = blob.clone();
+        trailing.push(0);
+        assert!(public_key_from_ssh_blob(&trailing).is_err());
+
+        let mut wrong_curve = Vec::new();
+        write_ssh_string(&mut wrong_curve, b"ecdsa-sha2-nistp256");
+        write_ssh_string(&mut wrong_curve, b"nistp384");
+        write_ssh_string(&mut wrong_curve, &[4; 65]);
+        assert!(public_key_from_ssh_blob(&wrong_curve).is_err());
+
+        let mut unknown = Vec::new();
+        write_ssh_string(&mut unknown, b"ssh-dss");
+        assert!(public_key_from_ssh_blob(&unknown).is_err());
+    }
 }
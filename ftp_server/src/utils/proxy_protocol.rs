@@ -0,0 +1,319 @@
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use proxy_protocol::{version1, version2, ProxyHeader};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+
+/// How long a proxy has to send the header after connecting.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest header a v1 proxy may send, CRLF included.
+const MAX_V1_HEADER: usize = 107;
+
+/// Signature every v2 header starts with.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Settings of the PROXY protocol, from the `[proxy_protocol]` table of the
+/// config file.
+///
+/// When enabled, every connection to the listeners and to the passive data
+/// ports must start with a PROXY v1 or v2 header, sent by one of the trusted
+/// proxies. The address in the header is then used for lockouts, key
+/// options and the audit log instead of the address of the proxy.
+///
+/// The headers are read by the FTPS relay rather than with the proxy mode of
+/// libunftp, which only reads v1 headers over IPv4, accepts them from any
+/// peer and serves every data connection on the control port.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyProtocol {
+    /// Addresses of the proxies allowed to send headers, connections from
+    /// anywhere else are closed.
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+/// The addresses of a connection as seen by the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxiedConnection {
+    /// Address of the client.
+    pub source: SocketAddr,
+    /// Address the client connected to on the proxy.
+    pub destination: SocketAddr,
+}
+
+impl ProxyProtocol {
+    /// Check the settings before the server starts.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.trusted_proxies.is_empty() {
+            return Err(Box::from(
+                "proxy_protocol.trusted_proxies is empty, no proxy could connect",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Read the PROXY header at the start of a connection.
+    ///
+    /// Exactly the header is consumed, so the stream can be handed on as is.
+    /// Health checks sent with the v2 `LOCAL` command or the v1 `UNKNOWN`
+    /// family keep the addresses of the socket.
+    pub async fn accept(&self, tcp: &mut TcpStream) -> Result<ProxiedConnection, Box<dyn Error>> {
+        let proxy_addr = tcp.peer_addr()?;
+        if !self.trusted_proxies.contains(&proxy_addr.ip()) {
+            return Err(Box::from(format!(
+                "{} is not a trusted proxy",
+                proxy_addr.ip()
+            )));
+        }
+        let socket = ProxiedConnection {
+            source: proxy_addr,
+            destination: tcp.local_addr()?,
+        };
+        let header = tokio::time::timeout(HEADER_TIMEOUT, read_header(tcp))
+            .await
+            .map_err(|_| "timed out waiting for the PROXY header")??;
+        parse_header(&header, socket)
+    }
+}
+
+/// Get the addresses from the bytes of a header, or those of the socket for
+/// health checks.
+fn parse_header(
+    header: &[u8],
+    socket: ProxiedConnection,
+) -> Result<ProxiedConnection, Box<dyn Error>> {
+    let header = proxy_protocol::parse(&mut &header[..])
+        .map_err(|e| format!("invalid PROXY header: {}", e))?;
+    let connection = match header {
+        ProxyHeader::Version1 { addresses } => match addresses {
+            version1::ProxyAddresses::Unknown => socket,
+            version1::ProxyAddresses::Ipv4 {
+                source,
+                destination,
+            } => ProxiedConnection {
+                source: source.into(),
+                destination: destination.into(),
+            },
+            version1::ProxyAddresses::Ipv6 {
+                source,
+                destination,
+            } => ProxiedConnection {
+                source: source.into(),
+                destination: destination.into(),
+            },
+        },
+        ProxyHeader::Version2 {
+            command: version2::ProxyCommand::Local,
+            ..
+        } => socket,
+        ProxyHeader::Version2 { addresses, .. } => match addresses {
+            version2::ProxyAddresses::Ipv4 {
+                source,
+                destination,
+            } => ProxiedConnection {
+                source: source.into(),
+                destination: destination.into(),
+            },
+            version2::ProxyAddresses::Ipv6 {
+                source,
+                destination,
+            } => ProxiedConnection {
+                source: source.into(),
+                destination: destination.into(),
+            },
+            version2::ProxyAddresses::Unspec | version2::ProxyAddresses::Unix { .. } => {
+                return Err(Box::from("PROXY header without TCP addresses"))
+            }
+        },
+        _ => return Err(Box::from("unsupported PROXY protocol version")),
+    };
+    Ok(connection)
+}
+
+/// Read the bytes of a v1 or v2 header, and nothing after it.
+async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut header = vec![0; 6];
+    stream.read_exact(&mut header).await?;
+
+    if header == b"PROXY " {
+        // The v1 header is a line of text, read byte by byte to stop right
+        // after its CRLF.
+        while !header.ends_with(b"\r\n") {
+            if header.len() == MAX_V1_HEADER {
+                return Err(Box::from("PROXY v1 header is too long"));
+            }
+            header.push(stream.read_u8().await?);
+        }
+        return Ok(header);
+    }
+
+    // The v2 header has a fixed 16 byte prefix ending with the length of
+    // the addresses that follow.
+    header.resize(16, 0);
+    stream.read_exact(&mut header[6..]).await?;
+    if &header[..12] != V2_SIGNATURE {
+        return Err(Box::from("connection does not start with a PROXY header"));
+    }
+    let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+    header.resize(16 + length, 0);
+    stream.read_exact(&mut header[16..]).await?;
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn socket() -> ProxiedConnection {
+        ProxiedConnection {
+            source: "127.0.0.1:40000".parse().unwrap(),
+            destination: "127.0.0.1:2121".parse().unwrap(),
+        }
+    }
+
+    /// Build a v2 header with the given version and command byte, family
+    /// byte and addresses.
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    /// Read and parse a header from the given bytes, returning the addresses
+    /// and what is left after the header.
+    async fn read(bytes: &[u8]) -> Result<(ProxiedConnection, Vec<u8>), Box<dyn Error>> {
+        let mut stream = bytes;
+        let header = read_header(&mut stream).await?;
+        Ok((parse_header(&header, socket())?, stream.to_vec()))
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers() {
+        let (connection, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.2 50000 21\r\nUSER fk\r\n")
+            .await
+            .unwrap();
+        assert_eq!(connection.source, "192.0.2.1:50000".parse().unwrap());
+        assert_eq!(connection.destination, "198.51.100.2:21".parse().unwrap());
+        assert_eq!(rest, b"USER fk\r\n");
+
+        let (connection, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 50000 21\r\n")
+            .await
+            .unwrap();
+        assert_eq!(connection.source, "[2001:db8::1]:50000".parse().unwrap());
+        assert_eq!(connection.destination, "[2001:db8::2]:21".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn v1_unknown_keeps_socket_addresses() {
+        let (connection, _) = read(b"PROXY UNKNOWN\r\n").await.unwrap();
+        assert_eq!(connection, socket());
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers() {
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 2];
+        addresses.extend_from_slice(&50000u16.to_be_bytes());
+        addresses.extend_from_slice(&21u16.to_be_bytes());
+        let mut bytes = v2_header(0x21, 0x11, &addresses);
+        bytes.extend_from_slice(b"USER fk\r\n");
+        let (connection, rest) = read(&bytes).await.unwrap();
+        assert_eq!(connection.source, "192.0.2.1:50000".parse().unwrap());
+        assert_eq!(connection.destination, "198.51.100.2:21".parse().unwrap());
+        assert_eq!(rest, b"USER fk\r\n");
+
+        let source: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let destination: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let mut addresses = source.octets().to_vec();
+        addresses.extend_from_slice(&destination.octets());
+        addresses.extend_from_slice(&50000u16.to_be_bytes());
+        addresses.extend_from_slice(&21u16.to_be_bytes());
+        let (connection, _) = read(&v2_header(0x21, 0x21, &addresses)).await.unwrap();
+        assert_eq!(connection.source, "[2001:db8::1]:50000".parse().unwrap());
+        assert_eq!(connection.destination, "[2001:db8::2]:21".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn v2_local_keeps_socket_addresses() {
+        let (connection, rest) = read(&v2_header(0x20, 0x00, &[])).await.unwrap();
+        assert_eq!(connection, socket());
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn v2_unspec_is_refused() {
+        assert!(read(&v2_header(0x21, 0x00, &[])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn truncated_headers_are_refused() {
+        let v2 = v2_header(0x21, 0x11, &[192, 0, 2, 1, 198, 51, 100, 2, 195, 80, 0, 21]);
+        for bytes in [
+            &b"PROX"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 50000 21",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 50000 21\r",
+            &v2[..10],
+            &v2[..15],
+            &v2[..v2.len() - 1],
+        ] {
+            assert!(read(bytes).await.is_err(), "{:?}", bytes);
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_v1_header_is_refused() {
+        let mut bytes = b"PROXY TCP4 ".to_vec();
+        bytes.resize(MAX_V1_HEADER + 10, b'1');
+        bytes.extend_from_slice(b"\r\n");
+        let error = read(&bytes).await.unwrap_err();
+        assert_eq!(error.to_string(), "PROXY v1 header is too long");
+    }
+
+    #[tokio::test]
+    async fn malformed_headers_are_refused() {
+        for bytes in [
+            &b"USER fk\r\nPASS x\r\n"[..],
+            b"PROXY TCP4 192.0.2.1\r\n",
+            b"PROXY TCP4 192.0.2.300 198.51.100.2 50000 21\r\n",
+            b"PROXY TCP4 2001:db8::1 2001:db8::2 50000 21\r\n",
+            b"\r\n\r\n\0\r\nQUIX\n\x21\x11\0\0",
+        ] {
+            assert!(read(bytes).await.is_err(), "{:?}", bytes);
+        }
+    }
+
+    #[tokio::test]
+    async fn accepts_trusted_proxies_only() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut proxy = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        proxy
+            .write_all(b"PROXY TCP4 192.0.2.1 198.51.100.2 50000 21\r\n")
+            .await
+            .unwrap();
+        let (mut tcp, _) = listener.accept().await.unwrap();
+
+        let untrusted = ProxyProtocol {
+            trusted_proxies: vec!["192.0.2.254".parse().unwrap()],
+        };
+        let error = untrusted.accept(&mut tcp).await.unwrap_err();
+        assert_eq!(error.to_string(), "127.0.0.1 is not a trusted proxy");
+
+        let trusted = ProxyProtocol {
+            trusted_proxies: vec!["127.0.0.1".parse().unwrap()],
+        };
+        let connection = trusted.accept(&mut tcp).await.unwrap();
+        assert_eq!(connection.source, "192.0.2.1:50000".parse().unwrap());
+    }
+}
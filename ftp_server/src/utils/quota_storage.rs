@@ -0,0 +1,523 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use libunftp::storage::{Error, ErrorKind, Fileinfo, Metadata, Result, StorageBackend};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::OwnedMutexGuard;
+
+use super::ftp_user::{FtpUser, Quota};
+
+/// Bytes and files stored by a user.
+#[derive(Debug, Clone, Copy, Default)]
+struct Usage {
+    bytes: u64,
+    files: u64,
+}
+
+/// Serialises the uploads of each user with a quota across all sessions,
+/// so that concurrent uploads never count on the same free space.
+#[derive(Debug, Default)]
+pub struct UploadLocks {
+    users: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl UploadLocks {
+    /// Wait until no other upload of the user runs.
+    async fn lock(&self, username: &str) -> OwnedMutexGuard<()> {
+        let lock = Arc::clone(
+            self.users
+                .lock()
+                .unwrap()
+                .entry(username.to_string())
+                .or_default(),
+        );
+        lock.lock_owned().await
+    }
+}
+
+/// Storage backend that enforces the byte and file-count quota of the
+/// logged in user on uploads.
+///
+/// Uploads that would exceed the quota fail with
+/// `ExceededStorageAllocationError`, which the server turns into a 552
+/// reply. Usage is computed from the user's home directory at the start of
+/// every upload, so files removed by other means are accounted for, and
+/// uploads of the same user run one at a time.
+///
+/// An upload stopped by the byte quota leaves the target as it was before:
+/// new uploads are written to a temporary file next to the target and only
+/// renamed over it once complete, and resumed uploads are cut back to where
+/// they started, or removed if the file did not exist.
+#[derive(Debug)]
+pub struct QuotaStorage<S> {
+    inner: S,
+    uploads: Arc<UploadLocks>,
+}
+
+impl<S> QuotaStorage<S> {
+    /// Wrap a storage backend. Every session of the server must share the
+    /// same upload locks.
+    pub fn new(inner: S, uploads: Arc<UploadLocks>) -> Self {
+        QuotaStorage { inner, uploads }
+    }
+}
+
+/// Add up the size and number of the files visible to the user.
+async fn usage<S>(storage: &S, user: &FtpUser) -> Result<Usage>
+where
+    S: StorageBackend<FtpUser>,
+    S::Metadata: Metadata,
+{
+    let mut usage = Usage::default();
+    let mut pending = vec![PathBuf::from("/")];
+    while let Some(dir) = pending.pop() {
+        for entry in storage.list(user, &dir).await? {
+            let path = dir.join(&entry.path);
+            if entry.metadata.is_dir() {
+                pending.push(path);
+            } else {
+                usage.bytes += entry.metadata.len();
+                usage.files += 1;
+            }
+        }
+    }
+    Ok(usage)
+}
+
+/// Reports the storage usage and the quota of users, which the relay sends
+/// in its reply to `SITE QUOTA`.
+#[async_trait]
+pub trait QuotaReport: Debug + Send + Sync {
+    /// Lines of `key=value`, with `none` for the limits of unlimited users.
+    async fn quota_report(&self, user: &FtpUser) -> Result<Vec<String>>;
+}
+
+/// Counts the usage of a user in a storage backend made for each report
+/// and jailed like the sessions of the user.
+pub struct QuotaReporter<F> {
+    make_storage: F,
+}
+
+impl<F> QuotaReporter<F> {
+    pub fn new(make_storage: F) -> Self {
+        QuotaReporter { make_storage }
+    }
+}
+
+impl<F> Debug for QuotaReporter<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QuotaReporter").finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl<F, S> QuotaReport for QuotaReporter<F>
+where
+    F: Fn() -> S + Send + Sync,
+    S: StorageBackend<FtpUser>,
+    S::Metadata: Metadata,
+{
+    async fn quota_report(&self, user: &FtpUser) -> Result<Vec<String>> {
+        let mut storage = (self.make_storage)();
+        storage
+            .enter(user)
+            .map_err(|e| Error::new(ErrorKind::LocalError, e))?;
+        let usage = usage(&storage, user).await?;
+        let limit = |max: Option<u64>| max.map_or("none".to_string(), |max| max.to_string());
+        Ok(vec![
+            format!("used_bytes={}", usage.bytes),
+            format!("used_files={}", usage.files),
+            format!("max_bytes={}", limit(user.quota.max_bytes)),
+            format!("max_files={}", limit(user.quota.max_files)),
+        ])
+    }
+}
+
+#[async_trait]
+impl<S> StorageBackend<FtpUser> for QuotaStorage<S>
+where
+    S: StorageBackend<FtpUser>,
+    S::Metadata: Metadata,
+{
+    type Metadata = S::Metadata;
+
+    fn enter(&mut self, user_detail: &FtpUser) -> io::Result<()> {
+        self.inner.enter(user_detail)
+    }
+
+    fn supported_features(&self) -> u32 {
+        self.inner.supported_features()
+    }
+
+    async fn metadata<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &FtpUser,
+        path: P,
+    ) -> Result<Self::Metadata> {
+        self.inner.metadata(user, path).await
+    }
+
+    async fn md5<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, path: P) -> Result<String>
+    where
+        P: AsRef<Path> + Send + Debug,
+    {
+        self.inner.md5(user, path).await
+    }
+
+    async fn list<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &FtpUser,
+        path: P,
+    ) -> Result<Vec<Fileinfo<PathBuf, Self::Metadata>>>
+    where
+        <Self as StorageBackend<FtpUser>>::Metadata: Metadata,
+    {
+        self.inner.list(user, path).await
+    }
+
+    async fn get<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &FtpUser,
+        path: P,
+        start_pos: u64,
+    ) -> Result<Box<dyn tokio::io::AsyncRead + Send + Sync + Unpin>> {
+        self.inner.get(user, path, start_pos).await
+    }
+
+    async fn put<
+        P: AsRef<Path> + Send + Debug,
+        R: tokio::io::AsyncRead + Send + Sync + Unpin + 'static,
+    >(
+        &self,
+        user: &FtpUser,
+        input: R,
+        path: P,
+        start_pos: u64,
+    ) -> Result<u64> {
+        let path = path.as_ref().to_path_buf();
+        if user.quota == Quota::default() {
+            return self.inner.put(user, input, path, start_pos).await;
+        }
+
+        let _upload = self.uploads.lock(&user.username).await;
+        let usage = usage(&self.inner, user).await?;
+        let existing = match self.inner.metadata(user, &path).await {
+            Ok(metadata) => Some(metadata.len()),
+            Err(_) => None,
+        };
+        if existing.is_none()
+            && user
+                .quota
+                .max_files
+                .is_some_and(|max_files| usage.files >= max_files)
+        {
+            println!(
+                "Rejected upload of {} for {}: file quota of {} reached",
+                path.display(),
+                user.username,
+                usage.files
+            );
+            return Err(Error::from(ErrorKind::ExceededStorageAllocationError));
+        }
+
+        let Some(max_bytes) = user.quota.max_bytes else {
+            return self.inner.put(user, input, path, start_pos).await;
+        };
+        // The upload cuts the file to `start_pos` before writing, or extends
+        // it to there, so that much of the file counts.
+        let used = usage.bytes.saturating_sub(existing.unwrap_or(0)) + start_pos;
+        if used > max_bytes {
+            println!(
+                "Rejected upload of {} for {}: byte quota of {} exceeded",
+                path.display(),
+                user.username,
+                max_bytes
+            );
+            return Err(Error::from(ErrorKind::ExceededStorageAllocationError));
+        }
+
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let limited = LimitedReader {
+            inner: input,
+            remaining: max_bytes - used,
+            exceeded: Arc::clone(&exceeded),
+        };
+        let target = if start_pos == 0 {
+            upload_path(&path)?
+        } else {
+            path.clone()
+        };
+        let result = self.inner.put(user, limited, &target, start_pos).await;
+        if !exceeded.load(Ordering::SeqCst) {
+            if target != path {
+                self.inner.rename(user, &target, &path).await?;
+            }
+            return result;
+        }
+
+        println!(
+            "Rejected upload of {} for {}: byte quota of {} exceeded",
+            path.display(),
+            user.username,
+            max_bytes
+        );
+        // A resumed upload cut or extended the file to `start_pos` itself,
+        // so the file goes back to the part that was there before.
+        let undone = match existing {
+            _ if target != path => self.inner.del(user, &target).await,
+            None => self.inner.del(user, &path).await,
+            Some(len) => self
+                .inner
+                .put(user, tokio::io::empty(), &path, len.min(start_pos))
+                .await
+                .map(|_| ()),
+        };
+        if let Err(e) = undone {
+            println!("Error on undo upload of {}: {}", path.display(), e);
+        }
+        Err(Error::from(ErrorKind::ExceededStorageAllocationError))
+    }
+
+    async fn del<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, path: P) -> Result<()> {
+        self.inner.del(user, path).await
+    }
+
+    async fn mkd<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, path: P) -> Result<()> {
+        self.inner.mkd(user, path).await
+    }
+
+    async fn rename<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &FtpUser,
+        from: P,
+        to: P,
+    ) -> Result<()> {
+        self.inner.rename(user, from, to).await
+    }
+
+    async fn rmd<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, path: P) -> Result<()> {
+        self.inner.rmd(user, path).await
+    }
+
+    async fn cwd<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, path: P) -> Result<()> {
+        self.inner.cwd(user, path).await
+    }
+}
+
+/// Path of the temporary file a new upload is written to, a hidden file
+/// with a random suffix in the directory of the target.
+fn upload_path(path: &Path) -> Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| Error::from(ErrorKind::FileNameNotAllowedError))?;
+    let mut suffix = [0u8; 8];
+    openssl::rand::rand_bytes(&mut suffix).map_err(|e| Error::new(ErrorKind::LocalError, e))?;
+    let suffix: String = suffix.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(path.with_file_name(format!(
+        ".{}.{}.upload",
+        file_name.to_string_lossy(),
+        suffix
+    )))
+}
+
+/// Reader that fails once more than `remaining` bytes have been read,
+/// flagging `exceeded` so the caller can tell a quota error from an I/O
+/// error.
+struct LimitedReader<R> {
+    inner: R,
+    remaining: u64,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for LimitedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let read = (buf.filled().len() - filled) as u64;
+            if read > self.remaining {
+                self.exceeded.store(true, Ordering::SeqCst);
+                return Poll::Ready(Err(io::Error::other("storage quota exceeded")));
+            }
+            self.remaining -= read;
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+    use unftp_sbe_fs::Filesystem;
+
+    use super::super::ftp_user::Role;
+    use super::*;
+
+    fn storage(home: &TempDir) -> QuotaStorage<Filesystem> {
+        QuotaStorage::new(
+            Filesystem::new(home.path()),
+            Arc::new(UploadLocks::default()),
+        )
+    }
+
+    fn user(max_bytes: Option<u64>, max_files: Option<u64>) -> FtpUser {
+        FtpUser {
+            username: "fk".to_string(),
+            home: None,
+            role: Role::ReadWrite,
+            quota: Quota {
+                max_bytes,
+                max_files,
+            },
+        }
+    }
+
+    async fn upload(
+        storage: &QuotaStorage<Filesystem>,
+        user: &FtpUser,
+        path: &str,
+        data: &'static [u8],
+        start_pos: u64,
+    ) -> Result<u64> {
+        storage.put(user, data, path, start_pos).await
+    }
+
+    /// Names of the files left in the home directory, upload files included.
+    fn files(home: &TempDir) -> Vec<String> {
+        let mut files: Vec<String> = fs::read_dir(home.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[tokio::test]
+    async fn rejects_upload_over_byte_limit() {
+        let home = TempDir::new().unwrap();
+        let storage = storage(&home);
+        let user = user(Some(10), None);
+
+        assert_eq!(
+            upload(&storage, &user, "a.txt", b"123456", 0)
+                .await
+                .unwrap(),
+            6
+        );
+        let error = upload(&storage, &user, "b.txt", b"123456", 0)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ExceededStorageAllocationError);
+        assert_eq!(files(&home), ["a.txt"]);
+
+        // Replacing a file only counts the new size.
+        assert_eq!(
+            upload(&storage, &user, "a.txt", b"1234567890", 0)
+                .await
+                .unwrap(),
+            10
+        );
+        assert_eq!(fs::read(home.path().join("a.txt")).unwrap(), b"1234567890");
+    }
+
+    #[tokio::test]
+    async fn rejects_upload_over_file_limit() {
+        let home = TempDir::new().unwrap();
+        let storage = storage(&home);
+        let user = user(None, Some(1));
+
+        upload(&storage, &user, "a.txt", b"first", 0).await.unwrap();
+        let error = upload(&storage, &user, "b.txt", b"second", 0)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ExceededStorageAllocationError);
+        assert_eq!(files(&home), ["a.txt"]);
+
+        // Existing files can still be replaced.
+        upload(&storage, &user, "a.txt", b"again", 0).await.unwrap();
+        assert_eq!(fs::read(home.path().join("a.txt")).unwrap(), b"again");
+    }
+
+    #[tokio::test]
+    async fn over_quota_upload_keeps_replaced_file() {
+        let home = TempDir::new().unwrap();
+        let storage = storage(&home);
+        let user = user(Some(8), None);
+
+        upload(&storage, &user, "a.txt", b"old", 0).await.unwrap();
+        let error = upload(&storage, &user, "a.txt", b"much too long", 0)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ExceededStorageAllocationError);
+        assert_eq!(files(&home), ["a.txt"]);
+        assert_eq!(fs::read(home.path().join("a.txt")).unwrap(), b"old");
+    }
+
+    #[tokio::test]
+    async fn over_quota_resumed_upload_is_cut_back() {
+        let home = TempDir::new().unwrap();
+        let storage = storage(&home);
+        let user = user(Some(8), None);
+
+        upload(&storage, &user, "a.txt", b"12345", 0).await.unwrap();
+        let error = upload(&storage, &user, "a.txt", b"6789", 5)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ExceededStorageAllocationError);
+        assert_eq!(fs::read(home.path().join("a.txt")).unwrap(), b"12345");
+
+        assert_eq!(
+            upload(&storage, &user, "a.txt", b"678", 5).await.unwrap(),
+            3
+        );
+        assert_eq!(fs::read(home.path().join("a.txt")).unwrap(), b"12345678");
+    }
+
+    #[tokio::test]
+    async fn reports_usage_and_quota() {
+        let home = TempDir::new().unwrap();
+        fs::create_dir(home.path().join("dir")).unwrap();
+        fs::write(home.path().join("a.txt"), b"1234").unwrap();
+        fs::write(home.path().join("dir").join("b.txt"), b"56").unwrap();
+        let path = home.path().to_path_buf();
+        let reporter = QuotaReporter::new(move || Filesystem::new(path.clone()));
+
+        assert_eq!(
+            reporter.quota_report(&user(Some(100), None)).await.unwrap(),
+            [
+                "used_bytes=6",
+                "used_files=2",
+                "max_bytes=100",
+                "max_files=none"
+            ]
+        );
+
+        // Only the home directory of a jailed user counts.
+        let jailed = FtpUser {
+            home: Some(home.path().join("dir")),
+            ..user(None, Some(3))
+        };
+        assert_eq!(
+            reporter.quota_report(&jailed).await.unwrap(),
+            [
+                "used_bytes=2",
+                "used_files=1",
+                "max_bytes=none",
+                "max_files=3"
+            ]
+        );
+    }
+}
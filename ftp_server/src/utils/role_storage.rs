@@ -0,0 +1,285 @@
+use std::fmt::Debug;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use libunftp::storage::{Error, ErrorKind, Fileinfo, Metadata, Result, StorageBackend};
+
+use super::ftp_user::{FtpUser, Permission};
+
+/// Storage backend that checks the role of the logged in user before
+/// handing each operation to the wrapped backend.
+///
+/// Denied operations fail with `PermissionDenied`, which the server turns
+/// into a 550 reply.
+#[derive(Debug)]
+pub struct RoleStorage<S> {
+    inner: S,
+}
+
+impl<S> RoleStorage<S> {
+    /// Wrap a storage backend.
+    pub fn new(inner: S) -> Self {
+        RoleStorage { inner }
+    }
+}
+
+/// Fail with `PermissionDenied` unless the user's role grants the permission.
+fn check(user: &FtpUser, permission: Permission, operation: &str, path: &Path) -> Result<()> {
+    if user.role.allows(permission) {
+        return Ok(());
+    }
+    println!(
+        "Denied {} of {} for {} ({})",
+        operation,
+        path.display(),
+        user.username,
+        user.role
+    );
+    Err(Error::from(ErrorKind::PermissionDenied))
+}
+
+#[async_trait]
+impl<S> StorageBackend<FtpUser> for RoleStorage<S>
+where
+    S: StorageBackend<FtpUser>,
+    S::Metadata: Metadata,
+{
+    type Metadata = S::Metadata;
+
+    fn enter(&mut self, user_detail: &FtpUser) -> io::Result<()> {
+        self.inner.enter(user_detail)
+    }
+
+    fn supported_features(&self) -> u32 {
+        self.inner.supported_features()
+    }
+
+    async fn metadata<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &FtpUser,
+        path: P,
+    ) -> Result<Self::Metadata> {
+        check(user, Permission::Read, "metadata", path.as_ref())?;
+        self.inner.metadata(user, path).await
+    }
+
+    async fn md5<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, path: P) -> Result<String>
+    where
+        P: AsRef<Path> + Send + Debug,
+    {
+        check(user, Permission::Read, "checksum", path.as_ref())?;
+        self.inner.md5(user, path).await
+    }
+
+    async fn list<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &FtpUser,
+        path: P,
+    ) -> Result<Vec<Fileinfo<PathBuf, Self::Metadata>>>
+    where
+        <Self as StorageBackend<FtpUser>>::Metadata: Metadata,
+    {
+        check(user, Permission::List, "listing", path.as_ref())?;
+        self.inner.list(user, path).await
+    }
+
+    async fn get<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &FtpUser,
+        path: P,
+        start_pos: u64,
+    ) -> Result<Box<dyn tokio::io::AsyncRead + Send + Sync + Unpin>> {
+        check(user, Permission::Read, "download", path.as_ref())?;
+        self.inner.get(user, path, start_pos).await
+    }
+
+    async fn put<
+        P: AsRef<Path> + Send + Debug,
+        R: tokio::io::AsyncRead + Send + Sync + Unpin + 'static,
+    >(
+        &self,
+        user: &FtpUser,
+        input: R,
+        path: P,
+        start_pos: u64,
+    ) -> Result<u64> {
+        let path = path.as_ref().to_path_buf();
+        check(user, Permission::Write, "upload", &path)?;
+        // Replacing or appending to an existing file destroys its content, so
+        // it needs the same permission as deleting it.
+        if self.inner.metadata(user, &path).await.is_ok() {
+            check(user, Permission::Delete, "overwrite", &path)?;
+        }
+        self.inner.put(user, input, path, start_pos).await
+    }
+
+    async fn del<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, path: P) -> Result<()> {
+        check(user, Permission::Delete, "delete", path.as_ref())?;
+        self.inner.del(user, path).await
+    }
+
+    async fn mkd<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, path: P) -> Result<()> {
+        check(user, Permission::Write, "mkdir", path.as_ref())?;
+        self.inner.mkd(user, path).await
+    }
+
+    async fn rename<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &FtpUser,
+        from: P,
+        to: P,
+    ) -> Result<()> {
+        check(user, Permission::Delete, "rename", from.as_ref())?;
+        self.inner.rename(user, from, to).await
+    }
+
+    async fn rmd<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, path: P) -> Result<()> {
+        check(user, Permission::Delete, "rmdir", path.as_ref())?;
+        self.inner.rmd(user, path).await
+    }
+
+    async fn cwd<P: AsRef<Path> + Send + Debug>(&self, user: &FtpUser, path: P) -> Result<()> {
+        self.inner.cwd(user, path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+    use tokio::io::AsyncReadExt;
+    use unftp_sbe_fs::Filesystem;
+
+    use super::super::ftp_user::{Quota, Role};
+    use super::*;
+
+    /// A storage root holding `old.txt` and the empty directory `dir`.
+    fn storage(root: &TempDir) -> RoleStorage<Filesystem> {
+        fs::write(root.path().join("old.txt"), b"old").unwrap();
+        fs::create_dir(root.path().join("dir")).unwrap();
+        RoleStorage::new(Filesystem::new(root.path()))
+    }
+
+    fn user(role: Role) -> FtpUser {
+        FtpUser {
+            username: "fk".to_string(),
+            home: None,
+            role,
+            quota: Quota::default(),
+        }
+    }
+
+    fn assert_denied<T>(result: Result<T>) {
+        match result {
+            Ok(_) => panic!("operation was allowed"),
+            Err(e) => assert_eq!(e.kind(), ErrorKind::PermissionDenied),
+        }
+    }
+
+    async fn download(
+        storage: &RoleStorage<Filesystem>,
+        user: &FtpUser,
+        path: &str,
+    ) -> Result<String> {
+        let mut reader = storage.get(user, path, 0).await?;
+        let mut content = String::new();
+        reader.read_to_string(&mut content).await.unwrap();
+        Ok(content)
+    }
+
+    #[tokio::test]
+    async fn read_only_can_list_and_download() {
+        let root = TempDir::new().unwrap();
+        let storage = storage(&root);
+        let user = user(Role::ReadOnly);
+
+        assert_eq!(storage.list(&user, "/").await.unwrap().len(), 2);
+        assert!(storage.metadata(&user, "old.txt").await.is_ok());
+        assert_eq!(download(&storage, &user, "old.txt").await.unwrap(), "old");
+
+        assert_denied(storage.put(&user, &b"new"[..], "new.txt", 0).await);
+        assert_denied(storage.put(&user, &b"new"[..], "old.txt", 0).await);
+        assert_denied(storage.mkd(&user, "new").await);
+        assert_denied(storage.del(&user, "old.txt").await);
+        assert_denied(storage.rename(&user, "old.txt", "moved.txt").await);
+        assert_denied(storage.rmd(&user, "dir").await);
+        assert!(!root.path().join("new.txt").exists());
+        assert_eq!(fs::read(root.path().join("old.txt")).unwrap(), b"old");
+        assert!(root.path().join("dir").is_dir());
+    }
+
+    #[tokio::test]
+    async fn upload_only_can_add_new_files() {
+        let root = TempDir::new().unwrap();
+        let storage = storage(&root);
+        let user = user(Role::UploadOnly);
+
+        assert_eq!(
+            storage.put(&user, &b"new"[..], "new.txt", 0).await.unwrap(),
+            3
+        );
+        assert_eq!(fs::read(root.path().join("new.txt")).unwrap(), b"new");
+        storage.mkd(&user, "uploads").await.unwrap();
+
+        assert_denied(storage.list(&user, "/").await);
+        assert_denied(storage.metadata(&user, "old.txt").await);
+        assert_denied(download(&storage, &user, "old.txt").await);
+        assert_denied(storage.del(&user, "old.txt").await);
+        assert_denied(storage.rename(&user, "old.txt", "moved.txt").await);
+        assert_denied(storage.rmd(&user, "dir").await);
+        assert_eq!(fs::read(root.path().join("old.txt")).unwrap(), b"old");
+    }
+
+    #[tokio::test]
+    async fn overwrite_needs_delete_permission() {
+        let root = TempDir::new().unwrap();
+        let storage = storage(&root);
+        let user = user(Role::UploadOnly);
+
+        // Replacing or appending to a file would destroy what is there.
+        assert_denied(storage.put(&user, &b"new"[..], "old.txt", 0).await);
+        assert_denied(storage.put(&user, &b"new"[..], "old.txt", 3).await);
+        assert_eq!(fs::read(root.path().join("old.txt")).unwrap(), b"old");
+
+        let user = FtpUser {
+            role: Role::ReadWrite,
+            ..user
+        };
+        storage.put(&user, &b"new"[..], "old.txt", 0).await.unwrap();
+        assert_eq!(fs::read(root.path().join("old.txt")).unwrap(), b"new");
+    }
+
+    #[tokio::test]
+    async fn read_write_and_admin_can_do_everything() {
+        for role in [Role::ReadWrite, Role::Admin] {
+            let root = TempDir::new().unwrap();
+            let storage = storage(&root);
+            let user = user(role);
+
+            assert_eq!(storage.list(&user, "/").await.unwrap().len(), 2);
+            assert_eq!(download(&storage, &user, "old.txt").await.unwrap(), "old");
+            storage.put(&user, &b"new"[..], "new.txt", 0).await.unwrap();
+            storage
+                .put(&user, &b"replaced"[..], "old.txt", 0)
+                .await
+                .unwrap();
+            storage.rename(&user, "old.txt", "moved.txt").await.unwrap();
+            storage.del(&user, "new.txt").await.unwrap();
+            storage.mkd(&user, "new").await.unwrap();
+            storage.rmd(&user, "dir").await.unwrap();
+
+            let mut names: Vec<String> = fs::read_dir(root.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+                .collect();
+            names.sort();
+            assert_eq!(names, ["moved.txt", "new"], "{}", role);
+            assert_eq!(
+                fs::read(root.path().join("moved.txt")).unwrap(),
+                b"replaced"
+            );
+        }
+    }
+}
@@ -0,0 +1,369 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer};
+
+use super::audit_log::AuditLogConfig;
+use super::key_store::KeyStoreKind;
+use super::lockout_utils::LockoutPolicy;
+use super::proxy_protocol::ProxyProtocol;
+use super::session_limits::SessionLimits;
+
+/// Environment variable overriding `bind_address`, `off` disables the
+/// explicit FTPS listener.
+pub const EXPLICIT_FTPS_ENV: &str = "FTP_SERVER_EXPLICIT_FTPS";
+
+/// Environment variable overriding `implicit_bind_address`, usually
+/// `0.0.0.0:990`, `off` disables the implicit FTPS listener.
+pub const IMPLICIT_FTPS_ENV: &str = "FTP_SERVER_IMPLICIT_FTPS";
+
+/// Environment variable overriding `key_store`, `files` or `sqlite:<path>`.
+pub const KEY_STORE_ENV: &str = "FTP_SERVER_KEY_STORE";
+
+/// Environment variable overriding `require_tls`, `true` or `false`.
+pub const REQUIRE_TLS_ENV: &str = "FTP_SERVER_REQUIRE_TLS";
+
+/// Settings of the server, read from the TOML file given with `--config`.
+///
+/// Every field is optional and defaults to the value the server used before
+/// it had a config file. Relative paths are resolved against the working
+/// directory.
+///
+/// A few settings can also be overridden with environment variables, which
+/// win over the file:
+///
+/// - `FTP_SERVER_EXPLICIT_FTPS` for `bind_address`
+/// - `FTP_SERVER_IMPLICIT_FTPS` for `implicit_bind_address`
+/// - `FTP_SERVER_KEY_STORE` for `key_store`
+/// - `FTP_SERVER_REQUIRE_TLS` for `require_tls`
+///
+/// ```toml
+/// bind_address = "127.0.0.1:2121"
+/// implicit_bind_address = "off"
+/// greeting = "welcome to my FTP server!"
+/// passive_ports = { start = 50000, end = 65535 }
+/// certificate_path = "server.certs"
+/// key_path = "server.key"
+/// home = "resources"
+/// keys_dir = "keys"
+/// key_store = "files"
+/// require_tls = false
+///
+/// [audit_log]
+/// path = "logs/auth_audit.jsonl"
+/// max_bytes = 10485760
+/// max_age_secs = 86400
+/// max_files = 7
+///
+/// [limits]
+/// idle_timeout_secs = 600
+/// login_timeout_secs = 60
+/// max_session_secs = 0
+/// max_sessions = 100
+/// max_sessions_per_ip = 10
+/// max_sessions_per_user = 10
+///
+/// [lockout]
+/// max_failures_per_user = 5
+/// max_failures_per_ip = 20
+/// base_delay_ms = 500
+/// max_delay_ms = 8000
+/// lockout_duration_secs = 900
+/// failure_window_secs = 900
+///
+/// # Only when running behind HAProxy or another PROXY protocol proxy.
+/// [proxy_protocol]
+/// trusted_proxies = ["127.0.0.1"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address of the explicit FTPS listener, `"off"` disables it.
+    #[serde(deserialize_with = "address_or_off")]
+    pub bind_address: Option<String>,
+    /// Address of the implicit FTPS listener, `"off"` (the default)
+    /// disables it.
+    #[serde(deserialize_with = "address_or_off")]
+    pub implicit_bind_address: Option<String>,
+    /// Message sent to clients when they connect.
+    pub greeting: String,
+    /// Ports handed out for passive data connections, the end is exclusive.
+    pub passive_ports: Range<u16>,
+    /// Certificate chain of the server, used for both FTPS listeners.
+    pub certificate_path: PathBuf,
+    /// Private key matching the certificate.
+    pub key_path: PathBuf,
+    /// Root of the users' home directories, created at startup when missing.
+    pub home: PathBuf,
+    /// Directory holding the users' key files and the revocation list.
+    pub keys_dir: PathBuf,
+    /// Where the keys are read from, `"files"` for `keys_dir` or
+    /// `"sqlite:<path>"` for an SQLite database.
+    pub key_store: KeyStoreKind,
+    /// Whether clients must use TLS on the control and data channels. Off by
+    /// default, like before the setting existed.
+    pub require_tls: bool,
+    /// Where the authentication audit log is written and how it is rotated.
+    pub audit_log: AuditLogConfig,
+    /// Expect a PROXY header on every connection, disabled by default.
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// Limits on the number and the duration of sessions.
+    pub limits: SessionLimits,
+    /// Delays and lockouts applied after failed logins.
+    pub lockout: LockoutPolicy,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_address: Some("127.0.0.1:2121".to_string()),
+            implicit_bind_address: None,
+            greeting: "welcome to my FTP server!".to_string(),
+            passive_ports: 50000..65535,
+            certificate_path: PathBuf::from("server.certs"),
+            key_path: PathBuf::from("server.key"),
+            home: PathBuf::from("resources"),
+            keys_dir: PathBuf::from("keys"),
+            key_store: KeyStoreKind::Files,
+            require_tls: false,
+            audit_log: AuditLogConfig::default(),
+            proxy_protocol: None,
+            limits: SessionLimits::default(),
+            lockout: LockoutPolicy::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Read the config file, or use the defaults when no path is given,
+    /// apply the environment overrides and validate the result.
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let mut config: ServerConfig = match path {
+            Some(path) => {
+                let content = fs::read_to_string(path)
+                    .map_err(|e| format!("cannot read config file {}: {}", path.display(), e))?;
+                toml::from_str(&content)
+                    .map_err(|e| format!("invalid config file {}: {}", path.display(), e))?
+            }
+            None => ServerConfig::default(),
+        };
+        config.apply_overrides(|name| env::var(name))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Replace the settings that have an environment variable set, read
+    /// with `var`.
+    fn apply_overrides<F>(&mut self, var: F) -> Result<(), Box<dyn Error>>
+    where
+        F: Fn(&str) -> Result<String, env::VarError>,
+    {
+        let read = |name: &str| match var(name) {
+            Ok(value) => Ok(Some(value)),
+            Err(env::VarError::NotPresent) => Ok(None),
+            Err(e) => Err(format!("invalid {}: {}", name, e)),
+        };
+        if let Some(value) = read(EXPLICIT_FTPS_ENV)? {
+            self.bind_address = parse_address_or_off(&value)
+                .map_err(|e| format!("invalid {}: {}", EXPLICIT_FTPS_ENV, e))?;
+        }
+        if let Some(value) = read(IMPLICIT_FTPS_ENV)? {
+            self.implicit_bind_address = parse_address_or_off(&value)
+                .map_err(|e| format!("invalid {}: {}", IMPLICIT_FTPS_ENV, e))?;
+        }
+        if let Some(value) = read(KEY_STORE_ENV)? {
+            self.key_store = value
+                .parse()
+                .map_err(|e| format!("invalid {}: {}", KEY_STORE_ENV, e))?;
+        }
+        if let Some(value) = read(REQUIRE_TLS_ENV)? {
+            self.require_tls = match value.trim().to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => true,
+                "0" | "false" | "no" | "off" => false,
+                _ => {
+                    return Err(Box::from(format!(
+                        "invalid {} value {:?}, expected \"true\" or \"false\"",
+                        REQUIRE_TLS_ENV, value
+                    )))
+                }
+            };
+        }
+        Ok(())
+    }
+
+    /// Check the settings before the server starts, so a typo is reported
+    /// at startup instead of on the first connection.
+    ///
+    /// The keys directory is only checked when the key store uses it.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.bind_address.is_none() && self.implicit_bind_address.is_none() {
+            return Err(Box::from(
+                "both the explicit and the implicit FTPS listener are off, the server would not listen anywhere",
+            ));
+        }
+        for address in self.bind_address.iter().chain(&self.implicit_bind_address) {
+            check_address(address)?;
+        }
+        if self.greeting.is_empty() || self.greeting.contains(['\r', '\n']) {
+            return Err(Box::from("greeting must be a single non-empty line"));
+        }
+        if self.passive_ports.is_empty() || self.passive_ports.start == 0 {
+            return Err(Box::from(format!(
+                "passive_ports {}..{} is empty, start must be between 1 and end",
+                self.passive_ports.start, self.passive_ports.end
+            )));
+        }
+        check_file("certificate_path", &self.certificate_path)?;
+        check_file("key_path", &self.key_path)?;
+        if self.home.exists() && !self.home.is_dir() {
+            return Err(Box::from(format!(
+                "home {} is not a directory",
+                self.home.display()
+            )));
+        }
+        if let Some(proxy_protocol) = &self.proxy_protocol {
+            proxy_protocol.validate()?;
+        }
+        self.audit_log.validate()?;
+        self.limits.validate()?;
+        self.lockout.validate()?;
+        Ok(())
+    }
+}
+
+/// Read a listener address, mapping `"off"` to no listener.
+fn address_or_off<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parse_address_or_off(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+fn parse_address_or_off(address: &str) -> Result<Option<String>, &'static str> {
+    let address = address.trim();
+    if address.eq_ignore_ascii_case("off") {
+        Ok(None)
+    } else if address.is_empty() {
+        Err("address is empty, use \"off\" to disable the listener")
+    } else {
+        Ok(Some(address.to_string()))
+    }
+}
+
+fn check_address(address: &str) -> Result<(), Box<dyn Error>> {
+    match address
+        .to_socket_addrs()
+        .map(|mut addresses| addresses.next())
+    {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(Box::from(format!(
+            "listener address {:?} does not resolve",
+            address
+        ))),
+        Err(e) => Err(Box::from(format!(
+            "invalid listener address {:?}, expected host:port: {}",
+            address, e
+        ))),
+    }
+}
+
+fn check_file(name: &str, path: &Path) -> Result<(), Box<dyn Error>> {
+    if !path.is_file() {
+        return Err(Box::from(format!(
+            "{} {} does not exist or is not a file",
+            name,
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn overridden(vars: &[(&str, &str)]) -> Result<ServerConfig, Box<dyn Error>> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let mut config = ServerConfig::default();
+        config.apply_overrides(|name| vars.get(name).cloned().ok_or(env::VarError::NotPresent))?;
+        Ok(config)
+    }
+
+    #[test]
+    fn reads_config_file() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            implicit_bind_address = "0.0.0.0:990"
+            bind_address = "off"
+            key_store = "sqlite:keys.db"
+            require_tls = true
+
+            [audit_log]
+            path = "/var/log/ftp/audit.jsonl"
+            max_files = 0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.bind_address, None);
+        assert_eq!(config.implicit_bind_address.as_deref(), Some("0.0.0.0:990"));
+        assert_eq!(config.key_store, KeyStoreKind::Sqlite("keys.db".into()));
+        assert!(config.require_tls);
+        assert_eq!(
+            config.audit_log,
+            AuditLogConfig {
+                path: PathBuf::from("/var/log/ftp/audit.jsonl"),
+                max_files: 0,
+                ..AuditLogConfig::default()
+            }
+        );
+
+        assert!(toml::from_str::<ServerConfig>("bind_address = \"\"").is_err());
+        assert!(toml::from_str::<ServerConfig>("[audit_log]\nrotate = true").is_err());
+    }
+
+    #[test]
+    fn defaults_match_the_server_without_config() {
+        let config = ServerConfig::default();
+        assert_eq!(config.bind_address.as_deref(), Some("127.0.0.1:2121"));
+        assert_eq!(config.implicit_bind_address, None);
+        assert!(!config.require_tls);
+        assert_eq!(config.audit_log.path, Path::new("logs/auth_audit.jsonl"));
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let config = overridden(&[
+            (EXPLICIT_FTPS_ENV, "off"),
+            (IMPLICIT_FTPS_ENV, " 0.0.0.0:990 "),
+            (KEY_STORE_ENV, "sqlite:keys.db"),
+            (REQUIRE_TLS_ENV, "Yes"),
+        ])
+        .unwrap();
+        assert_eq!(config.bind_address, None);
+        assert_eq!(config.implicit_bind_address.as_deref(), Some("0.0.0.0:990"));
+        assert_eq!(config.key_store, KeyStoreKind::Sqlite("keys.db".into()));
+        assert!(config.require_tls);
+
+        assert_eq!(overridden(&[]).unwrap(), ServerConfig::default());
+    }
+
+    #[test]
+    fn rejects_bad_overrides() {
+        for (name, value) in [
+            (EXPLICIT_FTPS_ENV, " "),
+            (KEY_STORE_ENV, "ldap"),
+            (REQUIRE_TLS_ENV, "maybe"),
+        ] {
+            assert!(overridden(&[(name, value)]).is_err(), "{}={}", name, value);
+        }
+    }
+}
@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::time::Instant;
+
+/// Limits on the sessions of the server, from the `[limits]` table of the
+/// config file.
+///
+/// A count of `0` disables that limit.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionLimits {
+    /// Close sessions with no command, reply or transfer for this long.
+    pub idle_timeout_secs: u64,
+    /// Close sessions that have not logged in this long after they
+    /// connected, TLS handshake included.
+    pub login_timeout_secs: u64,
+    /// Close sessions this long after they connected, `0` for no limit.
+    pub max_session_secs: u64,
+    /// Sessions open at the same time on the whole server.
+    pub max_sessions: usize,
+    /// Sessions open at the same time from one client address.
+    pub max_sessions_per_ip: usize,
+    /// Sessions logged in at the same time as one user.
+    pub max_sessions_per_user: usize,
+}
+
+impl Default for SessionLimits {
+    fn default() -> Self {
+        SessionLimits {
+            idle_timeout_secs: 600,
+            login_timeout_secs: 60,
+            max_session_secs: 0,
+            max_sessions: 100,
+            max_sessions_per_ip: 10,
+            max_sessions_per_user: 10,
+        }
+    }
+}
+
+impl SessionLimits {
+    /// Check the settings before the server starts.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.idle_timeout_secs == 0 {
+            return Err(Box::from("limits.idle_timeout_secs must be at least 1"));
+        }
+        if self.login_timeout_secs == 0 {
+            return Err(Box::from("limits.login_timeout_secs must be at least 1"));
+        }
+        Ok(())
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    pub fn login_timeout(&self) -> Duration {
+        Duration::from_secs(self.login_timeout_secs)
+    }
+
+    fn max_session(&self) -> Option<Duration> {
+        (self.max_session_secs > 0).then(|| Duration::from_secs(self.max_session_secs))
+    }
+}
+
+/// Counts the open sessions and hands out a slot to every session within
+/// the limits.
+#[derive(Debug)]
+pub struct SessionTracker {
+    limits: SessionLimits,
+    counts: Mutex<Counts>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    per_user: HashMap<String, usize>,
+}
+
+impl SessionTracker {
+    pub fn new(limits: SessionLimits) -> Arc<Self> {
+        Arc::new(SessionTracker {
+            limits,
+            counts: Mutex::default(),
+        })
+    }
+
+    pub fn limits(&self) -> &SessionLimits {
+        &self.limits
+    }
+
+    /// Take a slot for a new session from the given client address.
+    ///
+    /// Returns the reason for the 421 reply when the server or the address
+    /// already has too many sessions.
+    pub fn open(self: &Arc<Self>, client_ip: IpAddr) -> Result<SessionSlot, &'static str> {
+        let mut counts = self.counts.lock().unwrap();
+        if exceeds(counts.total, self.limits.max_sessions) {
+            return Err("Too many sessions on the server, try again later");
+        }
+        let per_ip = counts.per_ip.get(&client_ip).copied().unwrap_or(0);
+        if exceeds(per_ip, self.limits.max_sessions_per_ip) {
+            return Err("Too many sessions from your address");
+        }
+        counts.total += 1;
+        *counts.per_ip.entry(client_ip).or_default() += 1;
+
+        let now = Instant::now();
+        Ok(SessionSlot {
+            state: Arc::new(SessionState {
+                tracker: Arc::clone(self),
+                client_ip,
+                deadline: self.limits.max_session().map(|max| now + max),
+                login_deadline: now + self.limits.login_timeout(),
+                user: Mutex::default(),
+                last_activity: Mutex::new(now),
+                transfers: AtomicUsize::new(0),
+                close_reason: Mutex::default(),
+            }),
+        })
+    }
+}
+
+fn exceeds(count: usize, limit: usize) -> bool {
+    limit > 0 && count >= limit
+}
+
+/// The place of one session in the counts, given back when dropped.
+#[derive(Debug)]
+pub struct SessionSlot {
+    state: Arc<SessionState>,
+}
+
+impl SessionSlot {
+    /// State shared with the authenticator and the data connections of the
+    /// session.
+    pub fn state(&self) -> Arc<SessionState> {
+        Arc::clone(&self.state)
+    }
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        let state = &self.state;
+        let mut counts = state.tracker.counts.lock().unwrap();
+        counts.total -= 1;
+        release(&mut counts.per_ip, &state.client_ip);
+        if let Some(username) = state.user.lock().unwrap().take() {
+            release(&mut counts.per_user, &username);
+        }
+    }
+}
+
+fn release<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+/// What is known about a running session.
+#[derive(Debug)]
+pub struct SessionState {
+    tracker: Arc<SessionTracker>,
+    client_ip: IpAddr,
+    deadline: Option<Instant>,
+    login_deadline: Instant,
+    user: Mutex<Option<String>>,
+    last_activity: Mutex<Instant>,
+    transfers: AtomicUsize,
+    close_reason: Mutex<Option<&'static str>>,
+}
+
+impl SessionState {
+    /// Count the session against a user whose login was just verified.
+    ///
+    /// When the user already has too many sessions, the session is marked
+    /// to be closed and `false` is returned.
+    pub fn login(&self, username: &str) -> bool {
+        let limit = self.tracker.limits.max_sessions_per_user;
+        let mut counts = self.tracker.counts.lock().unwrap();
+        let mut user = self.user.lock().unwrap();
+        if let Some(previous) = user.take() {
+            release(&mut counts.per_user, &previous);
+        }
+        let count = counts.per_user.get(username).copied().unwrap_or(0);
+        if exceeds(count, limit) {
+            *self.close_reason.lock().unwrap() = Some("Too many sessions for this user");
+            return false;
+        }
+        *counts.per_user.entry(username.to_string()).or_default() += 1;
+        *user = Some(username.to_string());
+        true
+    }
+
+    /// Whether a user logged in on the session.
+    pub fn is_logged_in(&self) -> bool {
+        self.user.lock().unwrap().is_some()
+    }
+
+    /// The reason the session has to be closed, if it has.
+    pub fn close_reason(&self) -> Option<&'static str> {
+        *self.close_reason.lock().unwrap()
+    }
+
+    /// Note that the client or the session did something.
+    pub fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    /// Mark a data transfer as running until the returned guard is dropped.
+    /// The session is never idle while a transfer runs.
+    pub fn transfer(self: &Arc<Self>) -> TransferGuard {
+        self.transfers.fetch_add(1, Ordering::SeqCst);
+        TransferGuard {
+            state: Arc::clone(self),
+        }
+    }
+
+    /// Wait until the session has been idle for too long, has not logged in
+    /// in time or has reached its maximum lifetime, and return the reason
+    /// for the 421 reply.
+    pub async fn expired(&self) -> &'static str {
+        let idle_timeout = self.tracker.limits.idle_timeout();
+        loop {
+            let idle_at = *self.last_activity.lock().unwrap() + idle_timeout;
+            let mut wake_at = match self.deadline {
+                Some(deadline) => deadline.min(idle_at),
+                None => idle_at,
+            };
+            if !self.is_logged_in() {
+                wake_at = wake_at.min(self.login_deadline);
+            }
+            tokio::time::sleep_until(wake_at).await;
+
+            let now = Instant::now();
+            if self.deadline.is_some_and(|deadline| now >= deadline) {
+                return "Maximum session time reached, closing control connection";
+            }
+            if now >= self.login_deadline && !self.is_logged_in() {
+                return "Login timeout, closing control connection";
+            }
+            if self.transfers.load(Ordering::SeqCst) > 0 {
+                self.touch();
+            } else if now >= *self.last_activity.lock().unwrap() + idle_timeout {
+                return "Idle timeout, closing control connection";
+            }
+        }
+    }
+}
+
+/// Keeps a session busy while a data transfer runs.
+#[derive(Debug)]
+pub struct TransferGuard {
+    state: Arc<SessionState>,
+}
+
+impl Drop for TransferGuard {
+    fn drop(&mut self) {
+        self.state.touch();
+        self.state.transfers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER_CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn limits_sessions_per_ip_and_on_the_server() {
+        let tracker = SessionTracker::new(SessionLimits {
+            max_sessions: 3,
+            max_sessions_per_ip: 2,
+            ..SessionLimits::default()
+        });
+        let first = tracker.open(CLIENT).unwrap();
+        let _second = tracker.open(CLIENT).unwrap();
+        assert_eq!(
+            tracker.open(CLIENT).unwrap_err(),
+            "Too many sessions from your address"
+        );
+        let _third = tracker.open(OTHER_CLIENT).unwrap();
+        assert_eq!(
+            tracker.open(OTHER_CLIENT).unwrap_err(),
+            "Too many sessions on the server, try again later"
+        );
+
+        drop(first);
+        let _fourth = tracker.open(CLIENT).unwrap();
+    }
+
+    #[test]
+    fn limits_sessions_per_user() {
+        let tracker = SessionTracker::new(SessionLimits {
+            max_sessions_per_user: 1,
+            ..SessionLimits::default()
+        });
+        let first = tracker.open(CLIENT).unwrap();
+        assert!(first.state().login("fk"));
+        assert!(first.state().is_logged_in());
+
+        let second = tracker.open(CLIENT).unwrap();
+        assert!(!second.state().login("fk"));
+        assert!(!second.state().is_logged_in());
+        assert_eq!(
+            second.state().close_reason(),
+            Some("Too many sessions for this user")
+        );
+        let third = tracker.open(OTHER_CLIENT).unwrap();
+        assert!(third.state().login("other"));
+        assert_eq!(third.state().close_reason(), None);
+
+        drop(first);
+        let fourth = tracker.open(CLIENT).unwrap();
+        assert!(fourth.state().login("fk"));
+    }
+
+    #[test]
+    fn dropped_slots_are_released() {
+        let tracker = SessionTracker::new(SessionLimits::default());
+        let slots: Vec<SessionSlot> = [CLIENT, CLIENT, OTHER_CLIENT]
+            .into_iter()
+            .map(|ip| tracker.open(ip).unwrap())
+            .collect();
+        assert!(slots[0].state().login("fk"));
+        assert!(slots[2].state().login("fk"));
+        {
+            let counts = tracker.counts.lock().unwrap();
+            assert_eq!(counts.total, 3);
+            assert_eq!(counts.per_ip[&CLIENT], 2);
+            assert_eq!(counts.per_user["fk"], 2);
+        }
+
+        drop(slots);
+        let counts = tracker.counts.lock().unwrap();
+        assert_eq!(counts.total, 0);
+        assert!(counts.per_ip.is_empty());
+        assert!(counts.per_user.is_empty());
+    }
+
+    #[test]
+    fn zero_disables_limits() {
+        let tracker = SessionTracker::new(SessionLimits {
+            max_sessions: 0,
+            max_sessions_per_ip: 0,
+            max_sessions_per_user: 0,
+            ..SessionLimits::default()
+        });
+        let slots: Vec<SessionSlot> = (0..20).map(|_| tracker.open(CLIENT).unwrap()).collect();
+        assert!(slots.iter().all(|slot| slot.state().login("fk")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expires_sessions_that_do_not_log_in() {
+        let tracker = SessionTracker::new(SessionLimits {
+            idle_timeout_secs: 600,
+            login_timeout_secs: 60,
+            ..SessionLimits::default()
+        });
+        let slot = tracker.open(CLIENT).unwrap();
+        let start = Instant::now();
+        assert_eq!(
+            slot.state().expired().await,
+            "Login timeout, closing control connection"
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expires_idle_sessions() {
+        let tracker = SessionTracker::new(SessionLimits {
+            idle_timeout_secs: 30,
+            ..SessionLimits::default()
+        });
+        let slot = tracker.open(CLIENT).unwrap();
+        let state = slot.state();
+        assert!(state.login("fk"));
+        let start = Instant::now();
+
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        state.touch();
+        assert_eq!(
+            state.expired().await,
+            "Idle timeout, closing control connection"
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(50));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transfers_keep_sessions_busy() {
+        let tracker = SessionTracker::new(SessionLimits {
+            idle_timeout_secs: 30,
+            ..SessionLimits::default()
+        });
+        let slot = tracker.open(CLIENT).unwrap();
+        let state = slot.state();
+        assert!(state.login("fk"));
+        let start = Instant::now();
+
+        let transfer = state.transfer();
+        let expired = tokio::time::timeout(Duration::from_secs(100), state.expired()).await;
+        assert!(expired.is_err());
+        drop(transfer);
+        state.expired().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(130));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expires_sessions_at_maximum_time() {
+        let tracker = SessionTracker::new(SessionLimits {
+            idle_timeout_secs: 30,
+            max_session_secs: 45,
+            ..SessionLimits::default()
+        });
+        let slot = tracker.open(CLIENT).unwrap();
+        let state = slot.state();
+        assert!(state.login("fk"));
+        let start = Instant::now();
+
+        let _transfer = state.transfer();
+        assert_eq!(
+            state.expired().await,
+            "Maximum session time reached, closing control connection"
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(45));
+    }
+
+    #[test]
+    fn rejects_zero_timeouts() {
+        assert!(SessionLimits::default().validate().is_ok());
+        let limits = SessionLimits {
+            idle_timeout_secs: 0,
+            ..SessionLimits::default()
+        };
+        assert!(limits.validate().is_err());
+        let limits = SessionLimits {
+            login_timeout_secs: 0,
+            ..SessionLimits::default()
+        };
+        assert!(limits.validate().is_err());
+    }
+}
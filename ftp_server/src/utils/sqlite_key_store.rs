@@ -0,0 +1,255 @@
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::authorized_keys::{self, RegisteredUser};
+use super::ftp_user::{Quota, Role};
+use super::key_store::KeyStore;
+use super::totp_utils;
+
+/// Tables created in a new key database.
+///
+/// Each row of `authorized_keys` holds one key line in the same format as
+/// the `keys/<username>.keys` files, options and comment included.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+        username TEXT PRIMARY KEY,
+        role TEXT NOT NULL DEFAULT 'read-write',
+        quota_bytes INTEGER,
+        quota_files INTEGER,
+        totp_secret TEXT
+    );
+    CREATE TABLE IF NOT EXISTS authorized_keys (
+        id INTEGER PRIMARY KEY,
+        username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+        key TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS authorized_keys_username ON authorized_keys(username);
+    CREATE TABLE IF NOT EXISTS revoked_keys (
+        fingerprint TEXT PRIMARY KEY,
+        comment TEXT
+    );
+";
+
+/// Key store reading users, keys and revocations from an SQLite database.
+///
+/// Every lookup queries the database, so changes made by other tools are
+/// seen on the next login. Queries run on the blocking thread pool.
+#[derive(Debug)]
+pub struct SqliteKeyStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteKeyStore {
+    /// Open the database at the given path, creating it and its tables if
+    /// they do not exist.
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let store = SqliteKeyStore::with_connection(Connection::open(path)?)?;
+        println!("Using key database {}", path.display());
+        Ok(store)
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self, rusqlite::Error> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(SqliteKeyStore {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Run a query on the blocking thread pool.
+    async fn query<T, F>(&self, query: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, String> + Send + 'static,
+    {
+        let connection = Arc::clone(&self.connection);
+        tokio::task::spawn_blocking(move || query(&connection.lock().unwrap()))
+            .await
+            .map_err(|e| e.to_string())?
+    }
+}
+
+fn load_user(
+    connection: &Connection,
+    username: &str,
+) -> Result<Option<RegisteredUser>, Box<dyn Error>> {
+    let settings = connection
+        .query_row(
+            "SELECT role, quota_bytes, quota_files, totp_secret FROM users WHERE username = ?1",
+            params![username],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Option<i64>>(1)?,
+                    row.get::<_, Option<i64>>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                ))
+            },
+        )
+        .optional()?;
+    let Some((role, quota_bytes, quota_files, totp_secret)) = settings else {
+        return Ok(None);
+    };
+
+    let role: Role = role.parse()?;
+    let quota = Quota {
+        max_bytes: quota_bytes.map(u64::try_from).transpose()?,
+        max_files: quota_files.map(u64::try_from).transpose()?,
+    };
+    let totp = match totp_secret {
+        Some(secret) => Some(totp_utils::parse_secret(&secret)?),
+        None => None,
+    };
+
+    let mut statement = connection
+        .prepare("SELECT id, key FROM authorized_keys WHERE username = ?1 ORDER BY id")?;
+    let rows = statement.query_map(params![username], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+    })?;
+    let mut keys = Vec::new();
+    for row in rows {
+        let (id, line) = row?;
+        match authorized_keys::parse_key_line(&line) {
+            Ok(key) => keys.push(key),
+            Err(e) => println!("Skipping invalid authorized key {}: {}", id, e),
+        }
+    }
+
+    Ok(Some(RegisteredUser {
+        keys,
+        role,
+        quota,
+        totp,
+    }))
+}
+
+fn is_revoked(connection: &Connection, fingerprint: &str) -> Result<bool, rusqlite::Error> {
+    let revoked = connection
+        .query_row(
+            "SELECT 1 FROM revoked_keys WHERE fingerprint = ?1",
+            params![fingerprint],
+            |_| Ok(()),
+        )
+        .optional()?;
+    Ok(revoked.is_some())
+}
+
+#[async_trait]
+impl KeyStore for SqliteKeyStore {
+    async fn user(&self, username: &str) -> Option<Arc<RegisteredUser>> {
+        let name = username.to_string();
+        let user = self
+            .query(move |connection| load_user(connection, &name).map_err(|e| e.to_string()))
+            .await;
+        match user {
+            Ok(user) => user.map(Arc::new),
+            Err(e) => {
+                println!("Error on load keys of {}: {}", username, e);
+                None
+            }
+        }
+    }
+
+    /// Check whether a key fingerprint has been revoked.
+    ///
+    /// A database error counts as revoked, so an unreadable revocation list
+    /// never lets a revoked key in.
+    async fn is_revoked(&self, fingerprint: &str) -> bool {
+        let key = fingerprint.to_string();
+        let revoked = self
+            .query(move |connection| is_revoked(connection, &key).map_err(|e| e.to_string()))
+            .await;
+        match revoked {
+            Ok(revoked) => revoked,
+            Err(e) => {
+                println!("Error on check revoked key {}: {}", fingerprint, e);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ED25519_LINE: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIPgnzkFJpXB3Oi4cH10S70auznvcxxv6Hxv69wkc+Z6V edtest";
+    const ED25519_FINGERPRINT: &str = "SHA256:jf1QmROpn5Wa+2RUFzFR2nzq085ka6mXpi2f4vnS7Gc";
+
+    fn store(statements: &str) -> SqliteKeyStore {
+        let store = SqliteKeyStore::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+        store
+            .connection
+            .lock()
+            .unwrap()
+            .execute_batch(statements)
+            .unwrap();
+        store
+    }
+
+    #[tokio::test]
+    async fn loads_users_and_keys() {
+        let store = store(&format!(
+            "INSERT INTO users VALUES ('fk', 'admin', 1000, 10, 'JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP');
+             INSERT INTO users (username) VALUES ('plain');
+             INSERT INTO authorized_keys (username, key) VALUES ('fk', 'not a key');
+             INSERT INTO authorized_keys (username, key) VALUES ('fk', 'from=127.0.0.1 {}');",
+            ED25519_LINE
+        ));
+
+        let user = store.user("fk").await.unwrap();
+        assert_eq!(user.role, Role::Admin);
+        assert_eq!(
+            user.quota,
+            Quota {
+                max_bytes: Some(1000),
+                max_files: Some(10),
+            }
+        );
+        assert!(user.totp.is_some());
+        assert_eq!(user.keys.len(), 1);
+        assert_eq!(user.keys[0].fingerprint, ED25519_FINGERPRINT);
+
+        let user = store.user("plain").await.unwrap();
+        assert_eq!(user.role, Role::ReadWrite);
+        assert_eq!(user.quota, Quota::default());
+        assert!(user.totp.is_none());
+        assert!(user.keys.is_empty());
+
+        assert!(store.user("nobody").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_user_settings() {
+        let store = store(
+            "INSERT INTO users (username, role) VALUES ('badrole', 'root');
+             INSERT INTO users (username, quota_bytes) VALUES ('badquota', -1);
+             INSERT INTO users (username, totp_secret) VALUES ('badtotp', '1!');",
+        );
+        for username in ["badrole", "badquota", "badtotp"] {
+            assert!(store.user(username).await.is_none(), "{}", username);
+        }
+    }
+
+    #[tokio::test]
+    async fn checks_revoked_keys() {
+        let store = store(&format!(
+            "INSERT INTO revoked_keys VALUES ('{}', 'lost laptop');",
+            ED25519_FINGERPRINT
+        ));
+        assert!(store.is_revoked(ED25519_FINGERPRINT).await);
+        assert!(!store.is_revoked("SHA256:other").await);
+
+        store
+            .connection
+            .lock()
+            .unwrap()
+            .execute_batch("DROP TABLE revoked_keys;")
+            .unwrap();
+        assert!(store.is_revoked("SHA256:other").await);
+    }
+}
@@ -0,0 +1,21 @@
+use libunftp::options::FtpsRequired;
+
+/// Whether TLS is required on the control and data channels.
+///
+/// Clients that try to log in over plaintext get a 534 reply, as do clients
+/// that open a passive data connection without `PROT P`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlsPolicy {
+    pub control: FtpsRequired,
+    pub data: FtpsRequired,
+}
+
+impl TlsPolicy {
+    /// Require TLS on both channels, or on none of them.
+    pub fn required(required: bool) -> Self {
+        TlsPolicy {
+            control: required.into(),
+            data: required.into(),
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::error::Error;
+
+use totp_rs::{Algorithm, Secret, TOTP};
+
+/// Number of digits of a TOTP code.
+const TOTP_DIGITS: usize = 6;
+
+/// Seconds each TOTP code is valid for.
+const TOTP_STEP_SECS: u64 = 30;
+
+/// Number of steps before and after the current one that are also
+/// accepted, to allow for clock skew between the server and the
+/// authenticator app.
+const TOTP_SKEW_STEPS: u8 = 1;
+
+/// Build the TOTP generator of a user from their base32 shared secret, as
+/// shown by authenticator apps (spaces and padding are ignored).
+///
+/// Secrets shorter than 128 bits are rejected.
+pub fn parse_secret(secret_base32: &str) -> Result<TOTP, Box<dyn Error>> {
+    let secret: String = secret_base32
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '=')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    let secret = Secret::Encoded(secret)
+        .to_bytes()
+        .map_err(|_| "TOTP secret is not valid base32")?;
+    Ok(TOTP::new(
+        Algorithm::SHA1,
+        TOTP_DIGITS,
+        TOTP_SKEW_STEPS,
+        TOTP_STEP_SECS,
+        secret,
+    )?)
+}
+
+/// Check a TOTP code against the codes valid at `now` (seconds since the
+/// Unix epoch), accepting the neighbouring steps.
+pub fn check_code(totp: &TOTP, code: &str, now: u64) -> bool {
+    code.len() == TOTP_DIGITS && totp.check(code, now)
+}